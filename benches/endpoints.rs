@@ -0,0 +1,80 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use tokio::sync::mpsc;
+use tonic_dynamic_channel::{AutoBalancedChannel, EndpointTemplate};
+use url::Url;
+
+fn addresses(count: u32) -> Vec<IpAddr> {
+    (0..count)
+        .map(|i| IpAddr::from(Ipv4Addr::from(0x0a00_0000 + i)))
+        .collect()
+}
+
+fn template() -> EndpointTemplate {
+    EndpointTemplate::new(Url::parse("http://example.com:50051").unwrap())
+        .unwrap()
+        .timeout(Duration::from_secs(1))
+        .user_agent("bench")
+}
+
+/// Building an endpoint from scratch for every resolved address, a burst at
+/// a time.
+fn build_endpoints(c: &mut Criterion) {
+    let template = template();
+    let mut group = c.benchmark_group("build_endpoints");
+    for count in [1, 500] {
+        let addresses = addresses(count);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(count),
+            &addresses,
+            |b, addrs| {
+                b.iter(|| {
+                    for ip in addrs {
+                        black_box(template.try_build(*ip).unwrap());
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Registering a burst of newly discovered endpoints, with the endpoints
+/// built one at a time or concurrently on a multi-threaded runtime.
+fn register_endpoints(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap();
+    let addrs: Vec<SocketAddr> = addresses(200)
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, 0))
+        .collect();
+
+    let mut group = c.benchmark_group("register_endpoints");
+    for concurrency in [1, 32] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(concurrency),
+            &concurrency,
+            |b, concurrency| {
+                b.to_async(&runtime).iter(|| async {
+                    // The balanced channel only takes endpoints while serving
+                    // requests, so collect them instead.
+                    let (sender, _receiver) = mpsc::channel(addrs.len());
+                    let channel = AutoBalancedChannel::builder(template())
+                        .with_static_addresses(addrs.clone())
+                        .with_max_concurrent_connects(*concurrency)
+                        .build_with_sender(sender);
+                    assert!(channel.wait_for_min_endpoints(addrs.len()).await);
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, build_endpoints, register_endpoints);
+criterion_main!(benches);
@@ -0,0 +1,807 @@
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerLayer};
+use crate::endpoint_template::EndpointTemplate;
+use crate::failure::is_connection_failure;
+#[cfg(feature = "grpc-web")]
+use crate::grpc_web::GrpcWebChannel;
+use crate::proxy::{self, Connector};
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    future::Future,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use http::{HeaderMap, Request, Response};
+use rand::{rngs::StdRng, SeedableRng};
+use tokio::{
+    sync::{
+        mpsc::{self, Receiver, UnboundedReceiver},
+        watch, Notify,
+    },
+    time::{Instant, Sleep},
+};
+use tokio_stream::Stream;
+use tonic::{
+    body::BoxBody,
+    transport::{Body, Channel, Endpoint},
+    Status,
+};
+use tower::{
+    balance::p2c::Balance,
+    buffer::Buffer,
+    discover::{Change, Discover},
+    load::{self, CompleteOnResponse, PendingRequestsDiscover},
+    util::{BoxService, Either},
+    BoxError, Layer, Service,
+};
+
+const BUFFER_SIZE: usize = 1024;
+
+type BoxedBalance = BoxService<Request<BoxBody>, Response<Body>, BoxError>;
+
+/// When each endpoint was last sent a request, for closing idle connections.
+pub(crate) type LastUsed = Arc<Mutex<HashMap<SocketAddr, Instant>>>;
+
+/// How many requests are in flight to each endpoint, for load observability
+/// and for deferring the removal of endpoints until none is.
+#[derive(Clone, Default)]
+pub(crate) struct RequestTracker(Arc<Mutex<TrackerState>>);
+
+#[derive(Default)]
+struct TrackerState {
+    retiring: HashSet<SocketAddr>,
+    in_flight: HashMap<SocketAddr, usize>,
+}
+
+impl RequestTracker {
+    fn lock(&self) -> std::sync::MutexGuard<'_, TrackerState> {
+        self.0.lock().expect("request tracker lock poisoned")
+    }
+
+    /// Stops sending new requests to `addr`.
+    pub(crate) fn retire(&self, addr: SocketAddr) {
+        self.lock().retiring.insert(addr);
+    }
+
+    /// Sends requests to `addr` again, or forgets it once removed.
+    pub(crate) fn reinstate(&self, addr: SocketAddr) {
+        self.lock().retiring.remove(&addr);
+    }
+
+    fn is_retiring(&self, addr: SocketAddr) -> bool {
+        self.lock().retiring.contains(&addr)
+    }
+
+    pub(crate) fn is_idle(&self, addr: SocketAddr) -> bool {
+        !self.lock().in_flight.contains_key(&addr)
+    }
+
+    /// Requests in flight to each endpoint with any.
+    pub(crate) fn in_flight(&self) -> HashMap<SocketAddr, usize> {
+        self.lock().in_flight.clone()
+    }
+
+    /// Counts a request to `addr` as in flight until the guard is dropped.
+    pub(crate) fn start(&self, addr: SocketAddr) -> InFlight {
+        *self.lock().in_flight.entry(addr).or_default() += 1;
+        InFlight {
+            tracker: self.clone(),
+            addr,
+        }
+    }
+}
+
+pub(crate) struct InFlight {
+    tracker: RequestTracker,
+    addr: SocketAddr,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut state = self.tracker.lock();
+        if let Some(count) = state.in_flight.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                state.in_flight.remove(&self.addr);
+            }
+        }
+    }
+}
+
+/// Endpoints whose connection was lost, reported by the balanced channel for
+/// discovery to take them out until they connect again.
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionLosses(Arc<LossState>);
+
+#[derive(Default)]
+struct LossState {
+    lost: Mutex<HashSet<SocketAddr>>,
+    reported: Notify,
+}
+
+impl ConnectionLosses {
+    fn report(&self, addr: SocketAddr) {
+        self.0
+            .lost
+            .lock()
+            .expect("connection losses lock poisoned")
+            .insert(addr);
+        self.0.reported.notify_one();
+    }
+
+    /// Waits until a loss is reported, unless one already was since the last
+    /// call to [`Self::take`].
+    pub(crate) async fn reported(&self) {
+        self.0.reported.notified().await;
+    }
+
+    /// Endpoints reported lost since the last call.
+    pub(crate) fn take(&self) -> HashSet<SocketAddr> {
+        std::mem::take(&mut *self.0.lost.lock().expect("connection losses lock poisoned"))
+    }
+}
+
+/// Weight of the endpoints with each IP address, as computed by discovery.
+pub(crate) type EndpointWeights = Arc<watch::Sender<HashMap<IpAddr, u32>>>;
+
+/// What the balanced channel tracks about its endpoints for discovery, and
+/// the weights discovery gives them for balancing, each only if a feature
+/// needs it.
+#[derive(Clone, Default)]
+pub(crate) struct EndpointTracking {
+    pub(crate) last_used: Option<LastUsed>,
+    pub(crate) requests: Option<RequestTracker>,
+    pub(crate) losses: Option<ConnectionLosses>,
+    pub(crate) weights: Option<EndpointWeights>,
+}
+
+/// Load of the endpoints with an IP address, see
+/// [`AutoBalancedChannel::endpoint_load`](crate::AutoBalancedChannel::endpoint_load).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Load {
+    /// Requests sent whose response hasn't started yet. This is the load
+    /// [`BalancePolicy::LeastPendingRequests`] compares, too.
+    pub in_flight: usize,
+}
+
+/// Strategy used to pick an endpoint for each request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BalancePolicy {
+    /// Pick two random ready endpoints and send the request to the less
+    /// loaded one. Like in tonic's own balanced channel, every endpoint counts
+    /// as equally loaded, so this amounts to picking a random ready endpoint.
+    /// The default.
+    #[default]
+    PowerOfTwoChoices,
+    /// Pick two random ready endpoints and send the request to the one with
+    /// fewer pending requests, see [`Load::in_flight`].
+    LeastPendingRequests,
+    /// Cycle through ready endpoints in order, so that sequential requests are
+    /// spread regardless of load. Each endpoint gets as many requests in a row
+    /// as its weight, at least one, see
+    /// [`AutoBalancedChannel::endpoint_weight`](crate::AutoBalancedChannel::endpoint_weight).
+    RoundRobin,
+}
+
+/// A tonic-compatible channel balancing requests over the endpoints discovered
+/// by an [`AutoBalancedChannel`](crate::AutoBalancedChannel).
+///
+/// Like tonic's `Channel`, it is backed by a buffer, so cloning it is cheap and
+/// encouraged.
+///
+/// Message compression is negotiated by the generated client rather than the
+/// channel, so configure it there, e.g. with
+/// `FooClient::new(channel).send_compressed(CompressionEncoding::Gzip)`
+/// (requires tonic's `gzip` feature).
+///
+/// With the `grpc-web` feature, it can speak gRPC-Web to backends serving only
+/// that, see `EndpointTemplate::grpc_web`.
+#[derive(Clone)]
+pub struct BalancedChannel {
+    svc: Buffer<BoxedBalance, Request<BoxBody>>,
+    default_metadata: Arc<HeaderMap>,
+    deadline: Option<Duration>,
+}
+
+impl BalancedChannel {
+    pub(crate) fn new(
+        changes: Receiver<Change<SocketAddr, Endpoint>>,
+        policy: BalancePolicy,
+        endpoint_template: &EndpointTemplate,
+        tracking: EndpointTracking,
+        circuit_breaker: Option<CircuitBreakerLayer>,
+        deadline: Option<Duration>,
+        rng_seed: Option<u64>,
+    ) -> Self {
+        let weight: Weight<SocketAddr> = match tracking.weights.as_ref() {
+            Some(weights) => {
+                let weights = weights.subscribe();
+                Box::new(move |addr: &SocketAddr| {
+                    weights.borrow().get(&addr.ip()).copied().unwrap_or(1)
+                })
+            }
+            None => Box::new(|_: &SocketAddr| 1),
+        };
+        let discover = EndpointDiscover {
+            changes: forward_changes(changes),
+            tracking,
+            circuit_breaker,
+            connector: endpoint_template.connector(),
+            #[cfg(feature = "grpc-web")]
+            grpc_web: endpoint_template.is_grpc_web(),
+        };
+        let (svc, worker) = Buffer::pair(balancer(discover, policy, rng_seed, weight), BUFFER_SIZE);
+        tokio::spawn(worker);
+
+        Self {
+            svc,
+            default_metadata: Arc::new(endpoint_template.metadata().clone()),
+            deadline,
+        }
+    }
+}
+
+/// Weight of the endpoint with the given key, for the policies using them.
+type Weight<K> = Box<dyn Fn(&K) -> u32 + Send>;
+
+/// Balances requests over the services from `discover` according to `policy`.
+fn balancer<D, Req>(
+    discover: D,
+    policy: BalancePolicy,
+    rng_seed: Option<u64>,
+    weight: Weight<D::Key>,
+) -> BoxService<Req, <D::Service as Service<Req>>::Response, BoxError>
+where
+    D: Discover + Unpin + Send + 'static,
+    D::Key: Hash + Clone + Send,
+    D::Error: Into<BoxError>,
+    D::Service: Service<Req> + Send + 'static,
+    <D::Service as Service<Req>>::Response: 'static,
+    <D::Service as Service<Req>>::Error: Into<BoxError>,
+    <D::Service as Service<Req>>::Future: Send + 'static,
+    Req: Send + 'static,
+{
+    match policy {
+        BalancePolicy::PowerOfTwoChoices => {
+            power_of_two_choices(load::Constant::new(discover, 0), rng_seed)
+        }
+        BalancePolicy::LeastPendingRequests => power_of_two_choices(
+            PendingRequestsDiscover::new(discover, CompleteOnResponse::default()),
+            rng_seed,
+        ),
+        BalancePolicy::RoundRobin => BoxService::new(RoundRobin::new(discover, weight)),
+    }
+}
+
+fn power_of_two_choices<D, Req>(
+    discover: D,
+    rng_seed: Option<u64>,
+) -> BoxService<Req, <D::Service as Service<Req>>::Response, BoxError>
+where
+    D: Discover + Unpin + Send + 'static,
+    D::Key: Hash + Clone + Send,
+    D::Error: Into<BoxError>,
+    D::Service: Service<Req> + load::Load + Send + 'static,
+    <D::Service as load::Load>::Metric: fmt::Debug,
+    <D::Service as Service<Req>>::Response: 'static,
+    <D::Service as Service<Req>>::Error: Into<BoxError>,
+    <D::Service as Service<Req>>::Future: Send + 'static,
+    Req: Send + 'static,
+{
+    let balance = match rng_seed {
+        Some(seed) => Balance::from_rng(discover, StdRng::seed_from_u64(seed))
+            .expect("seeded RNG never fails"),
+        None => Balance::new(discover),
+    };
+    BoxService::new(balance)
+}
+
+impl Service<Request<BoxBody>> for BalancedChannel {
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.svc.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<BoxBody>) -> Self::Future {
+        let headers = request.headers_mut();
+        for key in self.default_metadata.keys() {
+            if !headers.contains_key(key) {
+                for value in self.default_metadata.get_all(key) {
+                    headers.append(key.clone(), value.clone());
+                }
+            }
+        }
+
+        let response = self.svc.call(request);
+        let Some(deadline) = self.deadline else {
+            return Box::pin(response);
+        };
+        // Covers waiting for a ready endpoint as well as the call itself. A
+        // `Status` is found by tonic in the error chain and passed on as is.
+        Box::pin(async move {
+            tokio::time::timeout(deadline, response)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(Status::deadline_exceeded("request deadline elapsed").into())
+                })
+        })
+    }
+}
+
+impl fmt::Debug for BalancedChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BalancedChannel").finish()
+    }
+}
+
+/// Moves endpoint changes into an unbounded queue as soon as the discovery
+/// loop sends them. The balancers only poll discovery when serving requests,
+/// so without this an idle channel would leave the loop waiting for room in
+/// the bounded channel it sends to.
+///
+/// Stops once the returned receiver is dropped, closing `changes` so that the
+/// discovery loop notices the balanced channel is gone.
+fn forward_changes(
+    mut changes: Receiver<Change<SocketAddr, Endpoint>>,
+) -> UnboundedReceiver<Change<SocketAddr, Endpoint>> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(change) = tokio::select! {
+            change = changes.recv() => change,
+            _ = sender.closed() => None,
+        } {
+            if sender.send(change).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Turns endpoint changes sent by the discovery loop into lazily connected
+/// channels the balancers can route to, behind a circuit breaker if enabled.
+struct EndpointDiscover {
+    changes: UnboundedReceiver<Change<SocketAddr, Endpoint>>,
+    tracking: EndpointTracking,
+    circuit_breaker: Option<CircuitBreakerLayer>,
+    connector: Option<Connector>,
+    #[cfg(feature = "grpc-web")]
+    grpc_web: bool,
+}
+
+impl EndpointDiscover {
+    fn connect(&self, endpoint: &Endpoint, addr: SocketAddr) -> EndpointChannel {
+        let channel = proxy::connect_lazy(endpoint, self.connector.as_ref(), addr);
+        #[cfg(feature = "grpc-web")]
+        if self.grpc_web {
+            return EndpointChannel::GrpcWeb(GrpcWebChannel::new(channel));
+        }
+        EndpointChannel::Grpc(channel)
+    }
+}
+
+type EndpointService = Either<CircuitBreaker<TrackedChannel>, TrackedChannel>;
+
+impl Stream for EndpointDiscover {
+    type Item = Result<Change<SocketAddr, EndpointService>, BoxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.changes.poll_recv(cx) {
+            // The balancers don't expect discovery to ever end, so a closed
+            // channel just means there will be no more changes.
+            Poll::Pending | Poll::Ready(None) => Poll::Pending,
+            Poll::Ready(Some(Change::Insert(key, endpoint))) => {
+                let channel = TrackedChannel {
+                    channel: self.connect(&endpoint, key),
+                    addr: key,
+                    tracking: self.tracking.clone(),
+                    lost: Arc::new(AtomicBool::new(false)),
+                };
+                let service = match &self.circuit_breaker {
+                    Some(circuit_breaker) => Either::A(circuit_breaker.layer(channel)),
+                    None => Either::B(channel),
+                };
+                Poll::Ready(Some(Ok(Change::Insert(key, service))))
+            }
+            Poll::Ready(Some(Change::Remove(key))) => Poll::Ready(Some(Ok(Change::Remove(key)))),
+        }
+    }
+}
+
+/// Channel to a single endpoint, recording when it's used if idle connections
+/// are to be closed, its requests in flight if removals are deferred, and
+/// whether its connection was lost if that triggers rediscovery.
+struct TrackedChannel {
+    channel: EndpointChannel,
+    addr: SocketAddr,
+    tracking: EndpointTracking,
+    /// Set once a request failed at the connection level.
+    lost: Arc<AtomicBool>,
+}
+
+impl Service<Request<BoxBody>> for TrackedChannel {
+    type Response = Response<Body>;
+    type Error = tonic::transport::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Never ready again, so that the balancers route around it until it's
+        // removed.
+        if let Some(requests) = &self.tracking.requests {
+            if requests.is_retiring(self.addr) {
+                return Poll::Pending;
+            }
+        }
+        if self.lost.load(Ordering::Acquire) {
+            return Poll::Pending;
+        }
+        self.channel.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<BoxBody>) -> Self::Future {
+        if let Some(last_used) = &self.tracking.last_used {
+            last_used
+                .lock()
+                .expect("last used lock poisoned")
+                .insert(self.addr, Instant::now());
+        }
+        let in_flight = self
+            .tracking
+            .requests
+            .as_ref()
+            .map(|requests| requests.start(self.addr));
+        let loss = self
+            .tracking
+            .losses
+            .clone()
+            .map(|losses| (losses, self.lost.clone(), self.addr));
+        let response = self.channel.call(request);
+        Box::pin(async move {
+            let response = response.await;
+            drop(in_flight);
+            if let (Err(error), Some((losses, lost, addr))) = (&response, loss) {
+                if is_connection_failure(error) && !lost.swap(true, Ordering::AcqRel) {
+                    tracing::debug!(endpoint = %addr, %error, "lost connection to endpoint");
+                    losses.report(addr);
+                }
+            }
+            response
+        })
+    }
+}
+
+/// Connection to a single endpoint, over gRPC or, if the template says so,
+/// gRPC-Web.
+enum EndpointChannel {
+    Grpc(Channel),
+    #[cfg(feature = "grpc-web")]
+    GrpcWeb(GrpcWebChannel),
+}
+
+impl EndpointChannel {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), tonic::transport::Error>> {
+        match self {
+            Self::Grpc(channel) => channel.poll_ready(cx),
+            #[cfg(feature = "grpc-web")]
+            Self::GrpcWeb(channel) => channel.poll_ready(cx),
+        }
+    }
+
+    fn call(
+        &mut self,
+        request: Request<BoxBody>,
+    ) -> Pin<Box<dyn Future<Output = Result<Response<Body>, tonic::transport::Error>> + Send>> {
+        match self {
+            Self::Grpc(channel) => Box::pin(channel.call(request)),
+            #[cfg(feature = "grpc-web")]
+            Self::GrpcWeb(channel) => channel.call(request),
+        }
+    }
+}
+
+/// How long the round robin balancer waits before polling failed services
+/// again when no service is ready, as nothing else would wake it.
+const FAILED_SERVICE_RETRY: Duration = Duration::from_millis(100);
+
+/// Balancer sending each request to the next ready service in turn, as many
+/// requests in a row as the service's weight.
+struct RoundRobin<D: Discover> {
+    discover: D,
+    weight: Weight<D::Key>,
+    services: Vec<(D::Key, D::Service)>,
+    next: usize,
+    /// Requests sent to the service at `next` in its current turn.
+    served: u32,
+    ready_index: Option<usize>,
+    retry_failed: Option<Pin<Box<Sleep>>>,
+}
+
+impl<D: Discover> RoundRobin<D> {
+    fn new(discover: D, weight: Weight<D::Key>) -> Self {
+        Self {
+            discover,
+            weight,
+            services: Vec::new(),
+            next: 0,
+            served: 0,
+            ready_index: None,
+            retry_failed: None,
+        }
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.services.remove(index);
+        if self.next > index {
+            self.next -= 1;
+        }
+    }
+}
+
+impl<D> RoundRobin<D>
+where
+    D: Discover + Unpin,
+    D::Error: Into<BoxError>,
+{
+    fn update_from_discover(&mut self, cx: &mut Context<'_>) -> Result<(), BoxError> {
+        while let Poll::Ready(Some(change)) = Pin::new(&mut self.discover).poll_discover(cx) {
+            match change.map_err(Into::into)? {
+                Change::Insert(key, service) => {
+                    if let Some(index) = self.services.iter().position(|(k, _)| *k == key) {
+                        self.remove(index);
+                    }
+                    self.services.push((key, service));
+                }
+                Change::Remove(key) => {
+                    if let Some(index) = self.services.iter().position(|(k, _)| *k == key) {
+                        self.remove(index);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D, Req> Service<Req> for RoundRobin<D>
+where
+    D: Discover + Unpin,
+    D::Error: Into<BoxError>,
+    D::Service: Service<Req>,
+    <D::Service as Service<Req>>::Error: Into<BoxError>,
+    <D::Service as Service<Req>>::Future: Send + 'static,
+{
+    type Response = <D::Service as Service<Req>>::Response;
+    type Error = BoxError;
+    type Future =
+        Pin<Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Discovery may reorder services, so any previous pick is void.
+        self.ready_index = None;
+        self.update_from_discover(cx)?;
+
+        let mut failed = false;
+        for checked in 0..self.services.len() {
+            let index = (self.next + checked) % self.services.len();
+            match self.services[index].1.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    self.retry_failed = None;
+                    self.ready_index = Some(index);
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Pending => {}
+                Poll::Ready(Err(error)) => {
+                    // Skipped rather than evicted, so that it's back in turn
+                    // once it's ready again.
+                    tracing::debug!(
+                        error = %error.into(),
+                        "skipping failed service in round robin balancer"
+                    );
+                    failed = true;
+                }
+            }
+        }
+
+        // Every pending service (if any) registered the waker, as did
+        // discovery, but failed ones didn't.
+        if failed {
+            let retry = self
+                .retry_failed
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(FAILED_SERVICE_RETRY)));
+            if retry.as_mut().poll(cx).is_ready() {
+                self.retry_failed = None;
+                cx.waker().wake_by_ref();
+            }
+        }
+        Poll::Pending
+    }
+
+    fn call(&mut self, request: Req) -> Self::Future {
+        let index = self.ready_index.take().expect("called before being ready");
+        let served = if index == self.next % self.services.len() {
+            self.served + 1
+        } else {
+            1
+        };
+        if served < (self.weight)(&self.services[index].0) {
+            self.next = index;
+            self.served = served;
+        } else {
+            self.next = index + 1;
+            self.served = 0;
+        }
+        let future = self.services[index].1.call(request);
+        Box::pin(async move { future.await.map_err(Into::into) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        future::{ready, Ready},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use tokio_stream::StreamExt;
+    use tower::{
+        discover::{Change, Discover},
+        BoxError, Service, ServiceExt,
+    };
+
+    use super::{balancer, BalancePolicy, Weight};
+
+    /// Service answering with its name, failing to get ready while `failing`
+    /// is set.
+    struct Named {
+        name: &'static str,
+        failing: Arc<AtomicBool>,
+    }
+
+    impl Service<()> for Named {
+        type Response = &'static str;
+        type Error = BoxError;
+        type Future = Ready<Result<&'static str, BoxError>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Poll::Ready(Err("not ready".into()));
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: ()) -> Self::Future {
+            ready(Ok(self.name))
+        }
+    }
+
+    fn discover(
+        services: &[(&'static str, &Arc<AtomicBool>)],
+    ) -> impl Discover<Key = &'static str, Service = Named, Error = Infallible> + Send + Unpin {
+        let changes: Vec<Result<_, Infallible>> = services
+            .iter()
+            .map(|(name, failing)| {
+                let failing = Arc::clone(failing);
+                Ok(Change::Insert(*name, Named { name, failing }))
+            })
+            .collect();
+        // Discovery never ends.
+        tokio_stream::iter(changes).chain(tokio_stream::pending())
+    }
+
+    fn unweighted() -> Weight<&'static str> {
+        Box::new(|_| 1)
+    }
+
+    #[tokio::test]
+    async fn round_robin_takes_back_failed_services_once_ready() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let healthy = Arc::new(AtomicBool::new(false));
+        let mut balancer = balancer(
+            discover(&[("a", &failing), ("b", &healthy)]),
+            BalancePolicy::RoundRobin,
+            None,
+            unweighted(),
+        );
+
+        let response = balancer.ready().await.unwrap().call(()).await.unwrap();
+        assert_eq!(response, "b");
+
+        failing.store(false, Ordering::SeqCst);
+        let mut responses = Vec::new();
+        for _ in 0..4 {
+            responses.push(balancer.ready().await.unwrap().call(()).await.unwrap());
+        }
+        assert_eq!(responses, ["a", "b", "a", "b"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn round_robin_retries_failed_services_when_none_is_ready() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let mut balancer = balancer(
+            discover(&[("a", &failing)]),
+            BalancePolicy::RoundRobin,
+            None,
+            unweighted(),
+        );
+
+        let recovering = failing.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            recovering.store(false, Ordering::SeqCst);
+        });
+        let ready = tokio::time::timeout(Duration::from_secs(1), balancer.ready()).await;
+        assert!(ready.is_ok(), "failed service was never polled again");
+    }
+
+    /// Sends requests without completing any, so that they all stay pending,
+    /// and counts how many each service got.
+    #[rstest::rstest]
+    #[case::power_of_two_choices(BalancePolicy::PowerOfTwoChoices, false)]
+    #[case::least_pending_requests(BalancePolicy::LeastPendingRequests, true)]
+    #[tokio::test]
+    async fn pending_requests_are_weighed_by_policy(
+        #[case] policy: BalancePolicy,
+        #[case] balanced: bool,
+    ) {
+        let healthy = Arc::new(AtomicBool::new(false));
+        let mut balancer = balancer(
+            discover(&[("a", &healthy), ("b", &healthy)]),
+            policy,
+            Some(7),
+            unweighted(),
+        );
+
+        let mut pending = Vec::new();
+        for _ in 0..20 {
+            pending.push(balancer.ready().await.unwrap().call(()));
+        }
+        let mut to_a = 0_usize;
+        for response in pending {
+            if response.await.unwrap() == "a" {
+                to_a += 1;
+            }
+        }
+        let to_b = 20 - to_a;
+        assert_eq!(
+            to_a.abs_diff(to_b) <= 1,
+            balanced,
+            "{to_a} to a, {to_b} to b"
+        );
+    }
+
+    #[tokio::test]
+    async fn round_robin_splits_traffic_by_weight() {
+        let healthy = Arc::new(AtomicBool::new(false));
+        let mut balancer = balancer(
+            discover(&[("a", &healthy), ("b", &healthy)]),
+            BalancePolicy::RoundRobin,
+            None,
+            Box::new(|name| if *name == "a" { 3 } else { 1 }),
+        );
+
+        let mut responses = Vec::new();
+        for _ in 0..8 {
+            responses.push(balancer.ready().await.unwrap().call(()).await.unwrap());
+        }
+        assert_eq!(responses, ["a", "a", "a", "b", "a", "a", "a", "b"]);
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    error::Error,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use tokio::time::{Instant, Sleep};
+use tower::{Layer, Service};
+
+use crate::failure::is_connection_failure;
+
+/// Settings of the per-endpoint circuit breaker enabled with
+/// [`AutoBalancedChannelBuilder::with_circuit_breaker`](crate::AutoBalancedChannelBuilder::with_circuit_breaker).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    /// Requests failing at the connection level in a row after which the
+    /// breaker opens.
+    pub failure_threshold: u32,
+    /// How long an open breaker keeps requests away from its endpoint before
+    /// letting a single one through to test whether it recovered.
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Wraps each endpoint's service in a [`CircuitBreaker`].
+#[derive(Clone, Debug)]
+pub(crate) struct CircuitBreakerLayer {
+    config: CircuitBreakerConfig,
+}
+
+impl CircuitBreakerLayer {
+    pub(crate) fn new(config: CircuitBreakerConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl<S> Layer<S> for CircuitBreakerLayer {
+    type Service = CircuitBreaker<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CircuitBreaker {
+            inner,
+            config: self.config,
+            state: Arc::new(Mutex::new(BreakerState {
+                phase: Phase::Closed { failures: 0 },
+                waker: None,
+            })),
+            cooldown: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// Letting a single request through, which decides whether to close or
+    /// open again.
+    HalfOpen {
+        trial_in_flight: bool,
+    },
+}
+
+struct BreakerState {
+    phase: Phase,
+    /// Woken once the trial request of a half-open breaker completes.
+    waker: Option<Waker>,
+}
+
+/// Lets another request through a half-open breaker if its trial request is
+/// dropped before completing, e.g. cancelled by the client or by a deadline,
+/// as that didn't tell whether the endpoint recovered.
+struct TrialGuard {
+    /// Unset once the trial completed.
+    state: Option<Arc<Mutex<BreakerState>>>,
+}
+
+impl TrialGuard {
+    fn complete(mut self) {
+        self.state = None;
+    }
+}
+
+impl Drop for TrialGuard {
+    fn drop(&mut self) {
+        let Some(state) = self.state.take() else {
+            return;
+        };
+        let mut state = state.lock().expect("circuit breaker lock poisoned");
+        if let Phase::HalfOpen {
+            trial_in_flight: true,
+        } = state.phase
+        {
+            tracing::debug!("circuit breaker trial cancelled");
+            state.phase = Phase::HalfOpen {
+                trial_in_flight: false,
+            };
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Service never ready while its circuit is open, so that the balancers route
+/// around its endpoint. Only connection failures count, see
+/// [`is_connection_failure`].
+pub(crate) struct CircuitBreaker<S> {
+    inner: S,
+    config: CircuitBreakerConfig,
+    /// Shared with the response futures, which record the outcomes.
+    state: Arc<Mutex<BreakerState>>,
+    /// Wakes the balancer up once the cooldown is over.
+    cooldown: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S, Request> Service<Request> for CircuitBreaker<S>
+where
+    S: Service<Request>,
+    S::Error: Error + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        {
+            let mut state = self.state.lock().expect("circuit breaker lock poisoned");
+            match state.phase {
+                Phase::Closed { .. }
+                | Phase::HalfOpen {
+                    trial_in_flight: false,
+                } => {}
+                Phase::Open { until } => {
+                    let cooldown = self
+                        .cooldown
+                        .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(until)));
+                    if cooldown.deadline() != until {
+                        cooldown.as_mut().reset(until);
+                    }
+                    if cooldown.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    tracing::debug!("circuit breaker half-open");
+                    state.phase = Phase::HalfOpen {
+                        trial_in_flight: false,
+                    };
+                }
+                Phase::HalfOpen {
+                    trial_in_flight: true,
+                } => {
+                    state.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut trial = TrialGuard { state: None };
+        {
+            let mut state = self.state.lock().expect("circuit breaker lock poisoned");
+            if let Phase::HalfOpen { .. } = state.phase {
+                state.phase = Phase::HalfOpen {
+                    trial_in_flight: true,
+                };
+                trial.state = Some(self.state.clone());
+            }
+        }
+        let response = self.inner.call(request);
+        let state = self.state.clone();
+        let config = self.config;
+        Box::pin(async move {
+            let response = response.await;
+            trial.complete();
+            let succeeded = match &response {
+                Ok(_) => true,
+                Err(error) => !is_connection_failure(error),
+            };
+            let mut state = state.lock().expect("circuit breaker lock poisoned");
+            state.phase = match (state.phase, succeeded) {
+                (Phase::HalfOpen { .. }, true) => {
+                    tracing::debug!("circuit breaker closed");
+                    Phase::Closed { failures: 0 }
+                }
+                (_, true) => Phase::Closed { failures: 0 },
+                // Requests sent before the breaker opened may still fail.
+                (Phase::Open { until }, false) => Phase::Open { until },
+                (Phase::Closed { failures }, false) if failures + 1 < config.failure_threshold => {
+                    Phase::Closed {
+                        failures: failures + 1,
+                    }
+                }
+                (_, false) => {
+                    tracing::debug!("circuit breaker open");
+                    Phase::Open {
+                        until: Instant::now() + config.cooldown,
+                    }
+                }
+            };
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+            drop(state);
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::pending,
+        io,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use sequential_test::sequential;
+    use tokio::time::Instant;
+    use tower::{service_fn, Layer, Service, ServiceExt};
+
+    use super::{CircuitBreakerConfig, CircuitBreakerLayer};
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn trips_and_recovers_after_cooldown() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let endpoint = {
+            let failing = failing.clone();
+            service_fn(move |()| {
+                let failing = failing.load(Ordering::SeqCst);
+                async move {
+                    match failing {
+                        true => Err(io::Error::from(io::ErrorKind::ConnectionRefused)),
+                        false => Ok(()),
+                    }
+                }
+            })
+        };
+        let mut breaker = CircuitBreakerLayer::new(CircuitBreakerConfig {
+            failure_threshold: 3,
+            cooldown: Duration::from_secs(10),
+        })
+        .layer(endpoint);
+
+        for _ in 0..3 {
+            let response = breaker.ready().await.unwrap().call(()).await;
+            assert!(response.is_err());
+        }
+
+        // Open until the cooldown is over, then a failed trial opens it again.
+        let started = Instant::now();
+        assert!(breaker.ready().await.unwrap().call(()).await.is_err());
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+        breaker.ready().await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_secs(20));
+
+        // Half-open, letting a single trial through at a time.
+        failing.store(false, Ordering::SeqCst);
+        let trial = breaker.call(());
+        let second = tokio::time::timeout(Duration::from_secs(1), breaker.ready()).await;
+        assert!(second.is_err(), "ready during the trial");
+        trial.await.unwrap();
+
+        // Closed again.
+        for _ in 0..5 {
+            breaker.ready().await.unwrap().call(()).await.unwrap();
+        }
+        assert_eq!(started.elapsed(), Duration::from_secs(21));
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn only_connection_failures_trip() {
+        let endpoint = service_fn(|()| async { Err::<(), _>(io::Error::other("timed out")) });
+        let mut breaker = CircuitBreakerLayer::new(CircuitBreakerConfig {
+            failure_threshold: 1,
+            cooldown: Duration::from_secs(10),
+        })
+        .layer(endpoint);
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            let response = breaker.ready().await.unwrap().call(()).await;
+            assert!(response.is_err());
+        }
+        assert_eq!(started.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn cancelled_trial_lets_another_through() {
+        let hanging = Arc::new(AtomicBool::new(false));
+        let endpoint = {
+            let hanging = hanging.clone();
+            service_fn(move |()| {
+                let hanging = hanging.load(Ordering::SeqCst);
+                async move {
+                    if hanging {
+                        pending::<()>().await;
+                    }
+                    Err::<(), _>(io::Error::from(io::ErrorKind::ConnectionRefused))
+                }
+            })
+        };
+        let mut breaker = CircuitBreakerLayer::new(CircuitBreakerConfig {
+            failure_threshold: 1,
+            cooldown: Duration::from_secs(10),
+        })
+        .layer(endpoint);
+        assert!(breaker.ready().await.unwrap().call(()).await.is_err());
+
+        // The trial hangs until cancelled, e.g. by a deadline.
+        hanging.store(true, Ordering::SeqCst);
+        let trial = breaker.ready().await.unwrap().call(());
+        let trial = tokio::time::timeout(Duration::from_secs(1), trial).await;
+        assert!(trial.is_err());
+
+        let next = tokio::time::timeout(Duration::from_secs(1), breaker.ready()).await;
+        assert!(
+            next.is_ok(),
+            "stuck half-open after the trial was cancelled"
+        );
+    }
+}
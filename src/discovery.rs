@@ -0,0 +1,1432 @@
+use crate::balance::{ConnectionLosses, EndpointWeights, LastUsed, RequestTracker};
+use crate::dynamic_channel::{
+    AddressOrder, ChannelSnapshot, DnsStatus, EndpointTagger, EndpointTags, ErrorClassifier,
+    ErrorDisposition, Health, HealthDerivation, ResolutionError, ResolutionRecord, RuntimeConfig,
+};
+use crate::endpoint_template::EndpointTemplate;
+use crate::events::{EndpointEvent, HealthEvent};
+use crate::health_check::HealthChecker;
+use crate::proxy;
+use crate::resolver::Resolver;
+use crate::sink::{DeltaSender, EndpointDelta};
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::ControlFlow,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use ipnet::IpNet;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng};
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, mpsc, oneshot, watch, Notify},
+    task::{JoinHandle, JoinSet},
+    time::{Instant, MissedTickBehavior},
+};
+use tonic::transport::{Endpoint, Uri};
+use tower::discover::Change;
+
+/// State of the background task that periodically resolves the template's
+/// domain and keeps the balanced channel's endpoints in sync with the result.
+///
+/// All timing goes through `tokio::time`, so tests can pause and advance time
+/// instead of sleeping. Only the timestamps of the resolution history use the
+/// wall clock.
+pub(crate) struct Discovery {
+    pub(crate) endpoint_template: watch::Receiver<EndpointTemplate>,
+    /// Origin of new endpoints in place of the template's, currently only set
+    /// to the canonical name of the domain if requested.
+    pub(crate) origin: Option<Uri>,
+    #[cfg(feature = "async-resolver")]
+    pub(crate) follow_cnames: bool,
+    #[cfg(feature = "async-resolver")]
+    pub(crate) canonical_name_setter: watch::Sender<Option<String>>,
+    /// Notified to rebuild all endpoints with the current template.
+    pub(crate) rebuild: Arc<Notify>,
+    /// Notified to resolve ahead of the next interval.
+    pub(crate) refresh: Arc<Notify>,
+    /// When the next resolution is due, `None` if none is scheduled.
+    pub(crate) next_resolution_setter: watch::Sender<Option<Instant>>,
+    /// Number of resolutions made so far, whatever their outcome.
+    pub(crate) resolution_cycles_setter: watch::Sender<u64>,
+    /// Number of endpoint changes the balanced channel failed to take.
+    pub(crate) send_failures_setter: watch::Sender<u64>,
+    /// Resolution is skipped while set, keeping the current endpoints.
+    pub(crate) paused: watch::Receiver<bool>,
+    pub(crate) resolver: Arc<dyn Resolver>,
+    /// Runtime-tunable settings, applied to `interval` and `disable_ipv6` at
+    /// the top of each cycle.
+    pub(crate) config: watch::Receiver<RuntimeConfig>,
+    pub(crate) interval: Duration,
+    pub(crate) dns_timeout: Option<Duration>,
+    pub(crate) unmap_ipv4_mapped: bool,
+    pub(crate) disable_ipv6: bool,
+    /// Destination whose route decides whether to ignore IPv6 addresses, see
+    /// [`Self::check_ipv6_route`].
+    pub(crate) ipv6_route_target: Option<Ipv6Addr>,
+    pub(crate) ipv6_unroutable: bool,
+    pub(crate) min_healthy_endpoints: usize,
+    /// Replaces the default health rules, if set.
+    pub(crate) health_derivation: Option<HealthDerivation>,
+    pub(crate) result_sanity_limit: Option<usize>,
+    pub(crate) refuse_oversized_results: bool,
+    pub(crate) retain_on_empty: bool,
+    pub(crate) static_addrs: Option<Vec<SocketAddr>>,
+    /// Resolve `localhost` to the loopback addresses without the resolver.
+    pub(crate) loopback_localhost: bool,
+    /// Number of recent resolutions whose addresses are all kept.
+    pub(crate) accumulation_window: usize,
+    /// How long the results of a resolution are kept at most, however
+    /// recent in cycles.
+    pub(crate) accumulation_ttl: Option<Duration>,
+    /// Results of the latest `accumulation_window` resolutions and when they
+    /// came in, newest last.
+    pub(crate) recent_results: VecDeque<(Instant, HashSet<SocketAddr>)>,
+    /// Order in which new endpoints are inserted.
+    pub(crate) address_order: AddressOrder,
+    /// Position of each address in the latest successful resolution.
+    pub(crate) resolution_order: HashMap<SocketAddr, usize>,
+    /// Source of all randomness, seeded if reproducibility was asked for.
+    pub(crate) rng: StdRng,
+    pub(crate) error_classifier: Option<ErrorClassifier>,
+    pub(crate) tagger: Option<EndpointTagger>,
+    pub(crate) tags_setter: watch::Sender<HashMap<IpAddr, EndpointTags>>,
+    pub(crate) subnet_weights: Vec<(IpNet, u32)>,
+    pub(crate) weights_setter: EndpointWeights,
+    pub(crate) sender: mpsc::Sender<Change<SocketAddr, Endpoint>>,
+    pub(crate) dns_status_setter: watch::Sender<DnsStatus>,
+    pub(crate) health_setter: watch::Sender<Health>,
+    pub(crate) delta_setter: watch::Sender<(usize, usize)>,
+    pub(crate) endpoint_count_setter: watch::Sender<usize>,
+    pub(crate) event_sender: broadcast::Sender<EndpointEvent>,
+    pub(crate) health_event_sender: broadcast::Sender<HealthEvent>,
+    /// Reports every update to the external sink, if there is one.
+    pub(crate) delta_sender: Option<DeltaSender>,
+    pub(crate) health_checker: Option<HealthChecker>,
+    pub(crate) connection_probe_interval: Option<Duration>,
+    pub(crate) max_concurrent_connects: usize,
+    pub(crate) max_connection_age: Option<Duration>,
+    /// When to recycle the connection of each endpoint, if there is a
+    /// maximum connection age.
+    pub(crate) recycle_at: HashMap<SocketAddr, Instant>,
+    pub(crate) idle_timeout: Option<Duration>,
+    /// Updated by the balanced channel whenever it uses an endpoint, if there
+    /// is an idle timeout.
+    pub(crate) last_used: Option<LastUsed>,
+    /// When each endpoint was last (re)inserted, if there is an idle timeout.
+    pub(crate) inserted_at: HashMap<SocketAddr, Instant>,
+    /// Set if removals are deferred until the endpoint is idle.
+    pub(crate) retirement: Option<RequestTracker>,
+    /// Endpoints removed from `endpoints` but not yet from the balanced
+    /// channel, as requests to them are still in flight.
+    pub(crate) retiring: HashSet<SocketAddr>,
+    /// Reported by the balanced channel, if lost connections trigger
+    /// rediscovery.
+    pub(crate) connection_losses: Option<ConnectionLosses>,
+    /// Resolved addresses taken out after losing their connection, until they
+    /// connect again.
+    pub(crate) disconnected: HashSet<SocketAddr>,
+    pub(crate) connection_failures_setter: watch::Sender<HashMap<SocketAddr, u64>>,
+    pub(crate) history_setter: watch::Sender<VecDeque<ResolutionRecord>>,
+    pub(crate) history_size: usize,
+    /// Published once per cycle, so that all its fields are consistent.
+    pub(crate) snapshot_setter: watch::Sender<ChannelSnapshot>,
+    pub(crate) last_success: Option<SystemTime>,
+    /// Same as `last_success`, on the monotonic clock.
+    pub(crate) last_success_at: Option<Instant>,
+    pub(crate) last_latency: Option<Duration>,
+    /// Receives the outcome of every resolution: the number of endpoints or
+    /// why it failed. `None` until the first one.
+    pub(crate) resolution_outcome: watch::Sender<Option<Result<usize, ResolutionError>>>,
+    /// Set when none of the endpoints could be connected to in the latest
+    /// connection probe.
+    pub(crate) last_connection_error: Option<String>,
+    /// Addresses from the latest successful resolution.
+    pub(crate) resolved: HashSet<SocketAddr>,
+    /// Weights the resolver gave the IPs of the latest successful resolution.
+    pub(crate) resolver_weights: HashMap<IpAddr, u32>,
+    /// Resolved addresses currently failing health checks.
+    pub(crate) ejected: HashSet<SocketAddr>,
+    /// Consecutive failed connection probes after which an endpoint is
+    /// drained, if enabled.
+    pub(crate) max_connection_failures: Option<u32>,
+    /// Consecutive failed connection probes of each address.
+    pub(crate) consecutive_failures: HashMap<SocketAddr, u32>,
+    /// Resolved addresses drained after too many failed connection probes.
+    pub(crate) unreachable: HashSet<SocketAddr>,
+    /// Addresses registered with the balanced channel.
+    pub(crate) endpoints: HashSet<SocketAddr>,
+}
+
+impl Discovery {
+    /// Registers `addrs` right away, before the first resolution, which then
+    /// replaces them like any other result.
+    pub(crate) fn seed(&mut self, addrs: Vec<SocketAddr>) {
+        for addr in addrs {
+            let Some(endpoint) =
+                build_endpoint(&self.endpoint_template, self.origin.as_ref(), addr)
+            else {
+                continue;
+            };
+            if self
+                .sender
+                .try_send(Change::Insert(addr, endpoint))
+                .is_err()
+            {
+                tracing::warn!(endpoint = %addr, "failed to register seed endpoint");
+                self.send_failures_setter
+                    .send_modify(|failures| *failures += 1);
+                continue;
+            }
+            self.resolved.insert(addr);
+            self.endpoints.insert(addr);
+            self.track_insertion(addr);
+        }
+        let count = self.endpoints.len();
+        self.endpoint_count_setter.send_replace(count);
+        self.update_endpoint_metadata();
+        self.publish_health();
+    }
+
+    /// Runs until `shutdown` fires, the balanced channel goes away or a fatal
+    /// resolution error occurs, and returns the number of endpoints removed on
+    /// the way out.
+    pub(crate) async fn run(mut self, mut shutdown: oneshot::Receiver<()>) -> usize {
+        self.check_ipv6_route().await;
+        let mut interval = tokio::time::interval(self.interval);
+        // Catching up on missed ticks would only resolve the same domain
+        // multiple times in a row.
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut health_check_interval = tokio::time::interval(
+            self.health_checker
+                .as_ref()
+                .map_or(self.interval, HealthChecker::interval),
+        );
+        let mut connection_probe_interval =
+            tokio::time::interval(self.connection_probe_interval.unwrap_or(self.interval));
+        // Idle connections are closed at most half the timeout late.
+        let mut idle_interval =
+            tokio::time::interval(self.idle_timeout.map_or(self.interval, |timeout| {
+                (timeout / 2).max(Duration::from_millis(1))
+            }));
+        let mut retirement_interval = tokio::time::interval(RETIREMENT_CHECK_INTERVAL);
+        // Forced resolutions are at least `REFRESH_DEBOUNCE` apart, the
+        // requests in between being coalesced into one.
+        let mut refresh_at: Option<Instant> = None;
+        let mut last_refresh: Option<Instant> = None;
+        // Tracked alongside `interval`, which doesn't tell.
+        let mut next_tick = Instant::now();
+        let mut resolved_once = false;
+        let mut final_health = Health::Broken;
+        loop {
+            if self.sender.is_closed() {
+                return 0;
+            }
+
+            let config = self.config.borrow_and_update().clone();
+            if config.interval != self.interval {
+                self.interval = config.interval;
+                next_tick = Instant::now() + self.interval;
+                interval = tokio::time::interval_at(next_tick, self.interval);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            }
+            self.disable_ipv6 = config.disable_ipv6;
+
+            let next_recycling = self.recycle_at.values().min().copied();
+            let paused = *self.paused.borrow_and_update();
+            let mut flow = ControlFlow::Continue(());
+            let mut resolved = false;
+            tokio::select! {
+                // Static addresses never change, so there is nothing to poll
+                // but whether lost connections are back.
+                _ = interval.tick(), if !paused && (!resolved_once || self.static_addrs.is_none() || !self.disconnected.is_empty()) => {
+                    next_tick = Instant::now() + self.interval;
+                    flow = self.resolve().await;
+                    resolved_once = true;
+                    resolved = true;
+                }
+                _ = health_check_interval.tick(), if self.health_checker.is_some() => {
+                    self.check_health().await;
+                }
+                _ = connection_probe_interval.tick(), if self.connection_probe_interval.is_some() => {
+                    self.probe_connections().await;
+                }
+                _ = tokio::time::sleep_until(next_recycling.unwrap_or_else(Instant::now)), if next_recycling.is_some() => {
+                    self.recycle_connections().await;
+                }
+                _ = idle_interval.tick(), if self.idle_timeout.is_some() => {
+                    self.close_idle_connections().await;
+                }
+                _ = retirement_interval.tick(), if !self.retiring.is_empty() => {
+                    self.remove_retired().await;
+                }
+                _ = self.rebuild.notified() => {
+                    self.rebuild_endpoints().await;
+                }
+                _ = async {
+                    match &self.connection_losses {
+                        Some(losses) => losses.reported().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    self.disconnect_lost().await;
+                }
+                _ = self.refresh.notified(), if refresh_at.is_none() => {
+                    let now = Instant::now();
+                    refresh_at = Some(last_refresh.map_or(now, |last| (last + REFRESH_DEBOUNCE).max(now)));
+                }
+                _ = tokio::time::sleep_until(refresh_at.unwrap_or_else(Instant::now)), if refresh_at.is_some() && !paused => {
+                    refresh_at = None;
+                    last_refresh = Some(Instant::now());
+                    if self.static_addrs.is_none() {
+                        flow = self.resolve().await;
+                        resolved_once = true;
+                        // The next periodic resolution is a whole interval later.
+                        interval.reset();
+                        next_tick = Instant::now() + self.interval;
+                    }
+                    // Static addresses are reported again as they are.
+                    resolved = resolved_once;
+                }
+                // Only wake the loop up to re-evaluate whether to resolve and
+                // how often.
+                Ok(()) = self.paused.changed() => {}
+                Ok(()) = self.config.changed() => {}
+                _ = &mut shutdown => break,
+            }
+            self.publish_health();
+            // Pausing may be what woke the loop up.
+            let paused = *self.paused.borrow();
+            let next_resolution = if paused || (resolved_once && self.static_addrs.is_some()) {
+                None
+            } else {
+                Some(refresh_at.map_or(next_tick, |refresh_at| refresh_at.min(next_tick)))
+            };
+            self.next_resolution_setter.send_replace(next_resolution);
+
+            // Sent after publishing health, so that it's up to date by the
+            // time `connect` returns.
+            if resolved {
+                let outcome = match &*self.dns_status_setter.borrow() {
+                    DnsStatus::ResolutionError { error } => Err(error.clone()),
+                    _ => Ok(self.endpoints.len()),
+                };
+                self.resolution_outcome.send_replace(Some(outcome));
+                self.resolution_cycles_setter
+                    .send_modify(|cycles| *cycles += 1);
+            }
+
+            if flow.is_break() {
+                final_health = Health::Stopped;
+                break;
+            }
+        }
+
+        self.close(final_health).await
+    }
+
+    /// Resolves the domain a single time, for a channel that never follows
+    /// DNS, and returns the number of endpoints sent to the balanced channel.
+    /// They are left there when `self` is dropped.
+    pub(crate) async fn resolve_once(mut self) -> Result<usize, ResolutionError> {
+        self.check_ipv6_route().await;
+        let _ = self.resolve().await;
+        let outcome = match &*self.dns_status_setter.borrow() {
+            DnsStatus::ResolutionError { error } => Err(error.clone()),
+            _ => Ok(self.endpoints.len()),
+        };
+        outcome
+    }
+
+    /// Ignores IPv6 addresses from now on if the host has no route to the
+    /// IPv6 route target, if any. Done once, before the first resolution.
+    async fn check_ipv6_route(&mut self) {
+        let Some(target) = self.ipv6_route_target else {
+            return;
+        };
+        if !ipv6_routable(target).await {
+            tracing::info!(%target, "no IPv6 route, ignoring IPv6 addresses");
+            self.ipv6_unroutable = true;
+        }
+    }
+
+    /// Breaks on a resolution error classified as fatal.
+    async fn resolve(&mut self) -> ControlFlow<()> {
+        let started = Instant::now();
+        let resolved = match &self.static_addrs {
+            Some(static_addrs) => Ok(static_addrs.iter().map(|addr| (*addr, 1)).collect()),
+            None => {
+                let domain = self.endpoint_template.borrow().domain().to_owned();
+                if self.loopback_localhost && domain.eq_ignore_ascii_case("localhost") {
+                    Ok(vec![
+                        (SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0), 1),
+                        (SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0), 1),
+                    ])
+                } else {
+                    let resolution = self.resolver.resolve_weighted(&domain);
+                    match self.dns_timeout {
+                        Some(timeout) => tokio::time::timeout(timeout, resolution)
+                            .await
+                            .unwrap_or_else(|_| {
+                                Err(io::Error::new(
+                                    io::ErrorKind::TimedOut,
+                                    format!("DNS query timed out after {timeout:?}"),
+                                ))
+                            }),
+                        None => resolution.await,
+                    }
+                }
+            }
+        };
+        self.last_latency = Some(started.elapsed());
+
+        match resolved {
+            Ok(socket_addrs) => {
+                if let Some(limit) = self.result_sanity_limit {
+                    if socket_addrs.len() > limit {
+                        let count = socket_addrs.len();
+                        tracing::warn!(
+                            domain = self.endpoint_template.borrow().domain(),
+                            count,
+                            limit,
+                            "resolved {count} addresses, exceeding the sanity limit of {limit}"
+                        );
+                        if self.refuse_oversized_results {
+                            let error = ResolutionError::other(format!(
+                                "{count} addresses exceed the sanity limit of {limit}"
+                            ));
+                            self.record_resolution(count, Some(error.clone()));
+                            let _ = self
+                                .dns_status_setter
+                                .send(DnsStatus::resolution_error(error));
+                            self.delta_setter.send_replace((0, 0));
+                            return ControlFlow::Continue(());
+                        }
+                    }
+                }
+
+                if socket_addrs.is_empty() && self.retain_on_empty {
+                    tracing::warn!(
+                        domain = self.endpoint_template.borrow().domain(),
+                        "resolved no addresses, keeping the previous endpoints"
+                    );
+                    self.record_resolution(0, None);
+                    let _ = self.dns_status_setter.send(DnsStatus::Empty);
+                    self.delta_setter.send_replace((0, 0));
+                    return ControlFlow::Continue(());
+                }
+
+                self.record_resolution(socket_addrs.len(), None);
+                self.last_success = Some(SystemTime::now());
+                self.last_success_at = Some(Instant::now());
+                let _ = self.dns_status_setter.send(DnsStatus::Ok);
+                let socket_addrs: Vec<(SocketAddr, u32)> = socket_addrs
+                    .into_iter()
+                    .map(|(addr, weight)| {
+                        let ip = addr.ip().to_canonical();
+                        // Rebuilding other IPv6 addresses would lose their
+                        // scope ID.
+                        if self.unmap_ipv4_mapped && ip != addr.ip() {
+                            (SocketAddr::new(ip, addr.port()), weight)
+                        } else {
+                            (addr, weight)
+                        }
+                    })
+                    .filter(|(addr, _)| {
+                        !((self.disable_ipv6 || self.ipv6_unroutable) && addr.is_ipv6())
+                    })
+                    .filter(|(addr, _)| {
+                        let link_local = is_link_local(addr);
+                        if link_local {
+                            tracing::warn!(address = %addr, "skipping link-local address");
+                        }
+                        !link_local
+                    })
+                    .collect();
+                self.resolution_order.clear();
+                for (position, (addr, _)) in socket_addrs.iter().enumerate() {
+                    self.resolution_order.entry(*addr).or_insert(position);
+                }
+                self.resolver_weights.clear();
+                for (addr, weight) in &socket_addrs {
+                    // An IP shared by several SRV targets weighs as the
+                    // heaviest of them.
+                    let current = self.resolver_weights.entry(addr.ip()).or_insert(0);
+                    *current = (*current).max(*weight);
+                }
+                let new_endpoints: HashSet<SocketAddr> =
+                    socket_addrs.into_iter().map(|(addr, _)| addr).collect();
+
+                #[cfg(feature = "async-resolver")]
+                if self.follow_cnames {
+                    self.update_canonical_name().await;
+                }
+                self.resolved = self.accumulate(new_endpoints);
+                self.readmit_reconnected().await;
+                self.sync().await;
+                // Weights may have changed even if the endpoints haven't.
+                self.update_endpoint_metadata();
+                ControlFlow::Continue(())
+            }
+            Err(e) => {
+                // DNS resolution errors might be recoverable and does
+                // not necessarily spell doom for the channel. Because
+                // of this, unless classified otherwise, we just report the
+                // interim problem and use last known IP addresses.
+                let disposition = self
+                    .error_classifier
+                    .as_ref()
+                    .map_or(ErrorDisposition::Retain, |classify| classify(&e));
+                let error = ResolutionError::io(e);
+                self.record_resolution(0, Some(error.clone()));
+                let _ = self
+                    .dns_status_setter
+                    .send(DnsStatus::resolution_error(error));
+
+                match disposition {
+                    ErrorDisposition::Retain => {
+                        self.delta_setter.send_replace((0, 0));
+                        ControlFlow::Continue(())
+                    }
+                    ErrorDisposition::Drain => {
+                        tracing::warn!(
+                            domain = self.endpoint_template.borrow().domain(),
+                            "draining endpoints after resolution error"
+                        );
+                        self.resolved.clear();
+                        self.recent_results.clear();
+                        self.sync().await;
+                        ControlFlow::Continue(())
+                    }
+                    ErrorDisposition::Fatal => {
+                        tracing::error!(
+                            domain = self.endpoint_template.borrow().domain(),
+                            "stopping discovery after fatal resolution error"
+                        );
+                        ControlFlow::Break(())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Looks up the canonical name of the domain and makes it the origin of
+    /// new endpoints. Failures keep the previous one.
+    #[cfg(feature = "async-resolver")]
+    async fn update_canonical_name(&mut self) {
+        let domain = self.endpoint_template.borrow().domain().to_owned();
+        let canonical_name = match self.resolver.canonical_name(&domain).await {
+            Ok(canonical_name) => canonical_name,
+            Err(error) => {
+                tracing::warn!(domain, %error, "failed to look up canonical name");
+                return;
+            }
+        };
+        if canonical_name == *self.canonical_name_setter.borrow() {
+            return;
+        }
+
+        self.origin = canonical_name.as_ref().and_then(|name| {
+            self.endpoint_template
+                .borrow()
+                .origin_for_domain(name)
+                .map_err(|error| {
+                    tracing::warn!(
+                        domain,
+                        name,
+                        ?error,
+                        "canonical name can't be used as origin"
+                    );
+                })
+                .ok()
+        });
+        tracing::debug!(domain, ?canonical_name, "canonical name changed");
+        self.canonical_name_setter.send_replace(canonical_name);
+    }
+
+    /// Returns the union of `result` and the results of the previous
+    /// resolutions within the accumulation window and TTL, so that records
+    /// rotated out of a single answer aren't dropped right away.
+    fn accumulate(&mut self, result: HashSet<SocketAddr>) -> HashSet<SocketAddr> {
+        if self.accumulation_window <= 1 {
+            return result;
+        }
+        if self.recent_results.len() == self.accumulation_window {
+            self.recent_results.pop_front();
+        }
+        if let Some(ttl) = self.accumulation_ttl {
+            self.recent_results
+                .retain(|(resolved_at, _)| resolved_at.elapsed() <= ttl);
+        }
+        self.recent_results.push_back((Instant::now(), result));
+        self.recent_results
+            .iter()
+            .flat_map(|(_, result)| result)
+            .copied()
+            .collect()
+    }
+
+    /// Appends the outcome of a resolution to the history, dropping the
+    /// oldest records beyond its size.
+    fn record_resolution(&self, count: usize, error: Option<ResolutionError>) {
+        if self.history_size == 0 {
+            return;
+        }
+        self.history_setter.send_modify(|history| {
+            if history.len() == self.history_size {
+                history.pop_front();
+            }
+            history.push_back(ResolutionRecord {
+                timestamp: SystemTime::now(),
+                count,
+                error,
+            });
+        });
+    }
+
+    async fn check_health(&mut self) {
+        let Some(health_checker) = &mut self.health_checker else {
+            return;
+        };
+        let endpoint_template = &self.endpoint_template;
+        let origin = self.origin.as_ref();
+        let connector = endpoint_template.borrow().connector();
+        let ejected = health_checker
+            .probe(&self.resolved, self.max_concurrent_connects, |addr| {
+                build_endpoint(endpoint_template, origin, addr)
+                    .map(|endpoint| proxy::connect_lazy(&endpoint, connector.as_ref(), addr))
+            })
+            .await;
+
+        if ejected != self.ejected {
+            for addr in ejected.difference(&self.ejected) {
+                tracing::warn!(endpoint = %addr, "ejecting endpoint failing health checks");
+            }
+            for addr in self.ejected.difference(&ejected) {
+                tracing::info!(endpoint = %addr, "readmitting endpoint passing health checks");
+            }
+            self.ejected = ejected;
+            self.sync().await;
+        }
+    }
+
+    /// Tries to connect to every registered endpoint and counts the failures.
+    /// Endpoints drained for failing too many in a row are probed too, to be
+    /// readmitted once they connect again.
+    async fn probe_connections(&mut self) {
+        let addrs: Vec<SocketAddr> = self.endpoints.union(&self.unreachable).copied().collect();
+        let results = self.try_connect(addrs).await;
+
+        let mut failed = HashSet::new();
+        let mut last_error = None;
+        for (addr, connected) in results {
+            match connected {
+                Ok(()) => {
+                    self.consecutive_failures.remove(&addr);
+                }
+                Err(error) => {
+                    tracing::debug!(endpoint = %addr, error, "failed to connect to endpoint");
+                    *self.consecutive_failures.entry(addr).or_default() += 1;
+                    if self.endpoints.contains(&addr) {
+                        failed.insert(addr);
+                        last_error = Some(error);
+                    }
+                }
+            }
+        }
+
+        self.connection_failures_setter.send_modify(|failures| {
+            failures.retain(|addr, _| self.endpoints.contains(addr));
+            for addr in &failed {
+                *failures.entry(*addr).or_default() += 1;
+            }
+        });
+
+        self.drain_unreachable().await;
+
+        // Only when no endpoint left after draining is reachable is the
+        // channel unreachable.
+        let all_failed =
+            !self.endpoints.is_empty() && self.endpoints.iter().all(|addr| failed.contains(addr));
+        self.last_connection_error = last_error.filter(|_| all_failed);
+    }
+
+    /// Opens a connection to each of `addrs`, bounded by the connection probe
+    /// interval, and returns whether it could.
+    async fn try_connect(&self, addrs: Vec<SocketAddr>) -> Vec<(SocketAddr, Result<(), String>)> {
+        let timeout = self.connection_probe_interval.unwrap_or(self.interval);
+        let connector = self.endpoint_template.borrow().connector();
+        let probes: Vec<_> = addrs
+            .into_iter()
+            .filter_map(|addr| {
+                let endpoint = build_endpoint(&self.endpoint_template, self.origin.as_ref(), addr)?;
+                let connector = connector.clone();
+                Some(async move {
+                    let connect = proxy::connect(&endpoint, connector.as_ref(), addr);
+                    let connected = match tokio::time::timeout(timeout, connect).await {
+                        Ok(Ok(_)) => Ok(()),
+                        Ok(Err(error)) => Err(error_chain(&error)),
+                        Err(_) => Err("timed out".to_owned()),
+                    };
+                    (addr, connected)
+                })
+            })
+            .collect();
+        join_bounded(probes, self.max_concurrent_connects).await
+    }
+
+    /// Takes the endpoints whose connection the balanced channel lost out,
+    /// and resolves again so that those gone from DNS are forgotten.
+    async fn disconnect_lost(&mut self) {
+        let Some(losses) = &self.connection_losses else {
+            return;
+        };
+        let lost: HashSet<SocketAddr> = losses
+            .take()
+            .into_iter()
+            .filter(|addr| self.endpoints.contains(addr))
+            .collect();
+        if lost.is_empty() {
+            return;
+        }
+        for addr in &lost {
+            tracing::warn!(endpoint = %addr, "taking out endpoint that lost its connection");
+        }
+        self.disconnected.extend(lost);
+        self.sync().await;
+        self.refresh.notify_one();
+    }
+
+    /// Readmits the endpoints taken out after losing their connection that
+    /// are still resolved and connect again.
+    async fn readmit_reconnected(&mut self) {
+        self.disconnected
+            .retain(|addr| self.resolved.contains(addr));
+        if self.disconnected.is_empty() {
+            return;
+        }
+        let results = self
+            .try_connect(self.disconnected.iter().copied().collect())
+            .await;
+        for (addr, connected) in results {
+            match connected {
+                Ok(()) => {
+                    tracing::info!(endpoint = %addr, "readmitting endpoint connecting again");
+                    self.disconnected.remove(&addr);
+                }
+                Err(error) => {
+                    tracing::debug!(endpoint = %addr, error, "endpoint still fails to connect");
+                }
+            }
+        }
+    }
+
+    /// Drains the endpoints failing the maximum number of connection probes
+    /// in a row and readmits those connecting again.
+    async fn drain_unreachable(&mut self) {
+        let Some(max_failures) = self.max_connection_failures else {
+            return;
+        };
+        self.consecutive_failures
+            .retain(|addr, _| self.resolved.contains(addr));
+        let unreachable: HashSet<SocketAddr> = self
+            .consecutive_failures
+            .iter()
+            .filter(|(_, failures)| **failures >= max_failures)
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        if unreachable != self.unreachable {
+            for addr in unreachable.difference(&self.unreachable) {
+                tracing::warn!(endpoint = %addr, "draining endpoint failing to connect");
+            }
+            for addr in self.unreachable.difference(&unreachable) {
+                tracing::info!(endpoint = %addr, "readmitting endpoint connecting again");
+            }
+            self.unreachable = unreachable;
+            self.sync().await;
+        }
+    }
+
+    /// Replaces every registered endpoint with one built from the current
+    /// template. Endpoints are keyed by address, so inserting the new one
+    /// replaces the old one without a window of missing endpoints.
+    async fn rebuild_endpoints(&mut self) {
+        let mut endpoints: Vec<SocketAddr> = self.endpoints.iter().copied().collect();
+        endpoints.sort();
+        for addr in endpoints {
+            // The old endpoint is kept if the new template doesn't fit.
+            let Some(endpoint) =
+                build_endpoint(&self.endpoint_template, self.origin.as_ref(), addr)
+            else {
+                continue;
+            };
+            tracing::debug!(endpoint = %addr, "rebuilding endpoint");
+            self.send_change(Change::Insert(addr, endpoint)).await;
+            self.track_insertion(addr);
+        }
+    }
+
+    /// Replaces the endpoints whose connections reached the maximum age, so
+    /// that the balanced channel connects to them anew.
+    async fn recycle_connections(&mut self) {
+        let now = Instant::now();
+        let mut due: Vec<SocketAddr> = self
+            .recycle_at
+            .iter()
+            .filter(|(_, recycle_at)| **recycle_at <= now)
+            .map(|(addr, _)| *addr)
+            .collect();
+        due.sort();
+        for addr in due {
+            self.track_insertion(addr);
+            let Some(endpoint) =
+                build_endpoint(&self.endpoint_template, self.origin.as_ref(), addr)
+            else {
+                continue;
+            };
+            tracing::debug!(endpoint = %addr, "recycling endpoint connection");
+            self.send_change(Change::Insert(addr, endpoint)).await;
+        }
+    }
+
+    /// Sends `change` to the balanced channel, counting it as a send failure
+    /// if the channel is gone. The balanced channel takes changes as they
+    /// come, whether it's serving requests or not, so waiting for room in the
+    /// buffer is brief.
+    async fn send_change(&self, change: Change<SocketAddr, Endpoint>) {
+        if self.sender.send(change).await.is_err() {
+            tracing::warn!("balanced channel gone, endpoint change dropped");
+            self.send_failures_setter
+                .send_modify(|failures| *failures += 1);
+        }
+    }
+
+    /// Bookkeeping for a newly (re)inserted endpoint, whose connection is yet
+    /// to be opened.
+    ///
+    /// With a maximum connection age, recycling is scheduled with a random
+    /// delay of up to a quarter of the age on top, so that endpoints inserted
+    /// together aren't all reconnected at once.
+    fn track_insertion(&mut self, addr: SocketAddr) {
+        let now = Instant::now();
+        if self.idle_timeout.is_some() {
+            self.inserted_at.insert(addr, now);
+        }
+        if let Some(max_age) = self.max_connection_age {
+            let stagger = max_age.mul_f64(self.rng.gen_range(0.0..0.25));
+            self.recycle_at.insert(addr, now + max_age + stagger);
+        }
+    }
+
+    /// Replaces the endpoints whose connections haven't been used for the idle
+    /// timeout, which closes the connections. The replacements only connect
+    /// once used again.
+    async fn close_idle_connections(&mut self) {
+        let (Some(timeout), Some(last_used)) = (self.idle_timeout, &self.last_used) else {
+            return;
+        };
+        let now = Instant::now();
+        let mut idle: Vec<SocketAddr> = {
+            let last_used = last_used.lock().expect("last used lock poisoned");
+            self.endpoints
+                .iter()
+                .filter(|addr| {
+                    // Unused since inserted means never connected.
+                    match (last_used.get(addr), self.inserted_at.get(addr)) {
+                        (Some(used), Some(inserted)) => used > inserted && now - *used >= timeout,
+                        _ => false,
+                    }
+                })
+                .copied()
+                .collect()
+        };
+        idle.sort();
+        for addr in idle {
+            let Some(endpoint) =
+                build_endpoint(&self.endpoint_template, self.origin.as_ref(), addr)
+            else {
+                continue;
+            };
+            tracing::debug!(endpoint = %addr, "closing idle endpoint connection");
+            self.send_change(Change::Insert(addr, endpoint)).await;
+            self.track_insertion(addr);
+        }
+    }
+
+    /// Removes the retiring endpoints no request is in flight to anymore.
+    async fn remove_retired(&mut self) {
+        let Some(retirement) = &self.retirement else {
+            return;
+        };
+        let mut idle: Vec<SocketAddr> = self
+            .retiring
+            .iter()
+            .filter(|addr| retirement.is_idle(**addr))
+            .copied()
+            .collect();
+        idle.sort();
+        for addr in idle {
+            tracing::debug!(endpoint = %addr, "removing idle retired endpoint");
+            self.send_change(Change::Remove(addr)).await;
+            retirement.reinstate(addr);
+            self.retiring.remove(&addr);
+        }
+    }
+
+    /// Registers the resolved endpoints, except those failing health checks,
+    /// drained for failing to connect or taken out after losing their
+    /// connection.
+    async fn sync(&mut self) {
+        self.unreachable.retain(|addr| self.resolved.contains(addr));
+        self.disconnected
+            .retain(|addr| self.resolved.contains(addr));
+        let endpoints = self
+            .resolved
+            .difference(&self.ejected)
+            .filter(|addr| !self.unreachable.contains(addr) && !self.disconnected.contains(addr))
+            .copied()
+            .collect();
+        self.update(endpoints).await;
+    }
+
+    async fn update(&mut self, mut new_endpoints: HashSet<SocketAddr>) {
+        // The common case with short intervals; existing endpoints and their
+        // connections are left alone.
+        if new_endpoints == self.endpoints {
+            self.delta_setter.send_replace((0, 0));
+            return;
+        }
+
+        let (mut added, removed) = diff_endpoints(&self.endpoints, &new_endpoints);
+        match self.address_order {
+            AddressOrder::Sorted => {}
+            AddressOrder::Shuffled => added.shuffle(&mut self.rng),
+            // Stable, so the rest stays sorted.
+            AddressOrder::ResolverOrder => added.sort_by_key(|addr| {
+                self.resolution_order
+                    .get(addr)
+                    .copied()
+                    .unwrap_or(usize::MAX)
+            }),
+        }
+        let (mut added_count, removed_count) = (added.len(), removed.len());
+        let mut delta = EndpointDelta::default();
+        let mut builds = self.build_endpoints(
+            added
+                .iter()
+                .filter(|addr| !self.retiring.contains(addr))
+                .copied(),
+        );
+
+        for new_addr in added {
+            // Still in the balanced channel, along with its connection.
+            if self.retiring.remove(&new_addr) {
+                tracing::debug!(endpoint = %new_addr, "reinstating retiring endpoint");
+                if let Some(retirement) = &self.retirement {
+                    retirement.reinstate(new_addr);
+                }
+                self.track_insertion(new_addr);
+                let _ = self.event_sender.send(EndpointEvent::Added(new_addr));
+                delta.added.push(new_addr);
+                continue;
+            }
+            let Some(new_endpoint) = builds.next().await else {
+                new_endpoints.remove(&new_addr);
+                added_count -= 1;
+                continue;
+            };
+            tracing::debug!(endpoint = %new_addr, "adding endpoint");
+            self.send_change(Change::Insert(new_addr, new_endpoint))
+                .await;
+            self.track_insertion(new_addr);
+            let _ = self.event_sender.send(EndpointEvent::Added(new_addr));
+            delta.added.push(new_addr);
+        }
+
+        for old_addr in removed {
+            tracing::debug!(endpoint = %old_addr, "removing endpoint");
+            self.recycle_at.remove(&old_addr);
+            self.inserted_at.remove(&old_addr);
+            if let Some(last_used) = &self.last_used {
+                last_used
+                    .lock()
+                    .expect("last used lock poisoned")
+                    .remove(&old_addr);
+            }
+            match &self.retirement {
+                Some(retirement) => {
+                    retirement.retire(old_addr);
+                    self.retiring.insert(old_addr);
+                }
+                None => {
+                    self.send_change(Change::Remove(old_addr)).await;
+                }
+            }
+            let _ = self.event_sender.send(EndpointEvent::Removed(old_addr));
+            delta.removed.push(old_addr);
+        }
+
+        self.endpoints = new_endpoints;
+        // The new set of endpoints hasn't been probed yet.
+        self.last_connection_error = None;
+        let count = self.endpoints.len();
+        self.endpoint_count_setter.send_replace(count);
+        self.update_endpoint_metadata();
+        if added_count > 0 {
+            self.warn_on_uri_collisions();
+        }
+        self.delta_setter.send_replace((added_count, removed_count));
+        if let Some(delta_sender) = &self.delta_sender {
+            if !delta.added.is_empty() || !delta.removed.is_empty() {
+                delta_sender.send(delta).await;
+            }
+        }
+    }
+
+    /// Starts building the endpoints for `addrs`, to be taken in order from
+    /// the returned builds as they finish.
+    fn build_endpoints(&self, addrs: impl Iterator<Item = SocketAddr>) -> EndpointBuilds {
+        EndpointBuilds {
+            endpoint_template: self.endpoint_template.clone(),
+            origin: self.origin.clone(),
+            limit: self.max_concurrent_connects.max(1),
+            pending: addrs.collect(),
+            running: VecDeque::new(),
+        }
+    }
+
+    /// Endpoints are keyed by address, so endpoints built into the same URI
+    /// (e.g. the same IP with port 0 and the template's port) aren't merged
+    /// and the backend gets connected to more than once.
+    fn warn_on_uri_collisions(&self) {
+        let template = self.endpoint_template.borrow();
+        let mut by_uri: HashMap<Uri, Vec<SocketAddr>> = HashMap::new();
+        for addr in &self.endpoints {
+            if let Ok(uri) = template.uri_for_socket_addr(*addr) {
+                by_uri.entry(uri).or_default().push(*addr);
+            }
+        }
+        for (uri, mut addrs) in by_uri {
+            if addrs.len() > 1 {
+                addrs.sort();
+                tracing::warn!(%uri, ?addrs, "endpoints {addrs:?} all connect to {uri}");
+            }
+        }
+    }
+
+    /// Computes the tags and weight of endpoint IPs new since the last update
+    /// and forgets those of removed endpoints.
+    fn update_endpoint_metadata(&self) {
+        let ips: HashSet<IpAddr> = self.endpoints.iter().map(SocketAddr::ip).collect();
+        if let Some(tagger) = &self.tagger {
+            update_per_ip(&self.tags_setter, &ips, |ip| tagger(ip));
+        }
+        // Unlike tags, resolver weights may change between resolutions, so
+        // they are all recomputed.
+        let weights: HashMap<IpAddr, u32> = ips
+            .into_iter()
+            .map(|ip| {
+                let resolver_weight = self.resolver_weights.get(&ip).copied().unwrap_or(1);
+                let weight =
+                    resolver_weight.saturating_mul(subnet_weight(&self.subnet_weights, ip));
+                // Round robin gives every ready endpoint at least one request
+                // per turn, so report a weight of 0 as the 1 it's served as.
+                (ip, weight.max(1))
+            })
+            .collect();
+        self.weights_setter.send_if_modified(|current| {
+            let modified = *current != weights;
+            *current = weights;
+            modified
+        });
+    }
+
+    fn publish_health(&self) {
+        self.health_setter.send_if_modified(|health| {
+            let new_health = match &self.health_derivation {
+                Some(derive) => derive(
+                    self.endpoints.len(),
+                    &self.dns_status_setter.borrow(),
+                    self.last_success_at.map(|at| at.elapsed()),
+                ),
+                None => Health::derive(
+                    self.endpoints.len(),
+                    self.last_connection_error.as_deref(),
+                    &self.dns_status_setter.borrow(),
+                    self.min_healthy_endpoints,
+                ),
+            };
+            let event = match (&*health, &new_health) {
+                (Health::Ok, Health::Undetermined) => match &*self.dns_status_setter.borrow() {
+                    DnsStatus::ResolutionError { error } => Some(HealthEvent::Undetermined {
+                        error: error.clone(),
+                    }),
+                    _ => None,
+                },
+                (Health::Undetermined, Health::Ok) => Some(HealthEvent::Recovered),
+                _ => None,
+            };
+            if let Some(event) = event {
+                let _ = self.health_event_sender.send(event);
+            }
+            let modified = *health != new_health;
+            *health = new_health;
+            modified
+        });
+        self.publish_snapshot();
+    }
+
+    fn publish_snapshot(&self) {
+        let mut endpoints: Vec<SocketAddr> = self.endpoints.iter().copied().collect();
+        endpoints.sort();
+        self.snapshot_setter.send_replace(ChannelSnapshot {
+            health: self.health_setter.borrow().clone(),
+            dns_status: self.dns_status_setter.borrow().clone(),
+            endpoints,
+            last_success: self.last_success,
+            last_latency: self.last_latency,
+        });
+    }
+
+    /// Graceful shutdown: deregister every endpoint so that the balance
+    /// channel tears down its connections and observers see the end.
+    async fn close(mut self, health: Health) -> usize {
+        let removed = self.endpoints.len();
+        // Nothing is waited for on the way out.
+        if self.retirement.take().is_some() {
+            for addr in std::mem::take(&mut self.retiring) {
+                self.send_change(Change::Remove(addr)).await;
+            }
+        }
+        self.update(HashSet::new()).await;
+        self.next_resolution_setter.send_replace(None);
+        let _ = self.health_setter.send(health);
+        self.publish_snapshot();
+        tracing::info!(
+            domain = self.endpoint_template.borrow().domain(),
+            removed,
+            "channel closed, removed {removed} endpoints"
+        );
+
+        removed
+    }
+}
+
+/// Minimum time between resolutions forced with `refresh`.
+const REFRESH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// How often retiring endpoints are checked for requests in flight.
+const RETIREMENT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long building an endpoint for a newly resolved address may take before
+/// the address is skipped until the next update, see [`EndpointBuilds`].
+const BUILD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Computes `value` for the IPs in `ips` missing from the map and removes the
+/// others from it, notifying receivers only if anything changed.
+fn update_per_ip<T>(
+    setter: &watch::Sender<HashMap<IpAddr, T>>,
+    ips: &HashSet<IpAddr>,
+    value: impl Fn(IpAddr) -> T,
+) {
+    setter.send_if_modified(|values| {
+        let before = values.len();
+        values.retain(|ip, _| ips.contains(ip));
+        let mut modified = values.len() != before;
+        for ip in ips {
+            values.entry(*ip).or_insert_with(|| {
+                modified = true;
+                value(*ip)
+            });
+        }
+        modified
+    });
+}
+
+/// Returns the weight of the most specific subnet containing `ip`, or 1 if
+/// there is none.
+fn subnet_weight(subnet_weights: &[(IpNet, u32)], ip: IpAddr) -> u32 {
+    subnet_weights
+        .iter()
+        .filter(|(subnet, _)| subnet.contains(&ip))
+        .max_by_key(|(subnet, _)| subnet.prefix_len())
+        .map_or(1, |(_, weight)| *weight)
+}
+
+/// Runs `futures` concurrently, but no more than `limit` at a time, and
+/// returns their outputs in the order they complete. Futures that panic are
+/// logged and left out.
+pub(crate) async fn join_bounded<T, F>(futures: impl IntoIterator<Item = F>, limit: usize) -> Vec<T>
+where
+    T: Send + 'static,
+    F: Future<Output = T> + Send + 'static,
+{
+    let mut futures = futures.into_iter();
+    let mut running = JoinSet::new();
+    let mut outputs = Vec::new();
+    for future in futures.by_ref().take(limit) {
+        running.spawn(future);
+    }
+    while let Some(output) = running.join_next().await {
+        if let Some(future) = futures.next() {
+            running.spawn(future);
+        }
+        match output {
+            Ok(output) => outputs.push(output),
+            Err(error) => tracing::error!(%error, "concurrent task failed"),
+        }
+    }
+    outputs
+}
+
+/// Endpoints being built for newly resolved addresses, see
+/// [`Discovery::build_endpoints`].
+///
+/// Builds run on tokio's blocking threads, up to `limit` at a time (shared
+/// with connection probes and health checks), so a slow one neither blocks
+/// the runtime nor keeps the others from starting. Each endpoint can be
+/// inserted as soon as it and those before it are built, which keeps the
+/// address order, and a build taking longer than [`BUILD_TIMEOUT`] is given
+/// up on.
+struct EndpointBuilds {
+    endpoint_template: watch::Receiver<EndpointTemplate>,
+    origin: Option<Uri>,
+    limit: usize,
+    pending: VecDeque<SocketAddr>,
+    running: VecDeque<(SocketAddr, Instant, JoinHandle<Option<Endpoint>>)>,
+}
+
+impl EndpointBuilds {
+    /// Waits for the next endpoint in order, or `None` if it couldn't be
+    /// built in time. Must be called once per address.
+    async fn next(&mut self) -> Option<Endpoint> {
+        while self.running.len() < self.limit {
+            let Some(addr) = self.pending.pop_front() else {
+                break;
+            };
+            let endpoint_template = self.endpoint_template.clone();
+            let origin = self.origin.clone();
+            let span = tracing::Span::current();
+            let build = tokio::task::spawn_blocking(move || {
+                span.in_scope(|| build_endpoint(&endpoint_template, origin.as_ref(), addr))
+            });
+            self.running
+                .push_back((addr, Instant::now() + BUILD_TIMEOUT, build));
+        }
+
+        let (addr, deadline, build) = self.running.pop_front()?;
+        match tokio::time::timeout_at(deadline, build).await {
+            Ok(Ok(endpoint)) => endpoint,
+            Ok(Err(error)) => {
+                tracing::error!(endpoint = %addr, %error, "building endpoint failed");
+                None
+            }
+            Err(_) => {
+                // The build can't be cancelled, but its result is dropped.
+                tracing::warn!(endpoint = %addr, "skipping endpoint taking too long to build");
+                None
+            }
+        }
+    }
+}
+
+/// Builds the endpoint for `addr`, logging (rather than panicking on) a
+/// template that can't be used with it. `origin` overrides the template's.
+fn build_endpoint(
+    endpoint_template: &watch::Receiver<EndpointTemplate>,
+    origin: Option<&Uri>,
+    addr: SocketAddr,
+) -> Option<Endpoint> {
+    match endpoint_template.borrow().build_for_socket_addr(addr) {
+        Ok(endpoint) => Some(match origin {
+            Some(origin) => endpoint.origin(origin.clone()),
+            None => endpoint,
+        }),
+        Err(error) => {
+            tracing::error!(endpoint = %addr, ?error, "skipping endpoint the template can't be built for");
+            None
+        }
+    }
+}
+
+/// Formats `error` along with all its sources, as tonic's transport errors
+/// hide the details in them.
+fn error_chain(error: &dyn Error) -> String {
+    let mut chain = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        chain = format!("{chain}: {error}");
+        source = error.source();
+    }
+    chain
+}
+
+/// Checks whether this host has a route to `target`. Connecting a UDP socket
+/// doesn't send anything, but fails without a matching route.
+async fn ipv6_routable(target: Ipv6Addr) -> bool {
+    match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await {
+        Ok(socket) => socket.connect((target, 53)).await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Whether `addr` is a link-local IPv6 address, whose scope ID (the interface
+/// to use) is lost when it becomes the host of an endpoint URI.
+fn is_link_local(addr: &SocketAddr) -> bool {
+    match addr {
+        SocketAddr::V4(_) => false,
+        // fe80::/10; `Ipv6Addr::is_unicast_link_local` needs Rust 1.84.
+        SocketAddr::V6(addr) => addr.scope_id() != 0 || addr.ip().segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/// Returns the endpoints to add and to remove to get from `old` to `new`.
+///
+/// Both lists are sorted so that the order of `Change`s sent to the balance
+/// channel doesn't depend on `HashSet` iteration order.
+fn diff_endpoints(
+    old: &HashSet<SocketAddr>,
+    new: &HashSet<SocketAddr>,
+) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+    let mut added: Vec<SocketAddr> = new.difference(old).copied().collect();
+    let mut removed: Vec<SocketAddr> = old.difference(new).copied().collect();
+    added.sort();
+    removed.sort();
+    (added, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use ipnet::IpNet;
+    use tokio::{sync::watch, time::Instant};
+
+    use super::{
+        diff_endpoints, is_link_local, join_bounded, subnet_weight, EndpointBuilds, BUILD_TIMEOUT,
+    };
+    use crate::test_util::{socket_addrs, template};
+
+    #[test]
+    fn endpoint_changes_are_sorted() {
+        let old = socket_addrs(&["10.0.0.3:0", "10.0.0.1:0", "[::2]:0", "10.0.0.2:0"]);
+        let new = socket_addrs(&["[::1]:0", "10.0.0.4:0", "10.0.0.2:0", "192.168.0.1:0"]);
+
+        let (added, removed) =
+            diff_endpoints(&old.into_iter().collect(), &new.into_iter().collect());
+
+        assert_eq!(
+            added,
+            socket_addrs(&["10.0.0.4:0", "192.168.0.1:0", "[::1]:0"])
+        );
+        assert_eq!(
+            removed,
+            socket_addrs(&["10.0.0.1:0", "10.0.0.3:0", "[::2]:0"])
+        );
+    }
+
+    #[test]
+    fn most_specific_subnet_weight_applies() {
+        let weights: Vec<(IpNet, u32)> = vec![
+            ("10.0.0.0/8".parse().unwrap(), 2),
+            ("10.1.0.0/16".parse().unwrap(), 5),
+        ];
+
+        let weight = |ip: &str| subnet_weight(&weights, ip.parse().unwrap());
+        assert_eq!(weight("10.0.0.1"), 2);
+        assert_eq!(weight("10.1.2.3"), 5);
+        assert_eq!(weight("192.168.0.1"), 1);
+        assert_eq!(weight("::1"), 1);
+    }
+
+    #[rstest::rstest]
+    #[case::link_local("[fe80::1]:0", true)]
+    #[case::link_local_upper_bound("[febf:ffff::1]:0", true)]
+    #[case::site_local("[fec0::1]:0", false)]
+    #[case::global("[2001:db8::1]:0", false)]
+    #[case::ipv4("169.254.0.1:0", false)]
+    fn link_local_addresses_are_detected(#[case] addr: &str, #[case] expected: bool) {
+        assert_eq!(
+            is_link_local(&SocketAddr::from_str(addr).unwrap()),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_endpoint_builds_are_skipped_in_order() {
+        let template = template().configure_endpoint(|endpoint| {
+            if endpoint.uri().host() == Some("10.0.0.1") {
+                std::thread::sleep(BUILD_TIMEOUT + Duration::from_millis(500));
+            }
+            endpoint
+        });
+        let (_template_setter, endpoint_template) = watch::channel(template);
+        let mut builds = EndpointBuilds {
+            endpoint_template,
+            origin: None,
+            limit: 2,
+            pending: socket_addrs(&["10.0.0.1:0", "10.0.0.2:0", "10.0.0.3:0"]).into(),
+            running: Default::default(),
+        };
+
+        let started = Instant::now();
+        assert!(builds.next().await.is_none());
+        assert!(started.elapsed() < BUILD_TIMEOUT + Duration::from_millis(250));
+        for host in ["10.0.0.2", "10.0.0.3"] {
+            let endpoint = builds.next().await.expect("endpoint built");
+            assert_eq!(endpoint.uri().host(), Some(host));
+        }
+        assert!(started.elapsed() < BUILD_TIMEOUT + Duration::from_millis(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn join_bounded_limits_concurrency() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let connects = (0..20).map(|i| {
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            async move {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(current, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10 + i % 3)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                i
+            }
+        });
+
+        let mut outputs = join_bounded(connects, 4).await;
+        outputs.sort();
+        assert_eq!(outputs, (0..20).collect::<Vec<_>>());
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn join_bounded_logs_panicked_futures() {
+        let futures = (0..3).map(|i| async move {
+            assert_ne!(i, 1, "build failed");
+            i
+        });
+
+        let mut outputs = join_bounded(futures, 2).await;
+        outputs.sort();
+        assert_eq!(outputs, [0, 2]);
+        assert!(logs_contain("concurrent task failed"));
+    }
+}
@@ -1,4 +1,4 @@
-use std::{io::Result, net::IpAddr};
+use std::{io::Result, net::SocketAddr};
 
 #[cfg(not(any(test, feature = "mock-dns")))]
 use std::net::ToSocketAddrs;
@@ -6,8 +6,11 @@ use std::net::ToSocketAddrs;
 #[cfg(any(test, feature = "mock-dns"))]
 use mock_net::ToSocketAddrs;
 
-pub fn resolve_domain(domain: &str) -> Result<impl Iterator<Item = IpAddr>> {
-    Ok((domain, 0).to_socket_addrs()?.map(|addr| addr.ip()))
+/// Resolves `domain` into socket addresses. The system resolver knows nothing
+/// about service ports, so they come back as 0, meaning "use the port from
+/// the endpoint template".
+pub fn resolve_domain(domain: &str) -> Result<impl Iterator<Item = SocketAddr>> {
+    (domain, 0).to_socket_addrs()
 }
 
 #[cfg(any(test, feature = "mock-dns"))]
@@ -46,26 +49,28 @@ pub mod mock_net {
 }
 
 #[test]
+#[sequential_test::sequential]
 fn can_mock_address_resolution() {
-    use std::{net::SocketAddr, str::FromStr};
+    use std::{net::IpAddr, str::FromStr};
 
-    let addresses = vec![
+    let addresses = [
         IpAddr::from_str("128.0.0.1").unwrap(),
         IpAddr::from_str("129.0.0.1").unwrap(),
         IpAddr::from_str("::2").unwrap(),
         IpAddr::from_str("::3").unwrap(),
     ];
 
+    let sockets = addresses
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, 0))
+        .collect::<Vec<_>>();
     {
-        let sockets = addresses
-            .iter()
-            .map(|ip| SocketAddr::new(*ip, 0))
-            .collect::<Vec<_>>();
+        let sockets = sockets.clone();
         mock_net::set_socket_addrs(Box::new(move |_, _| Ok(sockets.clone())));
     }
 
     assert_eq!(
         resolve_domain("localhost").unwrap().collect::<Vec<_>>(),
-        addresses
+        sockets
     );
 }
@@ -1,123 +1,756 @@
+use crate::balance::{
+    BalancePolicy, BalancedChannel, ConnectionLosses, EndpointTracking, LastUsed, Load,
+    RequestTracker,
+};
+use crate::circuit_breaker::{CircuitBreakerConfig, CircuitBreakerLayer};
 use crate::endpoint_template::EndpointTemplate;
+use crate::events::{EndpointEvent, EndpointEvents, HealthEvent, HealthEvents, LagPolicy};
+#[cfg(feature = "fault-injection")]
+use crate::fault::{Fault, FaultInjector, Faults};
+use crate::health_check::{HealthCheck, HealthChecker};
+#[cfg(feature = "async-resolver")]
+use crate::resolver::{DnsProtocol, DnsServerResolver, QueryTypes};
+use crate::resolver::{Resolver, SystemResolver};
+use crate::sink::{DeltaSender, DeltaSink, EndpointDelta, SinkOverflow};
 
-use crate::dns::resolve_domain;
+use crate::discovery::Discovery;
 
-use std::{collections::HashSet, net::IpAddr, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
+use ipnet::IpNet;
+use rand::{rngs::StdRng, SeedableRng};
 use tokio::{
-    sync::watch::{self, Receiver},
+    sync::{
+        broadcast, mpsc, oneshot,
+        watch::{self, Receiver},
+        Notify, Semaphore,
+    },
     task::JoinHandle,
+    time::Instant,
 };
-use tonic::transport::Channel;
+use tonic::transport::Endpoint;
 use tower::discover::Change;
+use tracing::{Instrument, Span};
 
 pub struct AutoBalancedChannel {
-    channel: Channel,
-    background_task: JoinHandle<()>,
+    name: String,
+    channel: BalancedChannel,
+    /// Unset for channels whose discovery never runs in the background, see
+    /// [`AutoBalancedChannelBuilder::resolve_once`].
+    background_task: Option<JoinHandle<usize>>,
+    shutdown_sender: Option<oneshot::Sender<()>>,
     dns_status_reader: Receiver<DnsStatus>,
-    endpoints_count_reader: Receiver<usize>,
+    health_reader: Receiver<Health>,
+    delta_reader: Receiver<(usize, usize)>,
+    endpoint_count_reader: Receiver<usize>,
+    event_sender: broadcast::Sender<EndpointEvent>,
+    health_event_sender: broadcast::Sender<HealthEvent>,
+    lag_policy: LagPolicy,
+    template_setter: watch::Sender<EndpointTemplate>,
+    rebuild: Arc<Notify>,
+    refresh: Arc<Notify>,
+    next_resolution_reader: Receiver<Option<Instant>>,
+    resolution_cycles_reader: Receiver<u64>,
+    send_failures_reader: Receiver<u64>,
+    /// Set if load is tracked or removals are deferred.
+    requests: Option<RequestTracker>,
+    #[cfg(feature = "fault-injection")]
+    faults: Faults,
+    pause_setter: watch::Sender<bool>,
+    connection_failures_reader: Receiver<HashMap<SocketAddr, u64>>,
+    tags_reader: Receiver<HashMap<IpAddr, EndpointTags>>,
+    weights_reader: Receiver<HashMap<IpAddr, u32>>,
+    history_reader: Receiver<VecDeque<ResolutionRecord>>,
+    snapshot_reader: Receiver<ChannelSnapshot>,
+    #[cfg(feature = "async-resolver")]
+    canonical_name_reader: Receiver<Option<String>>,
+    config_setter: watch::Sender<RuntimeConfig>,
+    min_interval: Duration,
+    resolution_outcome_reader: Receiver<Option<Result<usize, ResolutionError>>>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum DnsStatus {
+    /// No DNS resolution has completed yet.
+    Pending,
     Ok,
-    ResolutionError { details: String },
+    /// The latest resolution succeeded but returned no address, and the
+    /// previous endpoints were kept, see
+    /// [`AutoBalancedChannelBuilder::retain_on_empty`].
+    Empty,
+    ResolutionError {
+        error: ResolutionError,
+    },
 }
 
 impl DnsStatus {
-    fn resolution_error(e: impl std::fmt::Debug) -> Self {
-        Self::ResolutionError {
-            details: format!("{e:?}"),
-        }
+    pub(crate) fn resolution_error(error: ResolutionError) -> Self {
+        Self::ResolutionError { error }
     }
 
     fn is_error(&self) -> bool {
-        match &self {
-            Self::ResolutionError { .. } => true,
-            _ => false,
+        matches!(self, Self::ResolutionError { .. })
+    }
+}
+
+/// Why the latest DNS resolution was not used.
+///
+/// If resolution itself failed, the underlying [`io::Error`] is available
+/// through [`Error::source`].
+#[derive(Clone, Debug)]
+pub struct ResolutionError {
+    message: String,
+    source: Option<Arc<io::Error>>,
+}
+
+impl ResolutionError {
+    pub(crate) fn io(source: io::Error) -> Self {
+        Self {
+            message: "failed to resolve domain".to_owned(),
+            source: Some(Arc::new(source)),
+        }
+    }
+
+    pub(crate) fn other(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            source: None,
+        }
+    }
+}
+
+impl fmt::Display for ResolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Serialized as its message.
+#[cfg(feature = "serde")]
+impl serde::Serialize for ResolutionError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Error for ResolutionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref().map(|source| source as _)
+    }
+}
+
+// `io::Error` isn't comparable, so fall back to its kind and message.
+impl PartialEq for ResolutionError {
+    fn eq(&self, other: &Self) -> bool {
+        self.message == other.message
+            && match (&self.source, &other.source) {
+                (Some(a), Some(b)) => a.kind() == b.kind() && a.to_string() == b.to_string(),
+                (None, None) => true,
+                _ => false,
+            }
+    }
+}
+
+/// How to react to a failed resolution, as decided by the classifier set with
+/// [`AutoBalancedChannelBuilder::with_error_classifier`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorDisposition {
+    /// Keep the current endpoints and retry on the next cycle.
+    #[default]
+    Retain,
+    /// Remove the current endpoints and retry on the next cycle.
+    Drain,
+    /// Remove the current endpoints and stop resolving for good. Health
+    /// becomes [`Health::Stopped`].
+    Fatal,
+}
+
+pub(crate) type ErrorClassifier = Arc<dyn Fn(&io::Error) -> ErrorDisposition + Send + Sync>;
+
+pub(crate) type HealthDerivation =
+    Arc<dyn Fn(usize, &DnsStatus, Option<Duration>) -> Health + Send + Sync>;
+
+/// Arbitrary metadata of an endpoint, e.g. its availability zone or region,
+/// see [`AutoBalancedChannelBuilder::with_endpoint_tagger`].
+pub type EndpointTags = BTreeMap<String, String>;
+
+pub(crate) type EndpointTagger = Arc<dyn Fn(IpAddr) -> EndpointTags + Send + Sync>;
+
+/// Outcome of a single resolution, see
+/// [`AutoBalancedChannel::resolution_history`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolutionRecord {
+    /// When the resolution finished.
+    pub timestamp: SystemTime,
+    /// Number of addresses returned, before any filtering.
+    pub count: usize,
+    /// Why the result was not used, if it wasn't.
+    pub error: Option<ResolutionError>,
+}
+
+/// Settings of a running channel, changed with
+/// [`AutoBalancedChannel::update_config`]. Changes take effect on the next
+/// cycle of the background task.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RuntimeConfig {
+    /// How often to resolve the domain, see
+    /// [`AutoBalancedChannelBuilder::interval`]. Raised to the minimum
+    /// interval if needed. The next resolution is a full interval after the
+    /// change.
+    pub interval: Duration,
+    /// Ignore resolved IPv6 addresses, see
+    /// [`AutoBalancedChannelBuilder::disable_ipv6`]. IPv6 addresses are
+    /// ignored regardless once
+    /// [`AutoBalancedChannelBuilder::auto_disable_ipv6`] finds no route.
+    pub disable_ipv6: bool,
+}
+
+/// Consistent view of the state of a channel, see
+/// [`AutoBalancedChannel::snapshot`].
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChannelSnapshot {
+    pub health: Health,
+    pub dns_status: DnsStatus,
+    /// Addresses registered with the balanced channel, sorted.
+    pub endpoints: Vec<SocketAddr>,
+    /// When the latest successful resolution finished.
+    pub last_success: Option<SystemTime>,
+    /// How long the latest resolution took, successful or not.
+    pub last_latency: Option<Duration>,
+}
+
+impl ChannelSnapshot {
+    pub(crate) fn new() -> Self {
+        Self {
+            health: Health::Broken,
+            dns_status: DnsStatus::Pending,
+            endpoints: Vec::new(),
+            last_success: None,
+            last_latency: None,
+        }
+    }
+
+    pub fn endpoint_count(&self) -> usize {
+        self.endpoints.len()
+    }
+}
+
+/// Which name endpoints use as their origin (the `:authority` of requests),
+/// see [`AutoBalancedChannelBuilder::origin_name`].
+#[cfg(feature = "async-resolver")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OriginName {
+    /// The template's domain, or its explicitly configured origin.
+    #[default]
+    Original,
+    /// The name at the end of the domain's CNAME chain, as reported by
+    /// [`Resolver::canonical_name`].
+    Canonical,
+}
+
+/// Order in which newly resolved endpoints are inserted into the balanced
+/// channel, see [`AutoBalancedChannelBuilder::address_order`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressOrder {
+    /// Sorted by address, so that the order is the same whatever the resolver
+    /// returns.
+    #[default]
+    Sorted,
+    /// Random, so that many clients resolving the same records don't all
+    /// favour the same ones.
+    Shuffled,
+    /// As returned by the resolver, e.g. one sorting by proximity. Endpoints
+    /// the latest resolution didn't return, like those kept by
+    /// [`AutoBalancedChannelBuilder::with_accumulation_window`], come last.
+    ResolverOrder,
+}
+
+/// What [`AutoBalancedChannelBuilder::connect`] does when the first
+/// resolution succeeds without any addresses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmptyResolutionPolicy {
+    /// Return the channel anyway. It's [`Health::Broken`] until a later
+    /// resolution finds endpoints.
+    #[default]
+    AllowEmpty,
+    /// Fail with [`ChannelError::NoEndpoints`], treating an empty result as a
+    /// configuration error.
+    FailOnEmpty,
+}
+
+/// How [`AutoBalancedChannelBuilder::connect_with_retry`] retries the first
+/// resolution.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Resolutions to try in total, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each one after it.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Why [`AutoBalancedChannelBuilder::connect`] failed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChannelError {
+    /// The first resolution failed.
+    Resolution(ResolutionError),
+    /// The first resolution returned no addresses, and
+    /// [`EmptyResolutionPolicy::FailOnEmpty`] was requested.
+    NoEndpoints,
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Resolution(error) => write!(f, "first resolution failed: {error}"),
+            Self::NoEndpoints => f.write_str("first resolution returned no endpoints"),
+        }
+    }
+}
+
+impl Error for ChannelError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Resolution(error) => Some(error),
+            Self::NoEndpoints => None,
         }
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Health {
     /// There is at least one successfully detected and available endpoint
+    /// (or at least the configured minimum number of them).
     Ok,
-    /// Latest DNS resolution has failed, but there are still previously
-    /// registered endpoints, so making gRPC calls could succeed.
+    /// There are some endpoints available, but fewer than the minimum
+    /// configured with
+    /// [`AutoBalancedChannelBuilder::with_min_healthy_endpoints`].
+    Degraded,
+    /// There are endpoints, but none of them could be connected to in the
+    /// latest connection probe. Only reported with
+    /// [`AutoBalancedChannelBuilder::with_connection_probes`] enabled, until
+    /// endpoints are added or removed, as the new set hasn't been probed.
+    ///
+    /// Takes precedence over [`Health::Undetermined`] and
+    /// [`Health::Degraded`]: calls fail whatever DNS says or however many
+    /// endpoints there are.
+    Unreachable { last_error: String },
+    /// Latest DNS resolution has failed (or returned no address, see
+    /// [`AutoBalancedChannelBuilder::retain_on_empty`]), but there are still
+    /// previously registered endpoints, so making gRPC calls could succeed.
     Undetermined,
     /// There are no endpoints available. Calling gRPC method will block until
     /// one is detected.
     Broken,
+    /// Discovery stopped after a resolution error classified as
+    /// [`ErrorDisposition::Fatal`]. There are no endpoints and there never
+    /// will be.
+    Stopped,
+}
+
+impl Health {
+    pub(crate) fn derive(
+        endpoints_count: usize,
+        last_connection_error: Option<&str>,
+        dns_status: &DnsStatus,
+        min_healthy_endpoints: usize,
+    ) -> Self {
+        if endpoints_count == 0 {
+            Health::Broken
+        } else if let Some(last_error) = last_connection_error {
+            Health::Unreachable {
+                last_error: last_error.to_owned(),
+            }
+        } else if dns_status.is_error() || *dns_status == DnsStatus::Empty {
+            Health::Undetermined
+        } else if endpoints_count < min_healthy_endpoints {
+            Health::Degraded
+        } else {
+            Health::Ok
+        }
+    }
 }
 
 impl AutoBalancedChannel {
     const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+    const DEFAULT_MIN_INTERVAL: Duration = Duration::from_millis(5);
+    const DEFAULT_HISTORY_SIZE: usize = 16;
+    const DEFAULT_EVENT_BUFFER: usize = 64;
+    const DEFAULT_MAX_CONCURRENT_CONNECTS: usize = 16;
+    const DEFAULT_IPV6_ROUTE_TARGET: Ipv6Addr =
+        Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888);
 
     pub fn new(endpoint_template: EndpointTemplate) -> Self {
-        Self::with_interval(endpoint_template, Self::DEFAULT_INTERVAL)
+        Self::builder(endpoint_template).build()
     }
 
     pub fn with_interval(
         endpoint_template: EndpointTemplate,
         interval: Duration,
     ) -> AutoBalancedChannel {
-        let (channel, sender) = Channel::balance_channel::<IpAddr>(16);
-        let (dns_status_setter, dns_status_reader) = watch::channel::<DnsStatus>(DnsStatus::Ok);
-        let (endpoints_count_setter, endpoints_count_reader) = watch::channel::<usize>(0);
-
-        let background_task = tokio::spawn(async move {
-            let add_endpoint = |ip_address: IpAddr| {
-                let new_endpoint = endpoint_template.build(ip_address);
-                sender.send(Change::Insert(ip_address, new_endpoint))
-            };
+        Self::builder(endpoint_template).interval(interval).build()
+    }
 
-            let mut old_endpoints: HashSet<IpAddr> = HashSet::new();
-            let mut interval = tokio::time::interval(interval);
-            loop {
-                if sender.is_closed() {
-                    return;
-                }
-
-                match resolve_domain(endpoint_template.domain()) {
-                    Ok(ip_addrs) => {
-                        let _ = dns_status_setter.send(DnsStatus::Ok);
-                        let new_endpoints: HashSet<IpAddr> = ip_addrs.collect();
-
-                        for new_ip in new_endpoints.difference(&old_endpoints) {
-                            let _ = add_endpoint(*new_ip).await;
-                        }
-
-                        for old_ip in old_endpoints.difference(&new_endpoints) {
-                            let _ = sender.send(Change::Remove(*old_ip)).await;
-                        }
-
-                        old_endpoints = new_endpoints;
-
-                        let _ = endpoints_count_setter.send(old_endpoints.len());
-                    }
-                    Err(e) => {
-                        // DNS resolution errors might be recoverable and does
-                        // not necessarily spell doom for the channel. Because
-                        // of this, we just report the interim problem and use
-                        // last known IP addresses.
-                        let _ = dns_status_setter.send(DnsStatus::resolution_error(e));
-                    }
-                };
+    /// Shorthand for [`AutoBalancedChannelBuilder::connect_with_retry`] with
+    /// the default settings, retrying empty results too, e.g. while a
+    /// dependency deployed alongside has no ready backends yet.
+    pub async fn connect_with_retry(
+        endpoint_template: EndpointTemplate,
+        retry_policy: RetryPolicy,
+    ) -> Result<AutoBalancedChannel, ChannelError> {
+        Self::builder(endpoint_template)
+            .connect_with_retry(EmptyResolutionPolicy::FailOnEmpty, retry_policy)
+            .await
+    }
 
-                interval.tick().await;
-            }
+    /// Shorthand for [`AutoBalancedChannelBuilder::resolve_once`] with the
+    /// default settings.
+    pub async fn resolve_once(
+        endpoint_template: EndpointTemplate,
+    ) -> Result<BalancedChannel, ChannelError> {
+        Self::builder(endpoint_template).resolve_once().await
+    }
+
+    /// Builder preset for a Kubernetes headless service, whose domain (e.g.
+    /// `my-svc.my-namespace.svc.cluster.local`) resolves straight to the IPs
+    /// of its ready pods:
+    ///
+    /// - resolve every 5 seconds, CoreDNS' default TTL for cluster records, to
+    ///   keep up with pod churn;
+    /// - give up on resolutions after 2 seconds (see
+    ///   [`AutoBalancedChannelBuilder::dns_timeout`]), so a struggling cluster
+    ///   DNS doesn't hold up the next cycle.
+    ///
+    /// Addresses aren't accumulated over resolutions: a pod leaves the records
+    /// as soon as it stops being ready or starts terminating, so keeping it
+    /// would send traffic to a draining or dead pod, and restarted pods
+    /// usually come back with a new IP anyway.
+    ///
+    /// Both address families are kept. In dual-stack clusters each pod has an
+    /// address of each family, so add
+    /// [`AutoBalancedChannelBuilder::disable_ipv6`] (or filter IPv4 with a
+    /// custom resolver) to avoid balancing every pod twice.
+    pub fn kubernetes_headless(endpoint_template: EndpointTemplate) -> AutoBalancedChannelBuilder {
+        Self::builder(endpoint_template)
+            .interval(Duration::from_secs(5))
+            .dns_timeout(Duration::from_secs(2))
+    }
+
+    pub fn builder(endpoint_template: EndpointTemplate) -> AutoBalancedChannelBuilder {
+        AutoBalancedChannelBuilder {
+            endpoint_template,
+            resolver: Arc::new(SystemResolver),
+            #[cfg(feature = "async-resolver")]
+            dns_server: None,
+            #[cfg(feature = "async-resolver")]
+            query_types: QueryTypes::default(),
+            interval: Self::DEFAULT_INTERVAL,
+            min_interval: Self::DEFAULT_MIN_INTERVAL,
+            dns_timeout: None,
+            unmap_ipv4_mapped: true,
+            disable_ipv6: false,
+            auto_disable_ipv6: None,
+            min_healthy_endpoints: 1,
+            health_derivation: None,
+            balance_policy: BalancePolicy::default(),
+            result_sanity_limit: None,
+            refuse_oversized_results: false,
+            retain_on_empty: false,
+            static_addrs: None,
+            loopback_localhost: false,
+            accumulation_window: 1,
+            accumulation_ttl: None,
+            address_order: AddressOrder::default(),
+            rng_seed: None,
+            error_classifier: None,
+            tagger: None,
+            subnet_weights: Vec::new(),
+            seed_addrs: Vec::new(),
+            health_check: None,
+            connection_probe_interval: None,
+            max_connection_failures: None,
+            max_concurrent_connects: Self::DEFAULT_MAX_CONCURRENT_CONNECTS,
+            max_connection_age: None,
+            idle_timeout: None,
+            defer_removals: false,
+            rediscover_lost_connections: false,
+            track_load: false,
+            request_deadline: None,
+            circuit_breaker: None,
+            event_buffer: Self::DEFAULT_EVENT_BUFFER,
+            lag_policy: LagPolicy::default(),
+            delta_sink: None,
+            channel_name: None,
+            #[cfg(feature = "async-resolver")]
+            origin_name: OriginName::default(),
+            history_size: Self::DEFAULT_HISTORY_SIZE,
+        }
+    }
+
+    fn spawn(
+        builder: AutoBalancedChannelBuilder,
+        channel: BalancedChannel,
+        sender: mpsc::Sender<Change<SocketAddr, Endpoint>>,
+        tracking: EndpointTracking,
+    ) -> AutoBalancedChannel {
+        let (mut balanced, discovery, shutdown_receiver, span) =
+            Self::assemble(builder, channel, sender, tracking);
+        balanced.background_task = Some(tokio::spawn(
+            discovery.run(shutdown_receiver).instrument(span),
+        ));
+        balanced
+    }
+
+    /// Sets up the channel and its discovery, without running the latter,
+    /// and returns the span to run it in.
+    fn assemble(
+        builder: AutoBalancedChannelBuilder,
+        channel: BalancedChannel,
+        sender: mpsc::Sender<Change<SocketAddr, Endpoint>>,
+        tracking: EndpointTracking,
+    ) -> (AutoBalancedChannel, Discovery, oneshot::Receiver<()>, Span) {
+        let EndpointTracking {
+            last_used,
+            requests,
+            losses,
+            weights,
+        } = tracking;
+        let AutoBalancedChannelBuilder {
+            endpoint_template,
+            resolver,
+            #[cfg(feature = "async-resolver")]
+                dns_server: _,
+            #[cfg(feature = "async-resolver")]
+                query_types: _,
+            interval,
+            min_interval,
+            dns_timeout,
+            unmap_ipv4_mapped,
+            disable_ipv6,
+            auto_disable_ipv6,
+            min_healthy_endpoints,
+            health_derivation,
+            balance_policy: _,
+            result_sanity_limit,
+            refuse_oversized_results,
+            retain_on_empty,
+            static_addrs,
+            loopback_localhost,
+            accumulation_window,
+            accumulation_ttl,
+            address_order,
+            rng_seed,
+            error_classifier,
+            tagger,
+            subnet_weights,
+            seed_addrs,
+            health_check,
+            connection_probe_interval,
+            max_connection_failures,
+            max_concurrent_connects,
+            max_connection_age,
+            idle_timeout,
+            defer_removals,
+            rediscover_lost_connections: _,
+            track_load: _,
+            request_deadline: _,
+            circuit_breaker: _,
+            event_buffer,
+            lag_policy,
+            delta_sink,
+            channel_name,
+            #[cfg(feature = "async-resolver")]
+            origin_name,
+            history_size,
+        } = builder;
+
+        let name = channel_name.unwrap_or_else(|| endpoint_template.domain().to_owned());
+        let span = tracing::info_span!("auto_balanced_channel", channel = %name);
+
+        let (dns_status_setter, dns_status_reader) =
+            watch::channel::<DnsStatus>(DnsStatus::Pending);
+        let (health_setter, health_reader) = watch::channel::<Health>(Health::Broken);
+        let (delta_setter, delta_reader) = watch::channel((0, 0));
+        let (endpoint_count_setter, endpoint_count_reader) = watch::channel(0);
+        let (event_sender, _) = broadcast::channel(event_buffer.max(1));
+        let (health_event_sender, _) = broadcast::channel(event_buffer.max(1));
+        let (template_setter, endpoint_template) = watch::channel(endpoint_template);
+        let rebuild = Arc::new(Notify::new());
+        let refresh = Arc::new(Notify::new());
+        let (next_resolution_setter, next_resolution_reader) = watch::channel(None);
+        let (resolution_cycles_setter, resolution_cycles_reader) = watch::channel(0);
+        let (send_failures_setter, send_failures_reader) = watch::channel(0);
+        let (pause_setter, paused) = watch::channel(false);
+        let (connection_failures_setter, connection_failures_reader) =
+            watch::channel(HashMap::new());
+        let (history_setter, history_reader) = watch::channel(VecDeque::new());
+        let (tags_setter, tags_reader) = watch::channel(HashMap::new());
+        let weights_setter = weights.unwrap_or_else(|| Arc::new(watch::channel(HashMap::new()).0));
+        let weights_reader = weights_setter.subscribe();
+        let (snapshot_setter, snapshot_reader) = watch::channel(ChannelSnapshot::new());
+        #[cfg(feature = "async-resolver")]
+        let (canonical_name_setter, canonical_name_reader) = watch::channel(None);
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        let (resolution_outcome, resolution_outcome_reader) = watch::channel(None);
+
+        #[cfg(feature = "fault-injection")]
+        let faults = Faults::default();
+        #[cfg(feature = "fault-injection")]
+        let resolver = Arc::new(FaultInjector::new(resolver, faults.clone()));
+
+        let interval = interval.max(min_interval);
+        let (config_setter, config) = watch::channel(RuntimeConfig {
+            interval,
+            disable_ipv6,
         });
+        let mut discovery = Discovery {
+            endpoint_template,
+            origin: None,
+            #[cfg(feature = "async-resolver")]
+            follow_cnames: origin_name == OriginName::Canonical,
+            #[cfg(feature = "async-resolver")]
+            canonical_name_setter,
+            rebuild: rebuild.clone(),
+            refresh: refresh.clone(),
+            next_resolution_setter,
+            resolution_cycles_setter,
+            send_failures_setter,
+            paused,
+            resolver,
+            config,
+            interval,
+            dns_timeout,
+            unmap_ipv4_mapped,
+            disable_ipv6,
+            ipv6_route_target: auto_disable_ipv6,
+            ipv6_unroutable: false,
+            min_healthy_endpoints,
+            health_derivation,
+            result_sanity_limit,
+            refuse_oversized_results,
+            retain_on_empty,
+            static_addrs,
+            loopback_localhost,
+            accumulation_window,
+            accumulation_ttl,
+            recent_results: VecDeque::new(),
+            address_order,
+            resolution_order: HashMap::new(),
+            rng: rng_seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64),
+            error_classifier,
+            tagger,
+            tags_setter,
+            subnet_weights,
+            weights_setter,
+            sender,
+            dns_status_setter,
+            health_setter,
+            delta_setter,
+            endpoint_count_setter,
+            event_sender: event_sender.clone(),
+            health_event_sender: health_event_sender.clone(),
+            delta_sender: delta_sink.map(|(sink, buffer, overflow)| {
+                span.in_scope(|| DeltaSender::spawn(sink, buffer, overflow))
+            }),
+            health_checker: health_check.map(HealthChecker::new),
+            connection_probe_interval,
+            max_concurrent_connects: max_concurrent_connects.max(1),
+            max_connection_age,
+            recycle_at: HashMap::new(),
+            idle_timeout,
+            last_used,
+            inserted_at: HashMap::new(),
+            retirement: requests.clone().filter(|_| defer_removals),
+            retiring: HashSet::new(),
+            connection_losses: losses,
+            disconnected: HashSet::new(),
+            connection_failures_setter,
+            history_setter,
+            history_size,
+            snapshot_setter,
+            last_success: None,
+            last_success_at: None,
+            last_latency: None,
+            resolution_outcome,
+            last_connection_error: None,
+            resolved: HashSet::new(),
+            resolver_weights: HashMap::new(),
+            ejected: HashSet::new(),
+            max_connection_failures,
+            consecutive_failures: HashMap::new(),
+            unreachable: HashSet::new(),
+            endpoints: HashSet::new(),
+        };
+        discovery.seed(seed_addrs);
 
-        Self {
+        let balanced = Self {
+            name,
             channel,
-            background_task,
+            background_task: None,
+            shutdown_sender: Some(shutdown_sender),
             dns_status_reader,
-            endpoints_count_reader,
-        }
+            health_reader,
+            delta_reader,
+            endpoint_count_reader,
+            event_sender,
+            health_event_sender,
+            lag_policy,
+            template_setter,
+            rebuild,
+            refresh,
+            next_resolution_reader,
+            resolution_cycles_reader,
+            send_failures_reader,
+            requests,
+            #[cfg(feature = "fault-injection")]
+            faults,
+            pause_setter,
+            connection_failures_reader,
+            tags_reader,
+            weights_reader,
+            history_reader,
+            snapshot_reader,
+            #[cfg(feature = "async-resolver")]
+            canonical_name_reader,
+            config_setter,
+            min_interval,
+            resolution_outcome_reader,
+        };
+        (balanced, discovery, shutdown_receiver, span)
     }
 
-    pub fn channel(&self) -> Channel {
+    /// Returns the channel balancing requests over the discovered endpoints.
+    ///
+    /// This used to be tonic's `Channel`, which can only balance with its own
+    /// policy. Generated clients accept a [`BalancedChannel`] just the same,
+    /// but code naming tonic's type, e.g. `FooClient<Channel>`, has to name
+    /// `BalancedChannel` instead.
+    pub fn channel(&self) -> BalancedChannel {
         self.channel.clone()
     }
 
@@ -126,18 +759,2827 @@ impl AutoBalancedChannel {
     }
 
     pub fn get_health(&self) -> Health {
-        if *self.endpoints_count_reader.borrow() == 0 {
-            Health::Broken
-        } else if self.dns_status_reader.borrow().is_error() {
-            Health::Undetermined
-        } else {
-            Health::Ok
+        self.health_reader.borrow().to_owned()
+    }
+
+    /// Returns the name of the channel, see
+    /// [`AutoBalancedChannelBuilder::channel_name`].
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the domain being resolved, which reflects
+    /// [`Self::update_template`].
+    pub fn domain(&self) -> String {
+        self.template_setter.borrow().domain().to_owned()
+    }
+
+    /// Returns the resolution interval, after raising it to the minimum
+    /// interval if needed.
+    pub fn interval(&self) -> Duration {
+        self.config_setter.borrow().interval
+    }
+
+    /// Returns the current runtime-tunable settings.
+    pub fn config(&self) -> RuntimeConfig {
+        self.config_setter.borrow().clone()
+    }
+
+    /// Change any number of runtime-tunable settings at once. The background
+    /// task picks all of them up together on its next cycle.
+    pub fn update_config(&self, update: impl FnOnce(&mut RuntimeConfig)) {
+        self.config_setter.send_modify(|config| {
+            update(config);
+            config.interval = config.interval.max(self.min_interval);
+        });
+    }
+
+    /// Change how often the domain is resolved, see [`Self::update_config`].
+    pub fn set_interval(&self, interval: Duration) {
+        self.update_config(|config| config.interval = interval);
+    }
+
+    /// Change whether resolved IPv6 addresses are ignored, see
+    /// [`Self::update_config`].
+    pub fn set_disable_ipv6(&self, disabled: bool) {
+        self.update_config(|config| config.disable_ipv6 = disabled);
+    }
+
+    /// Replace the template used to build endpoints.
+    ///
+    /// Existing endpoints and their connections are kept as they are; only
+    /// endpoints added by later DNS polling cycles use the new template, unless
+    /// followed by [`Self::rebuild_all`]. If the domain changed, the next cycle
+    /// resolves the new one.
+    ///
+    /// The template's default metadata and proxy are applied by the balanced
+    /// channel and are not updated there.
+    pub fn update_template(&self, endpoint_template: EndpointTemplate) {
+        self.template_setter.send_replace(endpoint_template);
+    }
+
+    /// Rebuild all current endpoints with the current template, for template
+    /// changes that must also apply to existing connections (e.g. timeouts or
+    /// TLS). Each endpoint is replaced in place, so the channel never runs out
+    /// of endpoints meanwhile. Happens in the background shortly after.
+    pub fn rebuild_all(&self) {
+        self.rebuild.notify_one();
+    }
+
+    /// Resolve the domain right away rather than at the next interval, e.g.
+    /// after being told the backends changed. Happens in the background
+    /// shortly after, unless polling is paused. Forced resolutions are at
+    /// least half a second apart: calls in between are coalesced into a single
+    /// resolution at the end of it.
+    pub fn refresh(&self) {
+        self.refresh.notify_one();
+    }
+
+    /// Make the next resolution return `fault` instead of the resolver's
+    /// result, e.g. to check how the application copes with DNS failing in
+    /// staging. Unlike `mock_net`, only this channel is
+    /// affected. Static addresses and `localhost` with
+    /// [`AutoBalancedChannelBuilder::loopback_localhost`] bypass the resolver,
+    /// hence faults too.
+    #[cfg(feature = "fault-injection")]
+    pub fn inject_fault(&self, fault: Fault) {
+        self.inject_faults(fault, 1);
+    }
+
+    /// Like [`Self::inject_fault`], for the next `count` resolutions. Faults
+    /// already injected come first.
+    #[cfg(feature = "fault-injection")]
+    pub fn inject_faults(&self, fault: Fault, count: usize) {
+        self.faults
+            .lock()
+            .unwrap()
+            .extend((0..count).map(|_| fault.clone()));
+    }
+
+    /// Returns how long until the domain is resolved next, periodically or
+    /// as forced by [`Self::refresh`], e.g. for a dashboard. Zero while that
+    /// resolution is overdue or under way, `None` if none is coming: while
+    /// paused, after shutdown, or with static addresses.
+    ///
+    /// This is an estimate made at the end of the latest cycle of the
+    /// background task; a busy task may resolve a little later.
+    pub fn time_until_next_resolution(&self) -> Option<Duration> {
+        self.next_resolution_reader
+            .borrow()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Stop DNS polling, e.g. during a maintenance window, until
+    /// [`Self::resume`]. The current endpoints are kept and health checks,
+    /// connection probes and recycling carry on as usual.
+    pub fn pause(&self) {
+        self.pause_setter.send_replace(true);
+    }
+
+    /// Resume DNS polling stopped by [`Self::pause`]. If an interval elapsed
+    /// meanwhile, the domain is resolved right away.
+    pub fn resume(&self) {
+        self.pause_setter.send_replace(false);
+    }
+
+    /// Returns how many endpoints were added and removed, respectively, in the
+    /// most recent DNS polling cycle.
+    pub fn last_delta(&self) -> (usize, usize) {
+        *self.delta_reader.borrow()
+    }
+
+    /// Returns the number of endpoints currently registered with the balanced
+    /// channel.
+    pub fn endpoint_count(&self) -> usize {
+        *self.endpoint_count_reader.borrow()
+    }
+
+    /// Returns a receiver of the DNS status, for awaiting changes directly.
+    /// It's live: the status is updated after every resolution.
+    pub fn dns_status_receiver(&self) -> Receiver<DnsStatus> {
+        self.dns_status_reader.clone()
+    }
+
+    /// Returns a receiver of [`Self::endpoint_count`], for awaiting changes
+    /// directly. It's live: the count is updated whenever endpoints are added
+    /// or removed.
+    pub fn endpoint_count_receiver(&self) -> Receiver<usize> {
+        self.endpoint_count_reader.clone()
+    }
+
+    /// Returns a receiver of every endpoint added to or removed from the
+    /// balanced channel from now on. Unlike the other observers, it doesn't
+    /// only see the latest state, as long as it keeps up with the buffer depth
+    /// set with [`AutoBalancedChannelBuilder::with_event_buffer`].
+    pub fn events(&self) -> EndpointEvents {
+        EndpointEvents::new(self.event_sender.subscribe(), self.lag_policy)
+    }
+
+    /// Returns a receiver of every transition between [`Health::Ok`] and
+    /// [`Health::Undetermined`] from now on, e.g. to alert when the channel
+    /// starts relying on stale endpoints. Buffered like [`Self::events`].
+    pub fn health_events(&self) -> HealthEvents {
+        HealthEvents::new(self.health_event_sender.subscribe(), self.lag_policy)
+    }
+
+    /// Returns how many connection attempts to each current endpoint have
+    /// failed. Always empty unless enabled with
+    /// [`AutoBalancedChannelBuilder::with_connection_probes`].
+    ///
+    /// Unlike [`Self::get_health`], this tells apart endpoints that are
+    /// resolved but dead.
+    pub fn connection_failures(&self) -> HashMap<SocketAddr, u64> {
+        self.connection_failures_reader.borrow().clone()
+    }
+
+    /// Returns the tags of the endpoints with `ip`, as computed by the tagger
+    /// set with [`AutoBalancedChannelBuilder::with_endpoint_tagger`]. `None`
+    /// if there is no tagger or no such endpoint.
+    ///
+    /// Balancing doesn't take tags into account; they are meant for clients
+    /// doing their own routing.
+    pub fn endpoint_tags(&self, ip: IpAddr) -> Option<EndpointTags> {
+        self.tags_reader.borrow().get(&ip).cloned()
+    }
+
+    /// Returns the load of the current endpoints, by IP address, summed over
+    /// endpoints sharing one. Tower's balancers keep their own load figures
+    /// to themselves, so this is tracked separately, and is empty unless
+    /// enabled with [`AutoBalancedChannelBuilder::with_load_tracking`] (or
+    /// [`AutoBalancedChannelBuilder::defer_removals`], which tracks it too).
+    pub fn endpoint_load(&self) -> HashMap<IpAddr, Load> {
+        let Some(requests) = &self.requests else {
+            return HashMap::new();
+        };
+        let in_flight = requests.in_flight();
+        let mut loads: HashMap<IpAddr, Load> = HashMap::new();
+        for addr in &self.snapshot_reader.borrow().endpoints {
+            let load = loads.entry(addr.ip()).or_default();
+            load.in_flight += in_flight.get(addr).copied().unwrap_or_default();
+        }
+        loads
+    }
+
+    /// Returns the weight of the endpoints with `ip`: the weight the resolver
+    /// gave it (e.g. from SRV records, see [`Resolver::resolve_weighted`])
+    /// times that of its subnet, as assigned with
+    /// [`AutoBalancedChannelBuilder::with_subnet_weights`]. Both default to 1,
+    /// and a product of 0 counts as 1. `None` if there is no such endpoint.
+    ///
+    /// Only [`BalancePolicy::RoundRobin`] balances by weight.
+    pub fn endpoint_weight(&self, ip: IpAddr) -> Option<u32> {
+        self.weights_reader.borrow().get(&ip).copied()
+    }
+
+    /// Returns the name at the end of the domain's CNAME chain, if the domain
+    /// is an alias. Only looked up with [`OriginName::Canonical`].
+    #[cfg(feature = "async-resolver")]
+    pub fn canonical_name(&self) -> Option<String> {
+        self.canonical_name_reader.borrow().clone()
+    }
+
+    /// Returns the outcomes of the most recent resolutions, oldest first. How
+    /// many are kept is set with
+    /// [`AutoBalancedChannelBuilder::with_resolution_history_size`].
+    pub fn resolution_history(&self) -> Vec<ResolutionRecord> {
+        self.history_reader.borrow().iter().cloned().collect()
+    }
+
+    /// Returns the state of the channel as of the end of the latest cycle of
+    /// the background task, unlike the individual getters, which may each
+    /// reflect a different cycle.
+    pub fn snapshot(&self) -> ChannelSnapshot {
+        self.snapshot_reader.borrow().clone()
+    }
+
+    /// Returns the domain and [`Self::snapshot`] as a JSON object, e.g. for an
+    /// admin endpoint dumping the state of the application's channels.
+    #[cfg(feature = "serde")]
+    pub fn topology_json(&self) -> String {
+        #[derive(serde::Serialize)]
+        struct Topology<'a> {
+            domain: String,
+            #[serde(flatten)]
+            snapshot: &'a ChannelSnapshot,
+        }
+
+        let snapshot = self.snapshot_reader.borrow();
+        serde_json::to_string(&Topology {
+            domain: self.domain(),
+            snapshot: &snapshot,
+        })
+        .expect("snapshot serializable to JSON")
+    }
+
+    /// Returns how many times the domain has been resolved so far, whether
+    /// the resolution succeeded or not. Cycles of the background task doing
+    /// anything else, like health checks, don't count.
+    pub fn resolution_cycles(&self) -> u64 {
+        *self.resolution_cycles_reader.borrow()
+    }
+
+    /// Returns how many endpoint changes the balanced channel failed to take,
+    /// because it was gone or, for seed addresses, its buffer was full. A
+    /// growing count means that the channel no longer follows discovery.
+    pub fn send_failures(&self) -> u64 {
+        *self.send_failures_reader.borrow()
+    }
+
+    /// Waits until the domain has been resolved at least `cycles` times, see
+    /// [`Self::resolution_cycles`], e.g. in tests rather than sleeping in the
+    /// hope that a resolution happened. The channel's state is up to date with
+    /// that resolution by then.
+    ///
+    /// Returns `false` if discovery stopped short of it.
+    pub async fn wait_for_cycles(&self, cycles: u64) -> bool {
+        let mut resolution_cycles = self.resolution_cycles_reader.clone();
+        let reached = resolution_cycles
+            .wait_for(|resolved| *resolved >= cycles)
+            .await
+            .is_ok();
+        reached
+    }
+
+    /// Waits until an endpoint with `ip` is in the channel, e.g. a new canary
+    /// backend. Completes right away if there already is one.
+    ///
+    /// Never completes once discovery has stopped.
+    pub async fn wait_for_endpoint(&self, ip: IpAddr) {
+        self.wait_for_endpoints(|endpoints| endpoints.iter().any(|addr| addr.ip() == ip))
+            .await;
+    }
+
+    /// Waits until no endpoint with `ip` is in the channel anymore. Completes
+    /// right away if there is none.
+    pub async fn wait_for_endpoint_removed(&self, ip: IpAddr) {
+        self.wait_for_endpoints(|endpoints| endpoints.iter().all(|addr| addr.ip() != ip))
+            .await;
+    }
+
+    /// Waits until there are at least `count` endpoints in the channel, e.g.
+    /// for a quorum of backends before starting, whatever
+    /// [`AutoBalancedChannelBuilder::with_min_healthy_endpoints`] is.
+    /// Completes right away if there already are.
+    ///
+    /// Returns `false` if discovery stopped short of it.
+    pub async fn wait_for_min_endpoints(&self, count: usize) -> bool {
+        let mut endpoint_count = self.endpoint_count_reader.clone();
+        let reached = endpoint_count
+            .wait_for(|endpoints| *endpoints >= count)
+            .await
+            .is_ok();
+        reached
+    }
+
+    async fn wait_for_endpoints(&self, condition: impl Fn(&[SocketAddr]) -> bool) {
+        let mut snapshot = self.snapshot_reader.clone();
+        if snapshot
+            .wait_for(|snapshot| condition(&snapshot.endpoints))
+            .await
+            .is_err()
+        {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Returns `true` only when health is [`Health::Ok`].
+    ///
+    /// [`Health::Degraded`] and [`Health::Undetermined`] are neither healthy
+    /// nor broken: requests may still succeed, but the channel is not in the
+    /// state it was configured to be in.
+    pub fn is_healthy(&self) -> bool {
+        *self.health_reader.borrow() == Health::Ok
+    }
+
+    /// Returns `true` only when health is [`Health::Broken`], i.e. there are no
+    /// endpoints to send requests to. See [`Self::is_healthy`].
+    pub fn is_broken(&self) -> bool {
+        *self.health_reader.borrow() == Health::Broken
+    }
+
+    /// Returns `true` once any resolution has succeeded, whatever happened
+    /// since, telling "not resolved yet" apart from "failing now" e.g. at
+    /// startup. Results ignored as oversized or empty (see
+    /// [`AutoBalancedChannelBuilder::retain_on_empty`]) don't count.
+    pub fn has_resolved(&self) -> bool {
+        self.snapshot_reader.borrow().last_success.is_some()
+    }
+
+    /// Waits until the channel recovers from [`Health::Broken`], i.e. until
+    /// endpoints become available after a period of having none. If the
+    /// channel isn't broken now, this first waits for it to break.
+    ///
+    /// Never completes once discovery has stopped.
+    pub async fn wait_for_recovery(&self) {
+        let mut health = self.health_reader.clone();
+        let recovered = async {
+            health.wait_for(|health| *health == Health::Broken).await?;
+            health
+                .wait_for(|health| !matches!(health, Health::Broken | Health::Stopped))
+                .await
+                .map(|_| ())
+        };
+        if recovered.await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Split into the balanced channel and the handle of its discovery, so
+    /// that each can be owned separately, e.g. the channel by a larger tower
+    /// stack and the handle by whatever manages the channel's lifecycle.
+    pub fn into_parts(self) -> (BalancedChannel, DiscoveryHandle) {
+        (self.channel(), DiscoveryHandle { inner: self })
+    }
+
+    /// Stop the background DNS polling, deregister all current endpoints from
+    /// the balanced channel and wait for this to finish. Returns the number of
+    /// endpoints removed.
+    ///
+    /// Unlike dropping the channel, which simply aborts the background task,
+    /// this lets clones of [`Self::channel`] observe a clean teardown.
+    pub async fn shutdown(mut self) -> usize {
+        if let Some(shutdown_sender) = self.shutdown_sender.take() {
+            let _ = shutdown_sender.send(());
+        }
+        match self.background_task.take() {
+            Some(background_task) => background_task.await.unwrap_or(0),
+            None => 0,
         }
     }
 }
 
 impl Drop for AutoBalancedChannel {
     fn drop(&mut self) {
-        self.background_task.abort()
+        if let Some(background_task) = &self.background_task {
+            background_task.abort()
+        }
+    }
+}
+
+/// Discovery half of an [`AutoBalancedChannel`] split with
+/// [`AutoBalancedChannel::into_parts`]. It owns the background task and gives
+/// access to everything the channel reports and controls, except for the
+/// balanced channel itself.
+///
+/// Dropping it aborts the background task, after which the balanced channel
+/// keeps its last endpoints. Use [`Self::shutdown`] to deregister them too.
+pub struct DiscoveryHandle {
+    inner: AutoBalancedChannel,
+}
+
+impl DiscoveryHandle {
+    /// See [`AutoBalancedChannel::shutdown`].
+    pub async fn shutdown(self) -> usize {
+        self.inner.shutdown().await
+    }
+}
+
+impl Deref for DiscoveryHandle {
+    type Target = AutoBalancedChannel;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+pub struct AutoBalancedChannelBuilder {
+    endpoint_template: EndpointTemplate,
+    resolver: Arc<dyn Resolver>,
+    /// Set if `resolver` queries this DNS server, to recreate it with other
+    /// query types.
+    #[cfg(feature = "async-resolver")]
+    dns_server: Option<(SocketAddr, DnsProtocol)>,
+    #[cfg(feature = "async-resolver")]
+    query_types: QueryTypes,
+    interval: Duration,
+    min_interval: Duration,
+    dns_timeout: Option<Duration>,
+    unmap_ipv4_mapped: bool,
+    disable_ipv6: bool,
+    /// Address whose route is checked, if IPv6 is disabled without one.
+    auto_disable_ipv6: Option<Ipv6Addr>,
+    min_healthy_endpoints: usize,
+    health_derivation: Option<HealthDerivation>,
+    balance_policy: BalancePolicy,
+    result_sanity_limit: Option<usize>,
+    refuse_oversized_results: bool,
+    retain_on_empty: bool,
+    static_addrs: Option<Vec<SocketAddr>>,
+    loopback_localhost: bool,
+    accumulation_window: usize,
+    accumulation_ttl: Option<Duration>,
+    address_order: AddressOrder,
+    rng_seed: Option<u64>,
+    error_classifier: Option<ErrorClassifier>,
+    tagger: Option<EndpointTagger>,
+    subnet_weights: Vec<(IpNet, u32)>,
+    seed_addrs: Vec<SocketAddr>,
+    health_check: Option<HealthCheck>,
+    connection_probe_interval: Option<Duration>,
+    max_connection_failures: Option<u32>,
+    max_concurrent_connects: usize,
+    max_connection_age: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    defer_removals: bool,
+    rediscover_lost_connections: bool,
+    track_load: bool,
+    request_deadline: Option<Duration>,
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    event_buffer: usize,
+    lag_policy: LagPolicy,
+    delta_sink: Option<(DeltaSink, usize, SinkOverflow)>,
+    channel_name: Option<String>,
+    #[cfg(feature = "async-resolver")]
+    origin_name: OriginName,
+    history_size: usize,
+}
+
+impl AutoBalancedChannelBuilder {
+    /// How often to resolve the domain. Intervals shorter than the minimum
+    /// interval are raised to it, see [`Self::min_interval`].
+    pub fn interval(self, interval: Duration) -> Self {
+        Self { interval, ..self }
+    }
+
+    /// Give up on a resolution after `timeout` and report it as a resolution
+    /// error whose source has [`io::ErrorKind::TimedOut`], so that a stuck
+    /// resolver doesn't stall the background task. This is unrelated to
+    /// [`EndpointTemplate::connect_timeout`], which only limits connecting to
+    /// endpoints. Disabled by default.
+    pub fn dns_timeout(self, timeout: Duration) -> Self {
+        Self {
+            dns_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Resolve the template's domain with `resolver` instead of the system
+    /// resolver.
+    pub fn with_resolver(self, resolver: impl Resolver) -> Self {
+        self.with_shared_resolver(Arc::new(resolver))
+    }
+
+    /// Like [`Self::with_resolver`], but with a resolver shared with other
+    /// channels, e.g. one caching lookups so that channels resolving the same
+    /// domains don't each query DNS.
+    pub fn with_shared_resolver(self, resolver: Arc<dyn Resolver>) -> Self {
+        Self {
+            resolver,
+            #[cfg(feature = "async-resolver")]
+            dns_server: None,
+            ..self
+        }
+    }
+
+    /// Resolve the template's domain by querying the DNS server at `server`
+    /// rather than using the system resolver.
+    #[cfg(feature = "async-resolver")]
+    pub fn with_dns_server(self, server: SocketAddr, protocol: DnsProtocol) -> Self {
+        Self {
+            resolver: Arc::new(DnsServerResolver::with_query_types(
+                server,
+                protocol,
+                self.query_types,
+            )),
+            dns_server: Some((server, protocol)),
+            ..self
+        }
+    }
+
+    /// Which address records to query the DNS server set with
+    /// [`Self::with_dns_server`] for, e.g. [`QueryTypes::A`] to skip AAAA
+    /// lookups on IPv4-only networks. Other resolvers are unaffected; see
+    /// [`Self::disable_ipv6`] to filter their results instead. Defaults to
+    /// [`QueryTypes::AThenAaaa`].
+    #[cfg(feature = "async-resolver")]
+    pub fn query_types(self, query_types: QueryTypes) -> Self {
+        let builder = Self {
+            query_types,
+            ..self
+        };
+        match builder.dns_server {
+            Some((server, protocol)) => builder.with_dns_server(server, protocol),
+            None => builder,
+        }
+    }
+
+    /// Which name endpoints use as their origin. With
+    /// [`OriginName::Canonical`], the CNAME chain of the domain is followed on
+    /// every resolution and endpoints added afterwards use the name it ends
+    /// at, see [`AutoBalancedChannel::canonical_name`]. Defaults to
+    /// [`OriginName::Original`].
+    #[cfg(feature = "async-resolver")]
+    pub fn origin_name(self, origin_name: OriginName) -> Self {
+        Self {
+            origin_name,
+            ..self
+        }
+    }
+
+    /// Floor for the resolution interval, so that a pathologically short
+    /// interval doesn't keep the runtime busy resolving. Defaults to 5ms.
+    pub fn min_interval(self, min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            ..self
+        }
+    }
+
+    /// Convert IPv4-mapped IPv6 addresses (e.g. `::ffff:127.0.0.1`) into
+    /// their IPv4 form before registering endpoints, so that a host returned
+    /// in both representations gets a single connection. Enabled by default.
+    pub fn unmap_ipv4_mapped(self, enabled: bool) -> Self {
+        Self {
+            unmap_ipv4_mapped: enabled,
+            ..self
+        }
+    }
+
+    /// Ignore all IPv6 addresses returned by DNS. Useful on hosts without IPv6
+    /// connectivity, where connecting to them only times out slowly.
+    pub fn disable_ipv6(self, disabled: bool) -> Self {
+        Self {
+            disable_ipv6: disabled,
+            ..self
+        }
+    }
+
+    /// Ignore IPv6 addresses if, when discovery starts, this host has no
+    /// route to the IPv6 internet, as checked with Google Public DNS
+    /// (`2001:4860:4860::8888`) for a destination. Nothing is sent. Has no
+    /// effect if [`Self::disable_ipv6`] is set.
+    pub fn auto_disable_ipv6(self, enabled: bool) -> Self {
+        Self {
+            auto_disable_ipv6: enabled.then_some(AutoBalancedChannel::DEFAULT_IPV6_ROUTE_TARGET),
+            ..self
+        }
+    }
+
+    /// Like [`Self::auto_disable_ipv6`], but checks the route to `target`,
+    /// e.g. an address of the backends' network on hosts without a default
+    /// route.
+    pub fn auto_disable_ipv6_with_target(self, target: Ipv6Addr) -> Self {
+        Self {
+            auto_disable_ipv6: Some(target),
+            ..self
+        }
+    }
+
+    /// Require at least `count` endpoints for the channel to be reported as
+    /// [`Health::Ok`]. With fewer (but at least one) endpoints available,
+    /// health is [`Health::Degraded`]. Defaults to 1.
+    pub fn with_min_healthy_endpoints(self, count: usize) -> Self {
+        Self {
+            min_healthy_endpoints: count,
+            ..self
+        }
+    }
+
+    /// Derive health with `derive` instead of the default rules, to fit what
+    /// the caller considers healthy. It is passed the number of endpoints, the
+    /// DNS status and the time since the latest successful resolution (`None`
+    /// before the first one), and is called whenever discovery may have
+    /// changed any of them, so at least once per resolution.
+    ///
+    /// By default, health is, in order:
+    /// - [`Health::Broken`] without endpoints,
+    /// - [`Health::Unreachable`] if none of them connected in the latest
+    ///   connection probe,
+    /// - [`Health::Undetermined`] if the latest resolution failed or was
+    ///   empty,
+    /// - [`Health::Degraded`] with fewer endpoints than
+    ///   [`Self::with_min_healthy_endpoints`],
+    /// - [`Health::Ok`] otherwise.
+    ///
+    /// Neither connection probes nor the minimum number of endpoints are
+    /// taken into account by `derive`. [`Health::Stopped`] is reported once
+    /// discovery stops regardless.
+    pub fn with_health_derivation(
+        self,
+        derive: impl Fn(usize, &DnsStatus, Option<Duration>) -> Health + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            health_derivation: Some(Arc::new(derive)),
+            ..self
+        }
+    }
+
+    /// Strategy for spreading requests over the discovered endpoints. See
+    /// [`BalancePolicy`] for the supported policies.
+    pub fn balance_policy(self, balance_policy: BalancePolicy) -> Self {
+        Self {
+            balance_policy,
+            ..self
+        }
+    }
+
+    /// Log a warning whenever DNS returns more than `limit` addresses. This is
+    /// meant to catch misconfigured or poisoned DNS servers; see
+    /// [`Self::refuse_oversized_results`] to also ignore such results.
+    pub fn with_result_sanity_limit(self, limit: usize) -> Self {
+        Self {
+            result_sanity_limit: Some(limit),
+            ..self
+        }
+    }
+
+    /// Ignore resolutions exceeding the sanity limit and keep the previous
+    /// endpoints instead. The DNS status reports them as resolution errors.
+    pub fn refuse_oversized_results(self, enabled: bool) -> Self {
+        Self {
+            refuse_oversized_results: enabled,
+            ..self
+        }
+    }
+
+    /// Keep the previous endpoints when a resolution succeeds but returns no
+    /// address, e.g. during a deploy blip, like when resolution fails. The DNS
+    /// status reports such results as [`DnsStatus::Empty`] and health as
+    /// [`Health::Undetermined`]. Disabled by default, so that an empty result
+    /// removes all endpoints.
+    pub fn retain_on_empty(self, enabled: bool) -> Self {
+        Self {
+            retain_on_empty: enabled,
+            ..self
+        }
+    }
+
+    /// Balance over a fixed list of addresses instead of resolving the
+    /// template's domain, which is then only used for the `Host` header and
+    /// TLS. Port 0 means the template's port, like for resolved addresses.
+    ///
+    /// Handy for local development against known backends.
+    pub fn with_static_addresses(self, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        Self {
+            static_addrs: Some(addrs.into_iter().collect()),
+            ..self
+        }
+    }
+
+    /// Resolve `localhost` to the loopback addresses without asking the
+    /// resolver, so local development doesn't depend on its configuration.
+    /// IPv6 loopback is left out if IPv6 is disabled. Disabled by default.
+    pub fn loopback_localhost(self, enabled: bool) -> Self {
+        Self {
+            loopback_localhost: enabled,
+            ..self
+        }
+    }
+
+    /// Keep every address returned by any of the latest `cycles` resolutions
+    /// instead of only the latest one. This stops endpoints from churning
+    /// when DNS deliberately returns a rotating subset of the records, at the
+    /// cost of removing endpoints up to `cycles` intervals late. Defaults to 1,
+    /// i.e. only the latest resolution. See also [`Self::with_accumulation_ttl`].
+    pub fn with_accumulation_window(self, cycles: usize) -> Self {
+        Self {
+            accumulation_window: cycles,
+            ..self
+        }
+    }
+
+    /// Forget the addresses of a resolution within the accumulation window
+    /// once it is older than `ttl`, typically the TTL of the records, so that
+    /// removed records don't linger for long with a long interval or a wide
+    /// window. Resolvers don't report record TTLs, hence the setting. No
+    /// expiry by default.
+    pub fn with_accumulation_ttl(self, ttl: Duration) -> Self {
+        Self {
+            accumulation_ttl: Some(ttl),
+            ..self
+        }
+    }
+
+    /// Order in which newly resolved endpoints are inserted, which decides
+    /// e.g. which one [`BalancePolicy::RoundRobin`] starts with. Defaults to
+    /// [`AddressOrder::Sorted`].
+    pub fn address_order(self, address_order: AddressOrder) -> Self {
+        Self {
+            address_order,
+            ..self
+        }
+    }
+
+    /// Insert newly resolved endpoints in random order rather than sorted, so
+    /// that many clients resolving the same records don't all favour the same
+    /// ones. Disabled by default. Same as [`AddressOrder::Shuffled`], or
+    /// [`AddressOrder::Sorted`] if not `enabled`.
+    pub fn shuffle_addresses(self, enabled: bool) -> Self {
+        self.address_order(if enabled {
+            AddressOrder::Shuffled
+        } else {
+            AddressOrder::Sorted
+        })
+    }
+
+    /// Shuffle addresses (see [`Self::shuffle_addresses`]) in an order
+    /// determined by `seed`, for reproducible tests. Same as enabling
+    /// shuffling with [`Self::with_rng_seed`].
+    pub fn with_shuffle_seed(self, seed: u64) -> Self {
+        self.shuffle_addresses(true).with_rng_seed(seed)
+    }
+
+    /// Derive everything random from `seed` rather than from entropy, for
+    /// reproducible tests: the order of shuffled addresses, the stagger of
+    /// connection recycling and the endpoints picked by
+    /// [`BalancePolicy::PowerOfTwoChoices`] and
+    /// [`BalancePolicy::LeastPendingRequests`].
+    pub fn with_rng_seed(self, seed: u64) -> Self {
+        Self {
+            rng_seed: Some(seed),
+            ..self
+        }
+    }
+
+    /// Decide with `classifier` how to react to each failed resolution, e.g.
+    /// to drain the endpoints of a decommissioned service on
+    /// [`io::ErrorKind::NotFound`]. By default, all errors are
+    /// [`ErrorDisposition::Retain`].
+    pub fn with_error_classifier(
+        self,
+        classifier: impl Fn(&io::Error) -> ErrorDisposition + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            error_classifier: Some(Arc::new(classifier)),
+            ..self
+        }
+    }
+
+    /// Compute the tags of each endpoint IP with `tagger` as it's added, see
+    /// [`AutoBalancedChannel::endpoint_tags`].
+    pub fn with_endpoint_tagger(
+        self,
+        tagger: impl Fn(IpAddr) -> EndpointTags + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            tagger: Some(Arc::new(tagger)),
+            ..self
+        }
+    }
+
+    /// Weigh each endpoint by the most specific of `subnets` its IP is in, or
+    /// 1 if none, e.g. to prefer backends in the same rack. Plain A records
+    /// carry no weight, hence the manual configuration. A weight of 0 counts
+    /// as 1: endpoints can't be excluded this way. See
+    /// [`AutoBalancedChannel::endpoint_weight`].
+    pub fn with_subnet_weights(self, subnets: impl IntoIterator<Item = (IpNet, u32)>) -> Self {
+        Self {
+            subnet_weights: subnets.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Register `addrs` (e.g. remembered from a previous run) as soon as the
+    /// channel is built, so that it's usable before the first resolution.
+    /// Unlike [`Self::with_static_addresses`], the first resolution replaces
+    /// them.
+    pub fn with_seed_addresses(self, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        Self {
+            seed_addrs: addrs.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Periodically send `health_check` to every resolved endpoint and remove
+    /// endpoints failing it from the balanced channel. Ejected endpoints keep
+    /// being checked and are added back once they pass.
+    pub fn with_health_check(self, health_check: HealthCheck) -> Self {
+        Self {
+            health_check: Some(health_check),
+            ..self
+        }
+    }
+
+    /// Try to connect to every endpoint each `interval` and count the failed
+    /// attempts, see [`AutoBalancedChannel::connection_failures`]. Probes open
+    /// a new connection every time, so this is disabled by default.
+    pub fn with_connection_probes(self, interval: Duration) -> Self {
+        Self {
+            connection_probe_interval: Some(interval),
+            ..self
+        }
+    }
+
+    /// Drain endpoints failing `max_failures` connection probes in a row,
+    /// until they connect again, so that a channel whose every endpoint is
+    /// unreachable reports [`Health::Broken`] rather than staying `Ok` while
+    /// every request fails. Only takes effect with
+    /// [`Self::with_connection_probes`]. Disabled by default.
+    pub fn drain_after_connection_failures(self, max_failures: u32) -> Self {
+        Self {
+            max_connection_failures: Some(max_failures.max(1)),
+            ..self
+        }
+    }
+
+    /// How many connections connection probes and health checks may open at
+    /// once, so that probing many endpoints doesn't overwhelm local resources
+    /// or the backends. Defaults to 16. A limit of 0 is raised to 1.
+    ///
+    /// The same limit bounds how many endpoints are built at once for newly
+    /// resolved addresses.
+    pub fn with_max_concurrent_connects(self, limit: usize) -> Self {
+        Self {
+            max_concurrent_connects: limit,
+            ..self
+        }
+    }
+
+    /// Reconnect to each endpoint once its connection is `max_age` old, so
+    /// that long-lived HTTP/2 connections don't pin traffic to backends
+    /// trying to shed load. Reconnections are staggered by up to a quarter of
+    /// `max_age` so that they don't all happen at once. Disabled by default.
+    pub fn with_max_connection_age(self, max_age: Duration) -> Self {
+        Self {
+            max_connection_age: Some(max_age),
+            ..self
+        }
+    }
+
+    /// Close the connection to an endpoint once no request has been sent to
+    /// it for `timeout`. The endpoint stays in the channel and reconnects when
+    /// it's picked again. Disabled by default.
+    pub fn with_idle_connection_timeout(self, timeout: Duration) -> Self {
+        Self {
+            idle_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Keep endpoints that are gone from DNS in the balanced channel until no
+    /// request to them is in flight, rather than removing them right away,
+    /// which closes their connections and fails the requests. They get no new
+    /// requests meanwhile, and are reported removed from the start.
+    ///
+    /// This is simpler than fully draining connections: in-flight requests
+    /// only count until their response starts, so streaming responses can
+    /// still be cut, and nothing bounds the wait, so endpoints stay connected
+    /// as long as requests to them take. It also applies to endpoints removed
+    /// after [`ErrorDisposition::Drain`]. Disabled by default, as endpoints
+    /// are mostly removed once their backend is gone, when there's nothing to
+    /// wait for.
+    pub fn defer_removals(self, enabled: bool) -> Self {
+        Self {
+            defer_removals: enabled,
+            ..self
+        }
+    }
+
+    /// Take endpoints out of the balanced channel as soon as a request to them
+    /// fails at the connection level, e.g. because the backend closed the
+    /// connection with an HTTP/2 `GOAWAY` and no longer accepts new ones, and
+    /// resolve the domain again right away.
+    ///
+    /// Without this, tonic reconnects to the endpoint on the next request, and
+    /// keeps failing every request it's picked for while the backend is down.
+    /// With it, only the request that hit the lost connection fails, and the
+    /// endpoint is readmitted once a resolution still returns it and a new
+    /// connection to it succeeds, or removed like any other once DNS no longer
+    /// returns it. Endpoints that fail to connect when first used are taken
+    /// out the same way.
+    ///
+    /// Disabled by default, as an endpoint taken out doesn't come back before
+    /// the next resolution, which may be up to an interval later.
+    pub fn rediscover_lost_connections(self, enabled: bool) -> Self {
+        Self {
+            rediscover_lost_connections: enabled,
+            ..self
+        }
+    }
+
+    /// Count the requests in flight to each endpoint, see
+    /// [`AutoBalancedChannel::endpoint_load`]. Disabled by default, as it
+    /// takes a lock on every request.
+    pub fn with_load_tracking(self) -> Self {
+        Self {
+            track_load: true,
+            ..self
+        }
+    }
+
+    /// Fail requests through the balanced channel with `DEADLINE_EXCEEDED`
+    /// unless they complete within `deadline`, including any time spent
+    /// waiting for a ready endpoint.
+    ///
+    /// Unlike [`EndpointTemplate::timeout`], which applies to each endpoint's
+    /// connection separately, this is a single budget for the whole request
+    /// regardless of how many endpoints it goes through.
+    pub fn with_request_deadline(self, deadline: Duration) -> Self {
+        Self {
+            request_deadline: Some(deadline),
+            ..self
+        }
+    }
+
+    /// Put a circuit breaker in front of every endpoint: once requests to it
+    /// fail at the connection level `failure_threshold` times in a row, the
+    /// balancers route around it for the `cooldown`, after which a single
+    /// request is let through. Its success closes the circuit again, its
+    /// failure starts another cooldown.
+    ///
+    /// gRPC errors returned by the backend don't count, only failures to
+    /// reach it. Unlike [`Self::rediscover_lost_connections`], the endpoint
+    /// stays registered meanwhile, so it still counts towards health.
+    /// Disabled by default.
+    pub fn with_circuit_breaker(self, config: CircuitBreakerConfig) -> Self {
+        Self {
+            circuit_breaker: Some(config),
+            ..self
+        }
+    }
+
+    /// How many endpoint events [`AutoBalancedChannel::events`] buffers for
+    /// receivers that fall behind, and what those receivers see once they miss
+    /// some. Defaults to 64 events and [`LagPolicy::Error`]. A depth of 0 is
+    /// raised to 1.
+    pub fn with_event_buffer(self, depth: usize, lag_policy: LagPolicy) -> Self {
+        Self {
+            event_buffer: depth,
+            lag_policy,
+            ..self
+        }
+    }
+
+    /// Report the endpoints added and removed by every update to `sink`, e.g.
+    /// to mirror them into a local xDS cache or a sidecar.
+    ///
+    /// Unlike [`AutoBalancedChannel::events`], deliveries are awaited one at a
+    /// time, in order, from a separate task. A delivery that fails is logged
+    /// and retried 3 times with backoff starting at 100ms before being given
+    /// up on. Up to `buffer` deltas wait for the sink meanwhile; once the
+    /// buffer is full, `overflow` decides whether discovery drops the oldest
+    /// delta or waits for room.
+    ///
+    /// The sink task delivers what is left in the buffer after the channel is
+    /// gone and then stops.
+    pub fn with_delta_sink<F, Fut, E>(self, buffer: usize, overflow: SinkOverflow, sink: F) -> Self
+    where
+        F: Fn(EndpointDelta) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: fmt::Display,
+    {
+        let sink: DeltaSink = Arc::new(move |delta| {
+            let delivery = sink(delta);
+            Box::pin(async move { delivery.await.map_err(|error| error.to_string()) })
+        });
+        Self {
+            delta_sink: Some((sink, buffer, overflow)),
+            ..self
+        }
+    }
+
+    /// Name telling this channel apart from others in the same process.
+    /// Everything the channel's background tasks log is in an
+    /// `auto_balanced_channel` span with the name as its `channel` field.
+    /// There are no built-in metrics, but [`AutoBalancedChannel::name`] can
+    /// label those recorded by the caller. Defaults to the template's domain.
+    pub fn channel_name(self, name: impl Into<String>) -> Self {
+        Self {
+            channel_name: Some(name.into()),
+            ..self
+        }
+    }
+
+    /// Number of resolutions to keep in
+    /// [`AutoBalancedChannel::resolution_history`]. Defaults to 16; 0 disables
+    /// the history.
+    pub fn with_resolution_history_size(self, size: usize) -> Self {
+        Self {
+            history_size: size,
+            ..self
+        }
+    }
+
+    fn endpoint_tracking(&self) -> EndpointTracking {
+        EndpointTracking {
+            last_used: self.idle_timeout.map(|_| LastUsed::default()),
+            requests: (self.defer_removals || self.track_load).then(RequestTracker::default),
+            losses: self
+                .rediscover_lost_connections
+                .then(ConnectionLosses::default),
+            weights: (self.balance_policy == BalancePolicy::RoundRobin)
+                .then(|| Arc::new(watch::channel(HashMap::new()).0)),
+        }
+    }
+
+    pub fn build(self) -> AutoBalancedChannel {
+        // Endpoints are keyed by socket address rather than IP so that
+        // services sharing an IP on different ports don't collide. Addresses
+        // from the system resolver always carry port 0 though, so by default
+        // this is equivalent to keying by IP.
+        let (sender, receiver) = mpsc::channel(16.max(self.seed_addrs.len()));
+        let tracking = self.endpoint_tracking();
+        let channel = BalancedChannel::new(
+            receiver,
+            self.balance_policy,
+            &self.endpoint_template,
+            tracking.clone(),
+            self.circuit_breaker.map(CircuitBreakerLayer::new),
+            self.request_deadline,
+            self.rng_seed,
+        );
+        AutoBalancedChannel::spawn(self, channel, sender, tracking)
+    }
+
+    /// Build the channel and wait for the first resolution, failing if it
+    /// does. Whether an empty result is a failure depends on `policy`.
+    ///
+    /// Unlike [`Self::build`], the returned channel is usable right away
+    /// whenever DNS has any endpoints.
+    pub async fn connect(
+        self,
+        policy: EmptyResolutionPolicy,
+    ) -> Result<AutoBalancedChannel, ChannelError> {
+        let retry_policy = RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        };
+        self.connect_with_retry(policy, retry_policy).await
+    }
+
+    /// Like [`Self::connect`], but resolves again after a failed (or, per
+    /// `policy`, empty) first resolution, with exponential backoff, until one
+    /// succeeds or `retry_policy.max_attempts` have been made, e.g. while a
+    /// dependency is still being deployed. Fails with the error of the last
+    /// attempt.
+    ///
+    /// Retries are forced like with [`AutoBalancedChannel::refresh`], so
+    /// they are at least 500ms apart whatever the backoff. Discovery stops on
+    /// errors classified as [`ErrorDisposition::Fatal`], so those aren't
+    /// retried.
+    pub async fn connect_with_retry(
+        self,
+        policy: EmptyResolutionPolicy,
+        retry_policy: RetryPolicy,
+    ) -> Result<AutoBalancedChannel, ChannelError> {
+        let channel = self.build();
+        let mut outcomes = channel.resolution_outcome_reader.clone();
+        // The background task only goes away without resolving once the
+        // balanced channel is gone, which it isn't while we hold it.
+        let mut outcome = match outcomes.wait_for(Option::is_some).await {
+            Ok(outcome) => outcome.clone().expect("waited for an outcome"),
+            Err(_) => Ok(0),
+        };
+        let mut attempt = 1;
+        let mut backoff = retry_policy.initial_backoff;
+        loop {
+            let error = match outcome {
+                Ok(0) if policy == EmptyResolutionPolicy::FailOnEmpty => ChannelError::NoEndpoints,
+                Ok(_) => return Ok(channel),
+                Err(error) => ChannelError::Resolution(error),
+            };
+            if attempt >= retry_policy.max_attempts {
+                return Err(error);
+            }
+            tracing::warn!(%error, attempt, ?backoff, "failed to connect, retrying");
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+            backoff = (backoff * 2).min(retry_policy.max_backoff);
+
+            outcomes.borrow_and_update();
+            channel.refresh();
+            // Gone after a fatal error.
+            if outcomes.changed().await.is_err() {
+                return Err(error);
+            }
+            outcome = outcomes.borrow().clone().expect("resolved before");
+        }
+    }
+
+    /// Resolve the domain once and return a balanced channel over the
+    /// endpoints found, which never changes afterwards, e.g. for a CLI tool or
+    /// a batch job that doesn't live long enough for polling to matter.
+    ///
+    /// The domain is resolved right here rather than by a background task,
+    /// so nothing is left running besides the balanced channel itself, and
+    /// there is nothing to shut down. Settings only relevant to later cycles,
+    /// like the interval or health checks, have no effect. Fails like
+    /// [`Self::connect`] with [`EmptyResolutionPolicy::FailOnEmpty`], since a
+    /// channel without endpoints would never get any.
+    pub async fn resolve_once(self) -> Result<BalancedChannel, ChannelError> {
+        // The balanced channel takes no endpoints until its first request,
+        // so there must be room for all of them up front.
+        let (sender, receiver) = mpsc::channel(Semaphore::MAX_PERMITS);
+        let tracking = self.endpoint_tracking();
+        let channel = BalancedChannel::new(
+            receiver,
+            self.balance_policy,
+            &self.endpoint_template,
+            tracking.clone(),
+            self.circuit_breaker.map(CircuitBreakerLayer::new),
+            self.request_deadline,
+            self.rng_seed,
+        );
+        let (balanced, discovery, _, span) =
+            AutoBalancedChannel::assemble(self, channel, sender, tracking);
+        match discovery.resolve_once().instrument(span).await {
+            Ok(0) => Err(ChannelError::NoEndpoints),
+            Ok(_) => Ok(balanced.channel()),
+            Err(error) => Err(ChannelError::Resolution(error)),
+        }
+    }
+
+    /// Build a channel whose endpoint changes are sent to `sender` instead of
+    /// the balanced channel, so that tests can observe them directly.
+    ///
+    /// The returned [`AutoBalancedChannel::channel`] never gets any
+    /// endpoints.
+    #[cfg(any(test, feature = "mock-dns"))]
+    pub fn build_with_sender(
+        self,
+        sender: mpsc::Sender<Change<SocketAddr, Endpoint>>,
+    ) -> AutoBalancedChannel {
+        let (_, receiver) = mpsc::channel(1);
+        let tracking = self.endpoint_tracking();
+        let channel = BalancedChannel::new(
+            receiver,
+            self.balance_policy,
+            &self.endpoint_template,
+            tracking.clone(),
+            self.circuit_breaker.map(CircuitBreakerLayer::new),
+            self.request_deadline,
+            self.rng_seed,
+        );
+        AutoBalancedChannel::spawn(self, channel, sender, tracking)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        error::Error,
+        io,
+        net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use sequential_test::sequential;
+    use tokio::sync::mpsc;
+    use tower::discover::Change;
+    use url::Url;
+
+    use super::{
+        AutoBalancedChannel, ChannelError, DnsStatus, EmptyResolutionPolicy, EndpointTags,
+        ErrorDisposition, Health, RetryPolicy,
+    };
+    use crate::test_util::{set_dns, set_dns_sockets, socket_addrs, template};
+    use crate::{
+        balance::{EndpointTracking, RequestTracker},
+        dns::mock_net,
+        BalancePolicy, BalancedChannel, EndpointTemplate, EndpointTemplateError, EventsError,
+        HealthEvent, ResolutionError, ResolveFuture, Resolver,
+    };
+
+    #[test]
+    fn public_errors_are_thread_safe() {
+        fn assert_error<E: Error + Send + Sync + 'static>() {}
+        assert_error::<EndpointTemplateError>();
+        assert_error::<ResolutionError>();
+        assert_error::<ChannelError>();
+        assert_error::<EventsError>();
+    }
+
+    #[rstest::rstest]
+    #[case(true, Health::Degraded)]
+    #[case(false, Health::Ok)]
+    #[tokio::test]
+    #[sequential]
+    async fn ipv4_mapped_addresses_are_deduplicated(#[case] unmap: bool, #[case] expected: Health) {
+        set_dns(&["::ffff:127.0.0.1", "127.0.0.1"]);
+
+        // Requiring two endpoints makes the health reveal whether both
+        // representations were registered or only one.
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .unmap_ipv4_mapped(unmap)
+            .with_min_healthy_endpoints(2)
+            .build();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(balanced.get_health(), expected);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn health_is_degraded_below_min_healthy_endpoints() {
+        set_dns(&["127.0.0.1"]);
+
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .with_min_healthy_endpoints(2)
+            .build();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_health(), Health::Degraded);
+
+        set_dns(&["127.0.0.1", "::1"]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_health(), Health::Ok);
+
+        set_dns(&["::1"]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_health(), Health::Degraded);
+
+        set_dns(&[]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_health(), Health::Broken);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn shutdown_removes_all_endpoints() {
+        set_dns(&["127.0.0.1", "::1"]);
+
+        let balanced = AutoBalancedChannel::with_interval(template(), Duration::from_millis(1));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_health(), Health::Ok);
+
+        assert_eq!(balanced.shutdown().await, 2);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn endpoints_sharing_ip_do_not_collide() {
+        set_dns_sockets(vec![
+            SocketAddr::from_str("127.0.0.1:50051").unwrap(),
+            SocketAddr::from_str("127.0.0.1:50052").unwrap(),
+        ]);
+
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .with_min_healthy_endpoints(2)
+            .build();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(balanced.get_health(), Health::Ok);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn undetermined_is_neither_healthy_nor_broken() {
+        set_dns(&["127.0.0.1"]);
+
+        let balanced = AutoBalancedChannel::with_interval(template(), Duration::from_millis(1));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(balanced.is_healthy());
+        assert!(!balanced.is_broken());
+
+        mock_net::set_socket_addrs(Box::new(|_, _| Err(std::io::Error::other("DNS failure"))));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_health(), Health::Undetermined);
+        assert!(!balanced.is_healthy());
+        assert!(!balanced.is_broken());
+
+        set_dns(&[]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!balanced.is_healthy());
+        assert!(balanced.is_broken());
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn health_events_on_undetermined_edges() {
+        set_dns(&["127.0.0.1"]);
+
+        let balanced = AutoBalancedChannel::with_interval(template(), Duration::from_millis(1));
+        let mut events = balanced.health_events();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        mock_net::set_socket_addrs(Box::new(|_, _| Err(io::Error::other("DNS failure"))));
+        let HealthEvent::Undetermined { error } = events.recv().await.unwrap() else {
+            panic!("not an Undetermined event");
+        };
+        assert_eq!(error.source().unwrap().to_string(), "DNS failure");
+
+        set_dns(&["127.0.0.1"]);
+        assert_eq!(events.recv().await.unwrap(), HealthEvent::Recovered);
+
+        // Failing for many cycles, then recovering, is one edge each way.
+        mock_net::set_socket_addrs(Box::new(|_, _| Err(io::Error::other("DNS failure"))));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        set_dns(&["127.0.0.1"]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(matches!(
+            events.recv().await,
+            Ok(HealthEvent::Undetermined { .. })
+        ));
+        assert_eq!(events.recv().await.unwrap(), HealthEvent::Recovered);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), events.recv())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn resolution_error_keeps_io_error_as_source() {
+        mock_net::set_socket_addrs(Box::new(|_, _| {
+            Err(io::Error::new(io::ErrorKind::NotFound, "no such host"))
+        }));
+
+        let balanced = AutoBalancedChannel::with_interval(template(), Duration::from_millis(1));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let DnsStatus::ResolutionError { error } = balanced.get_dns_status() else {
+            panic!("status is not ResolutionError");
+        };
+        let source = error.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn slow_resolution_times_out() {
+        struct SlowResolver;
+
+        impl Resolver for SlowResolver {
+            fn resolve<'a>(&'a self, _domain: &'a str) -> ResolveFuture<'a> {
+                Box::pin(async {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    Ok(socket_addrs(&["10.0.0.1:0"]))
+                })
+            }
+        }
+
+        let balanced = AutoBalancedChannel::builder(template())
+            .with_resolver(SlowResolver)
+            .dns_timeout(Duration::from_secs(1))
+            .build();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let DnsStatus::ResolutionError { error } = balanced.get_dns_status() else {
+            panic!("status is not ResolutionError");
+        };
+        let source = error.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(balanced.get_health(), Health::Broken);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    #[tracing_test::traced_test]
+    async fn colliding_endpoint_uris_are_reported() {
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .with_static_addresses(socket_addrs(&[
+                "10.0.0.1:0",
+                "10.0.0.1:50051",
+                "10.0.0.2:0",
+            ]))
+            .build_with_sender(sender);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(balanced.endpoint_count(), 3);
+        assert!(logs_contain(
+            "endpoints [10.0.0.1:0, 10.0.0.1:50051] all connect to http://10.0.0.1:50051/"
+        ));
+        assert!(!logs_contain("10.0.0.2:0]"));
+    }
+
+    #[tokio::test]
+    #[sequential]
+    #[tracing_test::traced_test]
+    async fn oversized_results_are_refused() {
+        set_dns(&["10.0.0.1", "10.0.0.2"]);
+
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .with_result_sanity_limit(3)
+            .refuse_oversized_results(true)
+            .build();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        set_dns(&["10.0.0.3", "10.0.0.4", "10.0.0.5", "10.0.0.6"]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert!(logs_contain(
+            "resolved 4 addresses, exceeding the sanity limit of 3"
+        ));
+        assert!(matches!(
+            balanced.get_dns_status(),
+            DnsStatus::ResolutionError { .. }
+        ));
+        assert_eq!(balanced.get_health(), Health::Undetermined);
+        assert!(logs_contain("adding endpoint endpoint=10.0.0.2:0"));
+        assert!(!logs_contain("adding endpoint endpoint=10.0.0.3:0"));
+        assert!(!logs_contain("removing endpoint"));
+    }
+
+    #[rstest::rstest]
+    #[case::retained(true, DnsStatus::Empty, Health::Undetermined)]
+    #[case::removed(false, DnsStatus::Ok, Health::Broken)]
+    #[tokio::test]
+    #[sequential]
+    async fn empty_results_retained_if_enabled(
+        #[case] retain: bool,
+        #[case] empty_status: DnsStatus,
+        #[case] empty_health: Health,
+    ) {
+        set_dns(&["10.0.0.1"]);
+
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .retain_on_empty(retain)
+            .build();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        set_dns(&[]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_dns_status(), empty_status);
+        assert_eq!(balanced.get_health(), empty_health);
+        assert_eq!(balanced.endpoint_count(), usize::from(retain));
+
+        set_dns(&["10.0.0.2"]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_dns_status(), DnsStatus::Ok);
+        assert_eq!(balanced.get_health(), Health::Ok);
+        assert_eq!(balanced.snapshot().endpoints, socket_addrs(&["10.0.0.2:0"]));
+    }
+
+    #[tokio::test]
+    #[sequential]
+    #[tracing_test::traced_test]
+    async fn events_are_scoped_by_channel_name() {
+        set_dns(&["10.0.0.1"]);
+
+        let named = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .channel_name("payments")
+            .build();
+        let unnamed = AutoBalancedChannel::with_interval(template(), Duration::from_millis(1));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(named.name(), "payments");
+        assert_eq!(unnamed.name(), "localhost");
+        for name in ["payments", "localhost"] {
+            assert!(logs_contain(&format!(
+                "auto_balanced_channel{{channel={name}}}: tonic_dynamic_channel::discovery: adding endpoint"
+            )));
+        }
+    }
+
+    #[tokio::test]
+    #[sequential]
+    #[tracing_test::traced_test]
+    async fn link_local_addresses_are_skipped() {
+        set_dns_sockets(vec![
+            SocketAddr::from(SocketAddrV6::new("fe80::1".parse().unwrap(), 0, 0, 2)),
+            SocketAddr::from_str("[fe80::2]:0").unwrap(),
+            SocketAddr::from_str("10.0.0.1:0").unwrap(),
+        ]);
+
+        let balanced = AutoBalancedChannel::with_interval(template(), Duration::from_millis(1));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(balanced.snapshot().endpoints, socket_addrs(&["10.0.0.1:0"]));
+        assert!(logs_contain(
+            "skipping link-local address address=[fe80::1%2]:0"
+        ));
+        assert!(logs_contain(
+            "skipping link-local address address=[fe80::2]:0"
+        ));
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn dns_status_is_pending_before_first_resolution() {
+        set_dns(&["10.0.0.1"]);
+
+        // The background task doesn't get to run before the first await.
+        let balanced = AutoBalancedChannel::new(template());
+        assert_eq!(balanced.get_dns_status(), DnsStatus::Pending);
+        assert_eq!(balanced.get_health(), Health::Broken);
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_dns_status(), DnsStatus::Ok);
+        assert_eq!(balanced.get_health(), Health::Ok);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn last_delta_reflects_latest_cycle() {
+        set_dns(&["10.0.0.1", "10.0.0.2"]);
+
+        let balanced = AutoBalancedChannel::with_interval(template(), Duration::from_millis(100));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.last_delta(), (2, 0));
+
+        set_dns(&["10.0.0.2", "10.0.0.3", "10.0.0.4"]);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(balanced.last_delta(), (2, 1));
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn static_addresses_bypass_resolver() {
+        let resolver_calls = Arc::new(AtomicUsize::new(0));
+        let counter = resolver_calls.clone();
+        mock_net::set_socket_addrs(Box::new(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![])
+        }));
+
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .with_min_healthy_endpoints(2)
+            .with_static_addresses(socket_addrs(&["10.0.0.1:0", "10.0.0.2:50052"]))
+            .build();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(balanced.get_health(), Health::Ok);
+        assert_eq!(resolver_calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[rstest::rstest]
+    #[case::dual_stack(false, &["127.0.0.1:50051", "[::1]:50051"])]
+    #[case::ipv6_disabled(true, &["127.0.0.1:50051"])]
+    #[tokio::test]
+    #[sequential]
+    async fn localhost_resolves_to_loopback(#[case] disable_ipv6: bool, #[case] expected: &[&str]) {
+        let resolver_calls = Arc::new(AtomicUsize::new(0));
+        let counter = resolver_calls.clone();
+        mock_net::set_socket_addrs(Box::new(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![])
+        }));
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .disable_ipv6(disable_ipv6)
+            .loopback_localhost(true)
+            .build_with_sender(sender);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let mut uris = Vec::new();
+        while let Ok(change) = receiver.try_recv() {
+            if let Change::Insert(_, endpoint) = change {
+                uris.push(endpoint.uri().authority().unwrap().to_string());
+            }
+        }
+        uris.sort();
+        assert_eq!(uris, expected);
+        assert_eq!(resolver_calls.load(Ordering::SeqCst), 0);
+        assert_eq!(balanced.get_health(), Health::Ok);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn endpoints_added_after_template_update_use_new_template() {
+        set_dns(&["10.0.0.1"]);
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .build_with_sender(sender);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        balanced.update_template(
+            EndpointTemplate::new(Url::parse("http://localhost:50052").unwrap()).unwrap(),
+        );
+        set_dns(&["10.0.0.1", "10.0.0.2"]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let mut uris = Vec::new();
+        while let Ok(change) = receiver.try_recv() {
+            if let Change::Insert(_, endpoint) = change {
+                uris.push(endpoint.uri().to_string());
+            }
+        }
+        assert_eq!(uris, ["http://10.0.0.1:50051/", "http://10.0.0.2:50052/"]);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn ipv6_addresses_are_ignored_when_disabled() {
+        set_dns(&["10.0.0.1", "::1", "::ffff:10.0.0.2", "fd00::2"]);
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let _balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .disable_ipv6(true)
+            .build_with_sender(sender);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let mut inserted = Vec::new();
+        while let Ok(change) = receiver.try_recv() {
+            if let Change::Insert(addr, _) = change {
+                inserted.push(addr);
+            }
+        }
+        assert_eq!(inserted, socket_addrs(&["10.0.0.1:0", "10.0.0.2:0"]));
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn connection_failures_are_counted() {
+        set_dns(&["127.0.0.1"]);
+
+        // Nothing listens on this port, so connections are refused.
+        let balanced = AutoBalancedChannel::builder(
+            EndpointTemplate::new(Url::parse("http://localhost:50059").unwrap()).unwrap(),
+        )
+        .interval(Duration::from_millis(1))
+        .with_connection_probes(Duration::from_millis(5))
+        .build();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let failures = balanced.connection_failures();
+        assert!(failures[&socket_addrs(&["127.0.0.1:0"])[0]] > 0);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn health_is_unreachable_when_no_endpoint_connects() {
+        set_dns(&["127.0.0.1"]);
+
+        // Nothing listens on this port, so connections are refused.
+        let balanced = AutoBalancedChannel::builder(
+            EndpointTemplate::new(Url::parse("http://localhost:50059").unwrap()).unwrap(),
+        )
+        .interval(Duration::from_millis(1))
+        .with_connection_probes(Duration::from_millis(5))
+        .build();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let Health::Unreachable { last_error } = balanced.get_health() else {
+            panic!("health is {:?}", balanced.get_health());
+        };
+        assert!(last_error.contains("refused"), "{last_error}");
+        assert!(!balanced.is_healthy());
+        assert!(!balanced.is_broken());
+    }
+
+    // Link-local addresses can't be routed to without a scope ID.
+    #[rstest::rstest]
+    #[case::routable(Ipv6Addr::LOCALHOST, 2)]
+    #[case::unroutable("fe80::1".parse().unwrap(), 1)]
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn ipv6_is_disabled_without_route_to_target(
+        #[case] target: Ipv6Addr,
+        #[case] expected_count: usize,
+    ) {
+        set_dns(&["10.0.0.1", "::2"]);
+
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .auto_disable_ipv6_with_target(target)
+            .build_with_sender(sender);
+        assert!(balanced.wait_for_cycles(1).await);
+        assert_eq!(balanced.endpoint_count(), expected_count);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn unreachable_health_is_reset_when_endpoints_change() {
+        // Nothing listens on these ports, so connections are refused.
+        set_dns_sockets(socket_addrs(&["127.0.0.1:50058"]));
+
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_secs(1))
+            .with_connection_probes(Duration::from_secs(10))
+            .build_with_sender(sender);
+        // The first probe may run before the first resolution, so wait for
+        // the second one.
+        tokio::time::sleep(Duration::from_millis(10_500)).await;
+        assert!(matches!(balanced.get_health(), Health::Unreachable { .. }));
+
+        // Not probed yet.
+        set_dns_sockets(socket_addrs(&["127.0.0.1:50058", "127.0.0.1:50059"]));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(balanced.get_health(), Health::Ok);
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert!(matches!(balanced.get_health(), Health::Unreachable { .. }));
+    }
+
+    #[rstest::rstest]
+    #[case::over_undetermined(DnsStatus::Empty, 1)]
+    #[case::over_degraded(DnsStatus::Ok, 2)]
+    fn unreachable_takes_precedence(#[case] dns_status: DnsStatus, #[case] min_healthy: usize) {
+        assert_eq!(
+            Health::derive(1, Some("connection refused"), &dns_status, min_healthy),
+            Health::Unreachable {
+                last_error: "connection refused".to_owned()
+            }
+        );
+        assert_ne!(
+            Health::derive(1, None, &dns_status, min_healthy),
+            Health::Ok
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn unreachable_endpoints_are_drained_until_broken() {
+        // Nothing listens on these ports, so connections are refused.
+        set_dns_sockets(socket_addrs(&["127.0.0.1:50058", "127.0.0.1:50059"]));
+
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .with_connection_probes(Duration::from_millis(5))
+            .drain_after_connection_failures(3)
+            .build_with_sender(sender);
+        assert!(balanced.wait_for_cycles(1).await);
+        assert_eq!(balanced.endpoint_count(), 2);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(balanced.endpoint_count(), 0);
+        assert_eq!(balanced.get_health(), Health::Broken);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn resolves_override_domain() {
+        let queried = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = queried.clone();
+        mock_net::set_socket_addrs(Box::new(move |domain, _| {
+            recorder.lock().unwrap().push(domain.to_owned());
+            Ok(socket_addrs(&["10.0.0.1:0"]))
+        }));
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let _balanced = AutoBalancedChannel::builder(
+            EndpointTemplate::new(Url::parse("http://api.example.com:50051").unwrap())
+                .unwrap()
+                .resolve_as("internal.svc.cluster.local"),
+        )
+        .interval(Duration::from_millis(1))
+        .build_with_sender(sender);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let queried = queried.lock().unwrap();
+        assert!(!queried.is_empty());
+        assert!(queried
+            .iter()
+            .all(|domain| domain == "internal.svc.cluster.local"));
+        let Ok(Change::Insert(_, endpoint)) = receiver.try_recv() else {
+            panic!("no endpoint inserted");
+        };
+        assert_eq!(endpoint.uri().to_string(), "http://10.0.0.1:50051/");
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn short_intervals_are_raised_to_min_interval() {
+        let resolver_calls = Arc::new(AtomicUsize::new(0));
+        let counter = resolver_calls.clone();
+        mock_net::set_socket_addrs(Box::new(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![])
+        }));
+
+        let _balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .min_interval(Duration::from_millis(10))
+            .build();
+
+        // Other tasks keep making progress while the loop is running.
+        let mut yields = 0;
+        let started = tokio::time::Instant::now();
+        while started.elapsed() < Duration::from_millis(50) {
+            tokio::task::yield_now().await;
+            yields += 1;
+        }
+
+        let calls = resolver_calls.load(Ordering::SeqCst);
+        assert!((1..=6).contains(&calls), "resolved {calls} times");
+        assert!(yields > calls);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn seed_addresses_are_usable_before_first_resolution() {
+        let resolver_calls = Arc::new(AtomicUsize::new(0));
+        let counter = resolver_calls.clone();
+        mock_net::set_socket_addrs(Box::new(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(socket_addrs(&["10.0.0.2:0"]))
+        }));
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .with_seed_addresses(socket_addrs(&["10.0.0.1:0"]))
+            .build_with_sender(sender);
+
+        assert_eq!(resolver_calls.load(Ordering::SeqCst), 0);
+        assert_eq!(balanced.get_health(), Health::Ok);
+        assert!(
+            matches!(receiver.try_recv(), Ok(Change::Insert(addr, _)) if addr.to_string() == "10.0.0.1:0")
+        );
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(
+            matches!(receiver.try_recv(), Ok(Change::Insert(addr, _)) if addr.to_string() == "10.0.0.2:0")
+        );
+        assert!(
+            matches!(receiver.try_recv(), Ok(Change::Remove(addr)) if addr.to_string() == "10.0.0.1:0")
+        );
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn exposes_domain_and_interval() {
+        set_dns(&[]);
+
+        let balanced = AutoBalancedChannel::with_interval(template(), Duration::from_secs(3));
+        assert_eq!(balanced.domain(), "localhost");
+        assert_eq!(balanced.interval(), Duration::from_secs(3));
+
+        balanced.update_template(
+            EndpointTemplate::new(Url::parse("http://example.com:50051").unwrap()).unwrap(),
+        );
+        assert_eq!(balanced.domain(), "example.com");
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn changes_are_sent_to_injected_sender() {
+        set_dns(&["10.0.0.1", "10.0.0.2"]);
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .build_with_sender(sender);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        set_dns(&["10.0.0.2", "10.0.0.3"]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        balanced.shutdown().await;
+
+        let mut changes = Vec::new();
+        while let Ok(change) = receiver.try_recv() {
+            changes.push(match change {
+                Change::Insert(addr, _) => format!("+{addr}"),
+                Change::Remove(addr) => format!("-{addr}"),
+            });
+        }
+        assert_eq!(
+            changes,
+            [
+                "+10.0.0.1:0",
+                "+10.0.0.2:0",
+                "+10.0.0.3:0",
+                "-10.0.0.1:0",
+                "-10.0.0.2:0",
+                "-10.0.0.3:0",
+            ]
+        );
+    }
+
+    #[rstest::rstest]
+    #[case(EmptyResolutionPolicy::AllowEmpty)]
+    #[case(EmptyResolutionPolicy::FailOnEmpty)]
+    #[tokio::test]
+    #[sequential]
+    async fn connect_waits_for_first_resolution(#[case] policy: EmptyResolutionPolicy) {
+        set_dns(&["127.0.0.1"]);
+
+        let balanced = AutoBalancedChannel::builder(template())
+            .connect(policy)
+            .await
+            .unwrap();
+
+        assert_eq!(balanced.get_dns_status(), DnsStatus::Ok);
+        assert_eq!(balanced.get_health(), Health::Ok);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn connect_allows_empty_first_resolution_by_default() {
+        set_dns(&[]);
+
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .connect(EmptyResolutionPolicy::default())
+            .await
+            .unwrap();
+        assert_eq!(balanced.get_health(), Health::Broken);
+
+        set_dns(&["127.0.0.1"]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_health(), Health::Ok);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn connect_fails_on_empty_first_resolution() {
+        set_dns(&[]);
+
+        let result = AutoBalancedChannel::builder(template())
+            .connect(EmptyResolutionPolicy::FailOnEmpty)
+            .await;
+
+        assert_eq!(result.err(), Some(ChannelError::NoEndpoints));
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn connect_fails_on_first_resolution_error() {
+        mock_net::set_socket_addrs(Box::new(|_, _| Err(io::Error::other("DNS failure"))));
+
+        let result = AutoBalancedChannel::builder(template())
+            .connect(EmptyResolutionPolicy::AllowEmpty)
+            .await;
+
+        let Some(ChannelError::Resolution(error)) = result.err() else {
+            panic!("connect did not fail with a resolution error");
+        };
+        assert_eq!(error.source().unwrap().to_string(), "DNS failure");
+    }
+
+    #[rstest::rstest]
+    #[case::succeeds_on_last_attempt(3, true)]
+    #[case::runs_out_of_attempts(2, false)]
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn connect_retries_first_resolution(#[case] max_attempts: u32, #[case] connects: bool) {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        mock_net::set_socket_addrs(Box::new({
+            let attempts = attempts.clone();
+            move |_, _| match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(io::Error::other("DNS failure")),
+                _ => Ok(vec![SocketAddr::from_str("127.0.0.1:0").unwrap()]),
+            }
+        }));
+
+        let retry_policy = RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        };
+        let result = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_secs(60))
+            .connect_with_retry(EmptyResolutionPolicy::FailOnEmpty, retry_policy)
+            .await;
+
+        assert_eq!(attempts.load(Ordering::SeqCst), max_attempts as usize);
+        match result {
+            Ok(balanced) => {
+                assert!(connects);
+                assert_eq!(balanced.get_health(), Health::Ok);
+            }
+            Err(error) => {
+                assert!(!connects);
+                assert!(matches!(error, ChannelError::Resolution(_)));
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn resolution_history_keeps_latest_cycles_in_order() {
+        set_dns(&["127.0.0.1"]);
+
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(5))
+            .with_resolution_history_size(3)
+            .build_with_sender(sender);
+        assert!(balanced.wait_for_cycles(6).await);
+
+        mock_net::set_socket_addrs(Box::new(|_, _| Err(io::Error::other("DNS failure"))));
+        assert!(balanced.wait_for_cycles(7).await);
+        set_dns(&["127.0.0.1", "::1"]);
+        assert!(balanced.wait_for_cycles(8).await);
+
+        let history = balanced.resolution_history();
+        assert_eq!(history.len(), 3);
+        assert!(history
+            .windows(2)
+            .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        let summary: Vec<_> = history
+            .iter()
+            .map(|record| (record.count, record.error.is_some()))
+            .collect();
+        assert_eq!(summary, [(1, false), (0, true), (2, false)]);
+    }
+
+    #[rstest::rstest]
+    #[case(ErrorDisposition::Retain, Health::Undetermined)]
+    #[case(ErrorDisposition::Drain, Health::Broken)]
+    #[case(ErrorDisposition::Fatal, Health::Stopped)]
+    #[tokio::test]
+    #[sequential]
+    async fn resolution_errors_are_handled_as_classified(
+        #[case] disposition: ErrorDisposition,
+        #[case] expected: Health,
+    ) {
+        set_dns(&["127.0.0.1"]);
+
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .with_error_classifier(move |error| {
+                assert_eq!(error.kind(), io::ErrorKind::NotFound);
+                disposition
+            })
+            .build();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_health(), Health::Ok);
+
+        mock_net::set_socket_addrs(Box::new(|_, _| {
+            Err(io::Error::new(io::ErrorKind::NotFound, "no such host"))
+        }));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_health(), expected);
+
+        // Only a fatal error stops the resolution for good.
+        set_dns(&["127.0.0.1"]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let recovered = if disposition == ErrorDisposition::Fatal {
+            Health::Stopped
+        } else {
+            Health::Ok
+        };
+        assert_eq!(balanced.get_health(), recovered);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn endpoint_count_reflects_resolution() {
+        set_dns(&["127.0.0.1", "::1"]);
+
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .build();
+        assert_eq!(balanced.endpoint_count(), 0);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.endpoint_count(), 2);
+
+        set_dns(&["::1"]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.endpoint_count(), 1);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn wait_for_recovery_completes_once_endpoints_return() {
+        set_dns(&["127.0.0.1"]);
+
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .build();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_health(), Health::Ok);
+
+        // Healthy from the start isn't a recovery.
+        let wait = tokio::time::timeout(Duration::from_millis(10), balanced.wait_for_recovery());
+        assert!(wait.await.is_err());
+
+        set_dns(&[]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_health(), Health::Broken);
+        let wait = tokio::time::timeout(Duration::from_millis(10), balanced.wait_for_recovery());
+        assert!(wait.await.is_err());
+
+        set_dns(&["127.0.0.1"]);
+        tokio::time::timeout(Duration::from_millis(100), balanced.wait_for_recovery())
+            .await
+            .expect("channel did not recover");
+        assert_eq!(balanced.get_health(), Health::Ok);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn receivers_observe_updates() {
+        set_dns(&["127.0.0.1", "::1"]);
+
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .build();
+        let mut dns_status = balanced.dns_status_receiver();
+        let mut endpoint_count = balanced.endpoint_count_receiver();
+
+        let timeout = Duration::from_millis(100);
+        tokio::time::timeout(
+            timeout,
+            dns_status.wait_for(|status| *status == DnsStatus::Ok),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        tokio::time::timeout(timeout, endpoint_count.wait_for(|count| *count == 2))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn accumulation_window_keeps_rotating_records() {
+        let subsets = [
+            socket_addrs(&["10.0.0.1:0", "10.0.0.2:0"]),
+            socket_addrs(&["10.0.0.2:0", "10.0.0.3:0"]),
+            socket_addrs(&["10.0.0.3:0", "10.0.0.1:0"]),
+        ];
+        let resolver_calls = Arc::new(AtomicUsize::new(0));
+        let counter = resolver_calls.clone();
+        mock_net::set_socket_addrs(Box::new(move |_, _| {
+            let call = counter.fetch_add(1, Ordering::SeqCst);
+            Ok(subsets[call % subsets.len()].clone())
+        }));
+
+        let (sender, mut receiver) = mpsc::channel(64);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(5))
+            .with_accumulation_window(2)
+            .build_with_sender(sender);
+        tokio::time::sleep(Duration::from_millis(40)).await;
+
+        assert!(resolver_calls.load(Ordering::SeqCst) > 3);
+        assert_eq!(balanced.endpoint_count(), 3);
+        while let Ok(change) = receiver.try_recv() {
+            assert!(matches!(change, Change::Insert(..)), "endpoint removed");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn accumulated_records_expire_after_ttl() {
+        set_dns(&["10.0.0.1", "10.0.0.2"]);
+
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_secs(1))
+            .with_accumulation_window(10)
+            .with_accumulation_ttl(Duration::from_millis(2500))
+            .build_with_sender(sender);
+        assert!(balanced.wait_for_cycles(1).await);
+
+        set_dns(&["10.0.0.2"]);
+        assert!(balanced.wait_for_cycles(3).await);
+        assert_eq!(balanced.endpoint_count(), 2);
+
+        // Three seconds old, although well within the window.
+        assert!(balanced.wait_for_cycles(4).await);
+        assert_eq!(balanced.endpoint_count(), 1);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn rebuild_all_replaces_endpoints_in_place() {
+        set_dns(&["10.0.0.1", "10.0.0.2"]);
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .build_with_sender(sender);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        while receiver.try_recv().is_ok() {}
+
+        balanced.update_template(
+            EndpointTemplate::new(Url::parse("http://localhost:50052").unwrap()).unwrap(),
+        );
+        balanced.rebuild_all();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let mut uris = Vec::new();
+        while let Ok(change) = receiver.try_recv() {
+            match change {
+                Change::Insert(_, endpoint) => uris.push(endpoint.uri().to_string()),
+                Change::Remove(addr) => panic!("{addr} was removed"),
+            }
+        }
+        assert_eq!(uris, ["http://10.0.0.1:50052/", "http://10.0.0.2:50052/"]);
+        assert_eq!(balanced.endpoint_count(), 2);
+        assert_eq!(balanced.get_health(), Health::Ok);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn rebuild_all_postpones_connection_recycling() {
+        set_dns(&["10.0.0.1"]);
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .with_max_connection_age(Duration::from_secs(40))
+            .build_with_sender(sender);
+        assert!(balanced.wait_for_cycles(1).await);
+        while receiver.try_recv().is_ok() {}
+
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        balanced.rebuild_all();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(matches!(receiver.try_recv(), Ok(Change::Insert(..))));
+
+        // Due within 40s plus up to 10s of stagger of the rebuild, rather
+        // than of the first insertion.
+        tokio::time::sleep(Duration::from_secs(38)).await;
+        assert!(receiver.try_recv().is_err());
+        tokio::time::sleep(Duration::from_secs(12)).await;
+        assert!(matches!(receiver.try_recv(), Ok(Change::Insert(..))));
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn endpoints_are_tagged() {
+        set_dns(&["10.0.0.1", "10.1.0.1"]);
+
+        let tagger_calls = Arc::new(AtomicUsize::new(0));
+        let counter = tagger_calls.clone();
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .with_endpoint_tagger(move |ip| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let IpAddr::V4(ip) = ip else {
+                    return EndpointTags::new();
+                };
+                let zone = format!("zone-{}", ip.octets()[1]);
+                EndpointTags::from([("zone".to_owned(), zone)])
+            })
+            .build_with_sender(sender);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let zone = |ip: &str| {
+            balanced
+                .endpoint_tags(IpAddr::from_str(ip).unwrap())
+                .map(|tags| tags["zone"].clone())
+        };
+        assert_eq!(zone("10.0.0.1").as_deref(), Some("zone-0"));
+        assert_eq!(zone("10.1.0.1").as_deref(), Some("zone-1"));
+        assert_eq!(zone("10.2.0.1"), None);
+
+        set_dns(&["10.2.0.1"]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(zone("10.0.0.1"), None);
+        assert_eq!(zone("10.2.0.1").as_deref(), Some("zone-2"));
+        // Only computed once per added IP, not on every cycle.
+        assert_eq!(tagger_calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn kubernetes_headless_preset() {
+        let builder = AutoBalancedChannel::kubernetes_headless(template());
+        assert_eq!(builder.interval, Duration::from_secs(5));
+        assert_eq!(builder.accumulation_window, 1);
+        assert_eq!(builder.dns_timeout, Some(Duration::from_secs(2)));
+        assert!(!builder.disable_ipv6);
+
+        // Options can still be changed on top of the preset.
+        let builder = AutoBalancedChannel::kubernetes_headless(template()).disable_ipv6(true);
+        assert!(builder.disable_ipv6);
+        assert_eq!(builder.interval, Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn dropping_discovery_handle_stops_discovery() {
+        set_dns(&["10.0.0.1"]);
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let (_channel, handle) = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .build_with_sender(sender)
+            .into_parts();
+        handle
+            .endpoint_count_receiver()
+            .wait_for(|count| *count == 1)
+            .await
+            .unwrap();
+        assert_eq!(handle.get_health(), Health::Ok);
+
+        // The aborted task drops its end of the endpoint changes.
+        drop(handle);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while receiver.recv().await.is_some() {}
+        })
+        .await
+        .expect("background task still running");
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn config_changes_are_picked_up_together() {
+        set_dns(&["10.0.0.1", "::1"]);
+
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_secs(3600))
+            .build_with_sender(sender);
+        let mut endpoint_count = balanced.endpoint_count_receiver();
+        endpoint_count.wait_for(|count| *count == 2).await.unwrap();
+
+        // Neither would make a difference for another hour on its own.
+        balanced.update_config(|config| {
+            config.interval = Duration::from_millis(10);
+            config.disable_ipv6 = true;
+        });
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            endpoint_count.wait_for(|count| *count == 1),
+        )
+        .await
+        .expect("config not picked up")
+        .unwrap();
+        assert_eq!(balanced.interval(), Duration::from_millis(10));
+
+        // The minimum interval still applies.
+        balanced.set_interval(Duration::ZERO);
+        assert_eq!(
+            balanced.interval(),
+            AutoBalancedChannel::DEFAULT_MIN_INTERVAL
+        );
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn endpoints_are_weighted_by_subnet() {
+        set_dns(&["10.0.0.1", "10.1.0.1", "172.16.0.1", "192.168.0.1"]);
+
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .with_subnet_weights([
+                ("10.0.0.0/8".parse().unwrap(), 2),
+                ("10.1.0.0/16".parse().unwrap(), 5),
+                ("172.16.0.0/12".parse().unwrap(), 0),
+            ])
+            .build_with_sender(sender);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let weight = |ip: &str| balanced.endpoint_weight(IpAddr::from_str(ip).unwrap());
+        assert_eq!(weight("10.0.0.1"), Some(2));
+        assert_eq!(weight("10.1.0.1"), Some(5));
+        assert_eq!(weight("172.16.0.1"), Some(1));
+        assert_eq!(weight("192.168.0.1"), Some(1));
+        assert_eq!(weight("10.0.0.2"), None);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn snapshot_is_consistent() {
+        set_dns(&["10.0.0.2", "10.0.0.1"]);
+
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .build_with_sender(sender);
+        let snapshot = balanced.snapshot();
+        assert_eq!(snapshot.health, Health::Broken);
+        assert_eq!(snapshot.dns_status, DnsStatus::Pending);
+        assert_eq!(snapshot.endpoint_count(), 0);
+        assert_eq!(snapshot.last_success, None);
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let snapshot = balanced.snapshot();
+        assert_eq!(snapshot.health, Health::Ok);
+        assert_eq!(snapshot.dns_status, DnsStatus::Ok);
+        assert_eq!(
+            snapshot.endpoints,
+            socket_addrs(&["10.0.0.1:0", "10.0.0.2:0"])
+        );
+        assert!(snapshot.last_success.is_some());
+        assert!(snapshot.last_latency.is_some());
+
+        mock_net::set_socket_addrs(Box::new(|_, _| Err(io::Error::other("DNS failure"))));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let later = balanced.snapshot();
+        assert_eq!(later.health, Health::Undetermined);
+        assert!(later.dns_status.is_error());
+        assert_eq!(later.endpoints, snapshot.endpoints);
+        assert!(later.last_success >= snapshot.last_success);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn has_resolved_after_first_success() {
+        mock_net::set_socket_addrs(Box::new(|_, _| Err(io::Error::other("DNS failure"))));
+
+        let balanced = AutoBalancedChannel::with_interval(template(), Duration::from_millis(1));
+        assert!(!balanced.has_resolved());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!balanced.has_resolved());
+
+        set_dns(&["10.0.0.1"]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(balanced.has_resolved());
+
+        mock_net::set_socket_addrs(Box::new(|_, _| Err(io::Error::other("DNS failure"))));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.get_health(), Health::Undetermined);
+        assert!(balanced.has_resolved());
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    #[sequential]
+    async fn topology_is_exported_as_json() {
+        set_dns(&["10.0.0.2", "10.0.0.1"]);
+
+        let balanced = AutoBalancedChannel::with_interval(template(), Duration::from_millis(1));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let topology: serde_json::Value = serde_json::from_str(&balanced.topology_json()).unwrap();
+        assert_eq!(topology["domain"], "localhost");
+        assert_eq!(topology["health"], "Ok");
+        assert_eq!(topology["dns_status"], "Ok");
+        assert_eq!(
+            topology["endpoints"],
+            serde_json::json!(["10.0.0.1:0", "10.0.0.2:0"])
+        );
+        assert!(topology["last_success"].is_object());
+        assert!(topology["last_latency"].is_object());
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn resolution_cycles_count_every_resolution() {
+        set_dns(&["10.0.0.1"]);
+
+        let balanced = AutoBalancedChannel::with_interval(template(), Duration::from_secs(1));
+        assert_eq!(balanced.resolution_cycles(), 0);
+        assert!(balanced.wait_for_cycles(1).await);
+        assert_eq!(balanced.resolution_cycles(), 1);
+        assert_eq!(balanced.get_health(), Health::Ok);
+
+        // Failed resolutions count too.
+        mock_net::set_socket_addrs(Box::new(|_, _| Err(io::Error::other("DNS failure"))));
+        let started = tokio::time::Instant::now();
+        assert!(balanced.wait_for_cycles(3).await);
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+        assert_eq!(balanced.resolution_cycles(), 3);
+        assert_eq!(balanced.get_health(), Health::Undetermined);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn custom_health_derivation_treats_stale_data_as_broken() {
+        set_dns(&["10.0.0.1"]);
+
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_secs(1))
+            .with_health_derivation(|count, _, staleness| match staleness {
+                Some(staleness) if count > 0 && staleness <= Duration::from_secs(5) => Health::Ok,
+                _ => Health::Broken,
+            })
+            .build_with_sender(sender);
+        assert!(balanced.wait_for_cycles(1).await);
+        assert_eq!(balanced.get_health(), Health::Ok);
+
+        // Still fresh enough after a failed resolution, unlike by default.
+        mock_net::set_socket_addrs(Box::new(|_, _| Err(io::Error::other("DNS failure"))));
+        assert!(balanced.wait_for_cycles(3).await);
+        assert_eq!(balanced.get_health(), Health::Ok);
+
+        assert!(balanced.wait_for_cycles(8).await);
+        assert_eq!(balanced.endpoint_count(), 1);
+        assert_eq!(balanced.get_health(), Health::Broken);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn waits_for_min_endpoints() {
+        // One more endpoint revealed by each resolution.
+        let resolutions = Arc::new(AtomicUsize::new(0));
+        mock_net::set_socket_addrs(Box::new(move |_, _| {
+            let revealed = resolutions.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok((1..=revealed)
+                .map(|i| SocketAddr::new(IpAddr::from([10, 0, 0, i as u8]), 0))
+                .collect())
+        }));
+
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_secs(1))
+            .build_with_sender(sender);
+        let started = tokio::time::Instant::now();
+        assert!(balanced.wait_for_min_endpoints(3).await);
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+        assert_eq!(balanced.endpoint_count(), 3);
+
+        // Already satisfied.
+        tokio::time::timeout(Duration::ZERO, balanced.wait_for_min_endpoints(2))
+            .await
+            .expect("completes right away");
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn waits_end_when_discovery_stops() {
+        set_dns(&["10.0.0.1"]);
+
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_secs(1))
+            .with_error_classifier(|_| ErrorDisposition::Fatal)
+            .build_with_sender(sender);
+        assert!(balanced.wait_for_cycles(1).await);
+
+        mock_net::set_socket_addrs(Box::new(|_, _| Err(io::Error::other("DNS failure"))));
+        assert!(!balanced.wait_for_cycles(5).await);
+        assert!(!balanced.wait_for_min_endpoints(2).await);
+        assert_eq!(balanced.get_health(), Health::Stopped);
+
+        // Already reached before discovery stopped.
+        assert!(balanced.wait_for_cycles(1).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn failed_endpoint_changes_are_counted() {
+        set_dns(&["10.0.0.4", "10.0.0.5"]);
+
+        // Room for a single change, which is never taken.
+        let (sender, receiver) = mpsc::channel(1);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_secs(60))
+            .with_seed_addresses(socket_addrs(&["10.0.0.1:0", "10.0.0.2:0", "10.0.0.3:0"]))
+            .build_with_sender(sender);
+        assert_eq!(balanced.send_failures(), 2);
+
+        // The first resolution waits for room, and fails once the receiver
+        // is gone: two insertions and a removal.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.send_failures(), 2);
+        drop(receiver);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.send_failures(), 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn idle_channel_takes_endpoint_bursts() {
+        let ips: Vec<String> = (1..=40).map(|i| format!("10.0.0.{i}")).collect();
+        set_dns(&ips.iter().map(String::as_str).collect::<Vec<_>>());
+
+        // Many more changes than the buffer holds, with no request to make
+        // the balanced channel poll for them.
+        let balanced = AutoBalancedChannel::with_interval(template(), Duration::from_secs(1));
+        assert!(balanced.wait_for_cycles(1).await);
+        set_dns(&["10.0.0.41"]);
+        assert!(balanced.wait_for_cycles(3).await);
+        assert_eq!(balanced.endpoint_count(), 1);
+        assert_eq!(balanced.send_failures(), 0);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn time_until_next_resolution_counts_down() {
+        set_dns(&["10.0.0.1"]);
+
+        let balanced = AutoBalancedChannel::with_interval(template(), Duration::from_secs(60));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let first = balanced.time_until_next_resolution().unwrap();
+        assert!(first <= Duration::from_secs(60) && first > Duration::from_secs(59));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let second = balanced.time_until_next_resolution().unwrap();
+        assert!(second <= first - Duration::from_millis(50));
+
+        balanced.pause();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(balanced.time_until_next_resolution(), None);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn paused_polling_keeps_endpoints_until_resumed() {
+        set_dns(&["10.0.0.1"]);
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(1))
+            .build_with_sender(sender);
+        balanced
+            .endpoint_count_receiver()
+            .wait_for(|count| *count == 1)
+            .await
+            .unwrap();
+        while receiver.try_recv().is_ok() {}
+
+        balanced.pause();
+        set_dns(&["10.0.0.2", "10.0.0.3"]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(balanced.endpoint_count(), 1);
+        assert_eq!(balanced.get_health(), Health::Ok);
+
+        balanced.resume();
+        balanced
+            .endpoint_count_receiver()
+            .wait_for(|count| *count == 2)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn waits_for_specific_endpoint() {
+        set_dns(&["10.0.0.1"]);
+
+        let (sender, _receiver) = mpsc::channel(16);
+        let balanced = Arc::new(
+            AutoBalancedChannel::builder(template())
+                .interval(Duration::from_millis(5))
+                .build_with_sender(sender),
+        );
+        let canary = IpAddr::from_str("10.0.0.2").unwrap();
+        let added = tokio::spawn({
+            let balanced = balanced.clone();
+            async move { balanced.wait_for_endpoint(canary).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!added.is_finished());
+
+        set_dns(&["10.0.0.1", "10.0.0.2"]);
+        tokio::time::timeout(Duration::from_millis(100), added)
+            .await
+            .expect("canary added")
+            .unwrap();
+
+        set_dns(&["10.0.0.1"]);
+        tokio::time::timeout(
+            Duration::from_millis(100),
+            balanced.wait_for_endpoint_removed(canary),
+        )
+        .await
+        .expect("canary removed");
+        // Already absent.
+        balanced.wait_for_endpoint_removed(canary).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn resolve_once_never_polls_again() {
+        let resolver_calls = Arc::new(AtomicUsize::new(0));
+        let counter = resolver_calls.clone();
+        mock_net::set_socket_addrs(Box::new(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(socket_addrs(&["10.0.0.1:0"]))
+        }));
+
+        let _channel = AutoBalancedChannel::resolve_once(template()).await.unwrap();
+        assert_eq!(resolver_calls.load(Ordering::SeqCst), 1);
+
+        // Many default intervals later.
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert_eq!(resolver_calls.load(Ordering::SeqCst), 1);
+
+        set_dns(&[]);
+        let result = AutoBalancedChannel::resolve_once(template()).await;
+        assert_eq!(result.err(), Some(ChannelError::NoEndpoints));
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn resolve_once_takes_more_endpoints_than_the_change_buffer() {
+        mock_net::set_socket_addrs(Box::new(|_, _| {
+            Ok((1..=40)
+                .map(|i| SocketAddr::new(IpAddr::from([10, 0, 0, i]), 0))
+                .collect())
+        }));
+
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            AutoBalancedChannel::resolve_once(template()),
+        )
+        .await
+        .expect("resolved without any request draining the changes")
+        .unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn rapid_refreshes_are_coalesced() {
+        let resolver_calls = Arc::new(AtomicUsize::new(0));
+        let counter = resolver_calls.clone();
+        mock_net::set_socket_addrs(Box::new(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(socket_addrs(&["10.0.0.1:0"]))
+        }));
+
+        let balanced = AutoBalancedChannel::new(template());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(resolver_calls.load(Ordering::SeqCst), 1);
+
+        for _ in 0..10 {
+            balanced.refresh();
+            tokio::task::yield_now().await;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let extra = resolver_calls.load(Ordering::SeqCst) - 1;
+        assert!((1..=2).contains(&extra), "{extra} extra resolutions");
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn resolves_every_interval_with_paused_time() {
+        let resolver_calls = Arc::new(AtomicUsize::new(0));
+        let counter = resolver_calls.clone();
+        mock_net::set_socket_addrs(Box::new(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(socket_addrs(&["10.0.0.1:0"]))
+        }));
+
+        let balanced = AutoBalancedChannel::new(template());
+        // Once right away and then every 15 seconds.
+        tokio::time::sleep(Duration::from_secs(155)).await;
+
+        assert_eq!(resolver_calls.load(Ordering::SeqCst), 11);
+        assert_eq!(balanced.get_health(), Health::Ok);
+    }
+
+    #[rstest::rstest]
+    #[case::eager(false)]
+    #[case::deferred(true)]
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn removal_waits_for_requests_in_flight_if_deferred(#[case] defer: bool) {
+        set_dns(&["10.0.0.1"]);
+        let addr = socket_addrs(&["10.0.0.1:0"])[0];
+
+        // Like `build_with_sender`, but keeping hold of the in-flight
+        // requests the balanced channel would track.
+        let builder = AutoBalancedChannel::builder(template())
+            .interval(Duration::from_millis(10))
+            .defer_removals(defer);
+        let requests = defer.then(RequestTracker::default);
+        let tracking = EndpointTracking {
+            requests: requests.clone(),
+            ..EndpointTracking::default()
+        };
+        let channel = BalancedChannel::new(
+            mpsc::channel(1).1,
+            BalancePolicy::default(),
+            &template(),
+            tracking.clone(),
+            None,
+            None,
+            None,
+        );
+        let (sender, mut receiver) = mpsc::channel(16);
+        let balanced = AutoBalancedChannel::spawn(builder, channel, sender, tracking);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(matches!(receiver.try_recv(), Ok(Change::Insert(..))));
+
+        let in_flight = requests.as_ref().map(|requests| requests.start(addr));
+        set_dns(&[]);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(balanced.endpoint_count(), 0);
+        if defer {
+            assert!(receiver.try_recv().is_err());
+        } else {
+            assert!(matches!(receiver.try_recv(), Ok(Change::Remove(removed)) if removed == addr));
+        }
+
+        // Removed within one check of the request completing.
+        drop(in_flight);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if defer {
+            assert!(matches!(receiver.try_recv(), Ok(Change::Remove(removed)) if removed == addr));
+        }
+        assert!(receiver.try_recv().is_err());
     }
 }
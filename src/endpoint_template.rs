@@ -1,19 +1,33 @@
-use http::HeaderValue;
-use std::{net::IpAddr, str::FromStr, time::Duration};
-use tonic::transport::{Endpoint, Uri};
+use crate::proxy::{self, Connector, ProxyConfig};
+
+use http::{header::HeaderName, HeaderMap, HeaderValue};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+use tonic::transport::{Channel, Endpoint, Uri};
+#[cfg(feature = "tls")]
+use tonic::transport::{ClientTlsConfig, Identity};
 use url::{Host, Url};
 
+/// Settings shared by the endpoints built for each IP address a domain
+/// resolves to, mirroring those of tonic's `Endpoint`.
+///
+/// The HTTP/2 max frame size can't be set: tonic 0.11's `Endpoint` has no
+/// setter for it, so connections use hyper's default of 16 KiB.
 #[derive(Debug)]
 pub struct EndpointTemplate {
     url: Url,
+    resolution_domain: Option<String>,
     origin: Option<Uri>,
     user_agent: Option<HeaderValue>,
     concurrency_limit: Option<usize>,
     rate_limit: Option<(u64, Duration)>,
     timeout: Option<Duration>,
-    // Can't check this setter before calling build().
-    // Rarely used so let's ignore it for now.
-    // tls_config: Option<ClientTlsConfig>,
+    #[cfg(feature = "tls")]
+    tls_config: Option<ClientTlsConfig>,
     buffer_size: Option<usize>,
     init_stream_window_size: Option<u32>,
     init_connection_window_size: Option<u32>,
@@ -23,10 +37,29 @@ pub struct EndpointTemplate {
     http2_keep_alive_timeout: Option<Duration>,
     http2_keep_alive_while_idle: Option<bool>,
     connect_timeout: Option<Duration>,
+    connect_timeout_fn: Option<ConnectTimeoutFn>,
     http2_adaptive_window: Option<bool>,
+    default_metadata: HeaderMap,
+    configure_endpoint: Option<ConfigureEndpoint>,
+    proxy: Option<ProxyConfig>,
+    prototype: Option<Endpoint>,
+    #[cfg(feature = "grpc-web")]
+    grpc_web: bool,
 }
 
 impl EndpointTemplate {
+    /// User agent of endpoints unless set with [`Self::user_agent`], so that
+    /// backends can tell which clients discover them with this crate.
+    pub const DEFAULT_USER_AGENT: &'static str =
+        concat!("tonic-dynamic-channel/", env!("CARGO_PKG_VERSION"));
+
+    /// Creates a template from a URL with a domain that will be resolved to
+    /// IP addresses.
+    ///
+    /// The URL path is kept in the built endpoints' URIs, but tonic only takes
+    /// the scheme and authority from them and uses the gRPC method path for
+    /// requests, so a non-root path has no effect. A warning is logged in that
+    /// case; use [`Self::with_path`] to clear it.
     pub fn new(url: impl Into<Url>) -> Result<Self, Error> {
         let url: Url = url.into();
 
@@ -55,11 +88,16 @@ impl EndpointTemplate {
             return Err(Error::Inconvertible);
         }
 
+        warn_if_path_ignored(&url);
+
         Ok(Self {
             url,
+            resolution_domain: None,
             origin: None,
             user_agent: None,
             timeout: None,
+            #[cfg(feature = "tls")]
+            tls_config: None,
             concurrency_limit: None,
             rate_limit: None,
             buffer_size: None,
@@ -71,10 +109,74 @@ impl EndpointTemplate {
             http2_keep_alive_timeout: None,
             http2_keep_alive_while_idle: None,
             connect_timeout: None,
+            connect_timeout_fn: None,
             http2_adaptive_window: None,
+            default_metadata: HeaderMap::new(),
+            configure_endpoint: None,
+            proxy: None,
+            prototype: None,
+            #[cfg(feature = "grpc-web")]
+            grpc_web: false,
+        })
+    }
+
+    /// Creates a template copying `prototype` for every endpoint, keeping all
+    /// its settings, including those the template doesn't mirror. Its URI
+    /// must have a domain for a host, which is resolved as with [`Self::new`].
+    ///
+    /// tonic can't change an endpoint's URI once built, so copies keep the
+    /// prototype's: the domain stays the `:authority` of requests and the
+    /// server name verified over TLS. Instead, wherever this crate connects
+    /// endpoints (the balanced channel, connection probes, health checks and
+    /// [`Self::connect_single`]), it dials the resolved IP address and port
+    /// itself, defaulting to the URI's port when the resolver returns none.
+    /// Endpoints handed out otherwise, e.g. by [`Self::build`] or to
+    /// [`AutoBalancedChannel::into_parts`](crate::AutoBalancedChannel::into_parts)
+    /// callers, would connect to whichever address the system resolves.
+    ///
+    /// As tonic doesn't pass its TCP settings to custom connections, the
+    /// prototype's TCP keepalive is dropped, and TCP nodelay is set with
+    /// [`Self::tcp_nodelay`] rather than on the prototype. Everything else,
+    /// TLS and timeouts included, applies as configured.
+    ///
+    /// The template's own options are applied on top of copies, see
+    /// [`Self::build`], but only those explicitly set.
+    pub fn from_endpoint(prototype: Endpoint) -> Result<Self, Error> {
+        let url = Url::parse(&prototype.uri().to_string()).map_err(Error::InvalidUrl)?;
+        Ok(Self {
+            prototype: Some(prototype),
+            ..Self::new(url)?
         })
     }
 
+    /// Like [`Self::new`], but parses `url` first, failing with
+    /// [`Error::InvalidUrl`] if it isn't a valid URL.
+    pub fn parse(url: &str) -> Result<Self, Error> {
+        Self::new(Url::parse(url).map_err(Error::InvalidUrl)?)
+    }
+
+    pub fn path(&self) -> &str {
+        self.url.path()
+    }
+
+    /// Replaces the path of the template URL. See [`Self::new`] for why a
+    /// non-root path is not used by gRPC requests.
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.url.set_path(path);
+        warn_if_path_ignored(&self.url);
+        self
+    }
+
+    /// Resolves `domain` instead of the URL's host, e.g. for split-horizon DNS
+    /// where the name resolvable from inside a cluster differs from the public
+    /// one. Everything else, including [`Self::origin`], is unaffected.
+    pub fn resolve_as(self, domain: impl Into<String>) -> Self {
+        Self {
+            resolution_domain: Some(domain.into()),
+            ..self
+        }
+    }
+
     pub fn origin(self, origin: Uri) -> Self {
         Self {
             origin: Some(origin),
@@ -82,6 +184,8 @@ impl EndpointTemplate {
         }
     }
 
+    /// Replaces [`Self::DEFAULT_USER_AGENT`]. Either way, tonic appends its
+    /// own, e.g. `my-agent tonic/0.11.0`.
     pub fn user_agent(self, user_agent: impl TryInto<HeaderValue>) -> Self {
         Self {
             user_agent: Some(
@@ -94,6 +198,18 @@ impl EndpointTemplate {
         }
     }
 
+    /// Adds a metadata entry sent with every request made through the
+    /// balanced channel, unless the request already sets `key` itself. Can be
+    /// called multiple times, also with the same key.
+    ///
+    /// Fails if `key` or `value` is not a valid HTTP header name or value.
+    pub fn default_metadata(mut self, key: &str, value: &str) -> Result<Self, Error> {
+        let key = HeaderName::try_from(key).map_err(|_| Error::InvalidMetadataKey)?;
+        let value = HeaderValue::try_from(value).map_err(|_| Error::InvalidMetadataValue)?;
+        self.default_metadata.append(key, value);
+        Ok(self)
+    }
+
     pub fn timeout(self, dur: Duration) -> Self {
         Self {
             timeout: Some(dur),
@@ -108,6 +224,20 @@ impl EndpointTemplate {
         }
     }
 
+    /// Decide the connect timeout of each endpoint from its IP address, e.g.
+    /// to give far away backends of a mixed fleet more time. Overrides
+    /// [`Self::connect_timeout`]. `connect_timeout` is called whenever an
+    /// endpoint is built, so it should be cheap.
+    pub fn connect_timeout_fn(
+        self,
+        connect_timeout: impl Fn(IpAddr) -> Duration + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            connect_timeout_fn: Some(ConnectTimeoutFn(Box::new(connect_timeout))),
+            ..self
+        }
+    }
+
     pub fn tcp_keepalive(self, tcp_keepalive: Option<Duration>) -> Self {
         Self {
             tcp_keepalive,
@@ -115,6 +245,35 @@ impl EndpointTemplate {
         }
     }
 
+    /// Connect to endpoints over TLS configured with `tls_config`, failing
+    /// with [`Error::InvalidTlsConfig`] if its certificates or identity can't
+    /// be loaded.
+    ///
+    /// Since endpoint URIs have IP addresses for hosts, the server name
+    /// verified is always the template's domain, whatever `tls_config` says.
+    #[cfg(feature = "tls")]
+    pub fn tls_config(self, tls_config: ClientTlsConfig) -> Result<Self, Error> {
+        // tonic only loads the certificates when applying the configuration
+        // to an endpoint, so do it once here rather than fail on every build.
+        let domain = self.url.domain().unwrap_or_default().to_owned();
+        Endpoint::from_static("https://localhost")
+            .tls_config(tls_config.clone().domain_name(domain))
+            .map_err(|_| Error::InvalidTlsConfig)?;
+        Ok(Self {
+            tls_config: Some(tls_config),
+            ..self
+        })
+    }
+
+    /// Present `identity` (a client certificate and key) to servers requiring
+    /// mutual TLS, on top of the TLS configuration set so far, if any. See
+    /// [`Self::tls_config`].
+    #[cfg(feature = "tls")]
+    pub fn client_identity(self, identity: Identity) -> Result<Self, Error> {
+        let tls_config = self.tls_config.clone().unwrap_or_default();
+        self.tls_config(tls_config.identity(identity))
+    }
+
     pub fn concurrency_limit(self, limit: usize) -> Self {
         Self {
             concurrency_limit: Some(limit),
@@ -178,6 +337,27 @@ impl EndpointTemplate {
         }
     }
 
+    /// Sets up HTTP/2 keepalive in one go: a ping is sent every `interval`
+    /// (also when idle if `while_idle` is set) and the connection is closed if
+    /// it isn't acknowledged within `timeout`.
+    ///
+    /// Fails if `timeout` is not shorter than `interval`, as a ping would then
+    /// be sent before the previous one timed out.
+    pub fn http2_keepalive(
+        self,
+        interval: Duration,
+        timeout: Duration,
+        while_idle: bool,
+    ) -> Result<Self, Error> {
+        if timeout >= interval {
+            return Err(Error::InvalidKeepalive);
+        }
+        Ok(self
+            .http2_keep_alive_interval(interval)
+            .keep_alive_timeout(timeout)
+            .keep_alive_while_idle(while_idle))
+    }
+
     pub fn http2_adaptive_window(self, enabled: bool) -> Self {
         Self {
             http2_adaptive_window: Some(enabled),
@@ -185,14 +365,192 @@ impl EndpointTemplate {
         }
     }
 
+    /// Applies `configure` to every endpoint built, for tonic settings the
+    /// template doesn't mirror, e.g. when reusing code configuring an
+    /// [`Endpoint`] directly:
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use tonic_dynamic_channel::EndpointTemplate;
+    /// let template = EndpointTemplate::parse("http://example.com:50051")
+    ///     .unwrap()
+    ///     .configure_endpoint(|endpoint| endpoint.timeout(Duration::from_secs(1)));
+    /// ```
+    ///
+    /// Each endpoint is built from the template URL with the resolved IP
+    /// address substituted for the host (and the port, if resolved too) and
+    /// then passed through `configure`, after all the template's own options.
+    /// Its settings therefore take precedence, but it must not replace the
+    /// endpoint, or the IP address would be lost. To reuse a prebuilt
+    /// endpoint as is, see [`Self::from_endpoint`] instead.
+    pub fn configure_endpoint(
+        self,
+        configure: impl Fn(Endpoint) -> Endpoint + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            configure_endpoint: Some(ConfigureEndpoint(Box::new(configure))),
+            ..self
+        }
+    }
+
+    /// Connect to endpoints through `proxy`, e.g. in networks where outbound
+    /// traffic must go through one. The tunnel is opened to the resolved IP
+    /// address, so the proxy never sees the domain and each endpoint still
+    /// gets its own connection to balance over.
+    ///
+    /// The domain is thus resolved locally, which requires the local resolver
+    /// to return the backends' addresses, reachable from the proxy. Letting
+    /// the proxy resolve the domain instead (`socks5h`, or `CONNECT` to the
+    /// domain) would leave a single tunnel to whichever backend the proxy
+    /// picks, defeating balancing; where local DNS can't see the backends,
+    /// plug in a [`Resolver`](crate::Resolver) that can.
+    ///
+    /// tonic's `Endpoint` has no way to carry a custom connector, so endpoints
+    /// built by [`Self::build`] connect directly. The proxy is used wherever
+    /// this crate connects them: by the balanced channel, connection probes,
+    /// health checks and [`Self::connect_single`]. TLS (with the `tls`
+    /// feature) and the connect timeout apply on top of the tunnel, and TCP
+    /// nodelay to the connection to the proxy, but not TCP keepalive.
+    pub fn proxy(self, proxy: ProxyConfig) -> Self {
+        Self {
+            proxy: Some(proxy),
+            ..self
+        }
+    }
+
+    /// Speak gRPC-Web to endpoints instead of gRPC, for backends only serving
+    /// gRPC-Web, e.g. behind tonic-web's `GrpcWebLayer` or Envoy's `grpc_web`
+    /// filter.
+    ///
+    /// Requests sent through the balanced channel are framed as gRPC-Web, and
+    /// the trailers of responses decoded from their bodies. Tonic's `Channel`
+    /// only speaks HTTP/2, so the backend, or the proxy in front of it, must
+    /// accept gRPC-Web over HTTP/2, which is h2c for plain `http` URLs.
+    /// Endpoints serving gRPC-Web over HTTP/1.1 only won't work. Health checks
+    /// and connection probes are unaffected.
+    #[cfg(feature = "grpc-web")]
+    pub fn grpc_web(self) -> Self {
+        Self {
+            grpc_web: true,
+            ..self
+        }
+    }
+
+    #[cfg(feature = "grpc-web")]
+    pub(crate) fn is_grpc_web(&self) -> bool {
+        self.grpc_web
+    }
+
+    /// Connector to pass endpoints to, if connecting through a proxy or
+    /// copying a prototype.
+    pub(crate) fn connector(&self) -> Option<Connector> {
+        (self.proxy.is_some() || self.prototype.is_some())
+            .then(|| Connector::new(self.proxy.clone(), self.tcp_nodelay.unwrap_or(true)))
+    }
+
+    /// Builds an endpoint connecting to `ip_address`.
+    ///
+    /// The template URL with `ip_address` substituted for the host becomes the
+    /// endpoint's URI, unless the template was created with
+    /// [`Self::from_endpoint`], in which case the endpoint starts as a copy of
+    /// the prototype, URI included. The other options are then applied in declaration
+    /// order: origin, user agent, timeout, connect timeout (computed for
+    /// `ip_address` if [`Self::connect_timeout_fn`] is set), TCP keepalive,
+    /// concurrency limit, rate limit, initial stream and connection window
+    /// sizes, buffer size, TCP nodelay, HTTP/2 keepalive interval, timeout and
+    /// while idle, and HTTP/2 adaptive window. Each of tonic's setters only
+    /// sets its own option, so the order doesn't affect the result. Options
+    /// left unset keep tonic's defaults (or the prototype's settings), except
+    /// for the user agent, which defaults to [`Self::DEFAULT_USER_AGENT`]
+    /// without a prototype.
+    ///
+    /// With the `tls` feature, the TLS configuration is applied next, with the
+    /// template's domain as the server name. Finally, the endpoint is passed
+    /// through [`Self::configure_endpoint`], if set.
+    ///
+    /// # Panics
+    ///
+    /// If the IP address can't be substituted into the template URL, which
+    /// [`Self::new`] is meant to rule out. See [`Self::try_build`].
     pub fn build(&self, ip_address: impl Into<IpAddr>) -> Endpoint {
-        let mut endpoint = Endpoint::from(self.build_uri(ip_address.into()));
+        self.try_build(ip_address).expect("valid endpoint template")
+    }
+
+    /// Builds an endpoint like [`Self::build`] and connects to it, for when
+    /// there is a single known backend, e.g. in a health-checking sidecar. No
+    /// resolution nor balancing is involved.
+    pub async fn connect_single(
+        &self,
+        ip_address: impl Into<IpAddr>,
+    ) -> Result<Channel, ConnectError> {
+        let ip_address = ip_address.into();
+        let endpoint = self.try_build(ip_address).map_err(ConnectError::Build)?;
+        let addr = SocketAddr::new(ip_address, 0);
+        proxy::connect(&endpoint, self.connector().as_ref(), addr)
+            .await
+            .map_err(ConnectError::Transport)
+    }
+
+    /// Same as [`Self::build`], but also replaces the port from the template
+    /// URL. Useful when several services share an IP address.
+    pub fn build_with_port(&self, ip_address: impl Into<IpAddr>, port: u16) -> Endpoint {
+        self.try_build_with_port(ip_address, port)
+            .expect("valid endpoint template")
+    }
+
+    /// Fallible version of [`Self::build`].
+    pub fn try_build(&self, ip_address: impl Into<IpAddr>) -> Result<Endpoint, Error> {
+        self.build_endpoint(ip_address.into(), None)
+    }
+
+    /// Fallible version of [`Self::build_with_port`].
+    pub fn try_build_with_port(
+        &self,
+        ip_address: impl Into<IpAddr>,
+        port: u16,
+    ) -> Result<Endpoint, Error> {
+        self.build_endpoint(ip_address.into(), Some(port))
+    }
+
+    /// Builds an endpoint for a resolved socket address. Port 0 (which is what
+    /// the system resolver returns) keeps the port from the template URL.
+    pub(crate) fn build_for_socket_addr(&self, socket_addr: SocketAddr) -> Result<Endpoint, Error> {
+        match socket_addr.port() {
+            0 => self.try_build(socket_addr.ip()),
+            port => self.try_build_with_port(socket_addr.ip(), port),
+        }
+    }
+
+    /// URI of the endpoint [`Self::build_for_socket_addr`] would build,
+    /// without building it.
+    pub(crate) fn uri_for_socket_addr(&self, socket_addr: SocketAddr) -> Result<Uri, Error> {
+        let port = Some(socket_addr.port()).filter(|port| *port != 0);
+        self.build_uri(socket_addr.ip(), port)
+    }
+
+    fn build_endpoint(&self, ip_address: IpAddr, port: Option<u16>) -> Result<Endpoint, Error> {
+        // tonic's Endpoint can't be retargeted to another URI, so a prototype
+        // is copied as is and connected to the IP address by the connector.
+        // Otherwise, building from scratch is negligible next to connecting,
+        // see the `build_endpoints` benchmark.
+        let uri = self.build_uri(ip_address, port)?;
+        tracing::debug!(ip = %ip_address, %uri, "building endpoint");
+        let mut endpoint = match &self.prototype {
+            Some(prototype) => prototype.clone(),
+            None => Endpoint::from(uri),
+        };
 
         if let Some(origin) = self.origin.clone() {
             endpoint = endpoint.origin(origin);
         }
 
-        if let Some(user_agent) = self.user_agent.clone() {
+        let user_agent = match (&self.user_agent, &self.prototype) {
+            (Some(user_agent), _) => Some(user_agent.clone()),
+            // Keep the prototype's.
+            (None, Some(_)) => None,
+            (None, None) => Some(HeaderValue::from_static(Self::DEFAULT_USER_AGENT)),
+        };
+        if let Some(user_agent) = user_agent {
             // user_agent is already of the correct type so this will never
             // return an error.
             endpoint = endpoint.user_agent(user_agent).unwrap();
@@ -202,11 +560,15 @@ impl EndpointTemplate {
             endpoint = endpoint.timeout(timeout)
         }
 
-        if let Some(connect_timeout) = self.connect_timeout {
+        if let Some(ConnectTimeoutFn(connect_timeout)) = &self.connect_timeout_fn {
+            endpoint = endpoint.connect_timeout(connect_timeout(ip_address))
+        } else if let Some(connect_timeout) = self.connect_timeout {
             endpoint = endpoint.connect_timeout(connect_timeout)
         }
 
-        endpoint = endpoint.tcp_keepalive(self.tcp_keepalive);
+        if let Some(keepalive) = self.tcp_keepalive {
+            endpoint = endpoint.tcp_keepalive(Some(keepalive));
+        }
 
         if let Some(limit) = self.concurrency_limit {
             endpoint = endpoint.concurrency_limit(limit)
@@ -224,7 +586,9 @@ impl EndpointTemplate {
             endpoint = endpoint.initial_connection_window_size(sz);
         }
 
-        endpoint = endpoint.buffer_size(self.buffer_size);
+        if let Some(size) = self.buffer_size {
+            endpoint = endpoint.buffer_size(size);
+        }
 
         if let Some(tcp_nodelay) = self.tcp_nodelay {
             endpoint = endpoint.tcp_nodelay(tcp_nodelay);
@@ -246,21 +610,182 @@ impl EndpointTemplate {
             endpoint = endpoint.http2_adaptive_window(enabled);
         }
 
-        endpoint
+        #[cfg(feature = "tls")]
+        if let Some(tls_config) = self.tls_config.clone() {
+            endpoint = endpoint
+                .tls_config(tls_config.domain_name(self.url.domain().unwrap_or_default()))
+                .map_err(|_| Error::InvalidTlsConfig)?;
+        }
+
+        if let Some(ConfigureEndpoint(configure)) = &self.configure_endpoint {
+            endpoint = configure(endpoint);
+        }
+
+        Ok(endpoint)
+    }
+
+    pub(crate) fn metadata(&self) -> &HeaderMap {
+        &self.default_metadata
     }
 
+    /// Domain to resolve to find the endpoints.
     pub(crate) fn domain(&self) -> &str {
-        // Unwrap is safe as we are making sure Url contains a domain in the
-        // constructor.
-        &self.url.domain().unwrap()
+        match &self.resolution_domain {
+            Some(domain) => domain,
+            // Unwrap is safe as we are making sure Url contains a domain in
+            // the constructor.
+            None => self.url.domain().unwrap(),
+        }
+    }
+
+    /// Origin of endpoints reached through `domain` rather than the
+    /// template's domain, e.g. its canonical name.
+    #[cfg(feature = "async-resolver")]
+    pub(crate) fn origin_for_domain(&self, domain: &str) -> Result<Uri, Error> {
+        let mut url = self.url.clone();
+        url.set_host(Some(domain))
+            .map_err(|_| Error::Inconvertible)?;
+        url.set_path("");
+        Uri::from_str(url.as_str()).map_err(|_| Error::Inconvertible)
     }
 
-    fn build_uri(&self, ip_addr: IpAddr) -> Uri {
-        // We make sure this conversion doesn't return any errors in Self::new
-        // already so it's safe to unwrap here.
+    fn build_uri(&self, ip_addr: IpAddr, port: Option<u16>) -> Result<Uri, Error> {
+        // Self::new makes sure none of these fail, but this runs in the
+        // background task, where a panic would silently stop discovery.
         let mut url = self.url.clone();
-        url.set_ip_host(ip_addr).unwrap();
-        Uri::from_str(url.as_str()).unwrap()
+        url.set_ip_host(ip_addr).map_err(|_| Error::Inconvertible)?;
+        if port.is_some() {
+            url.set_port(port).map_err(|_| Error::Inconvertible)?;
+        }
+        Uri::from_str(url.as_str()).map_err(|_| Error::Inconvertible)
+    }
+}
+
+/// Summarizes the effective configuration, e.g. for logging it at startup:
+/// `http://example.com:50051 (timeout=1s, user_agent="my-agent")`. Options
+/// left unset are left out. Of the default metadata, only the keys are shown,
+/// as the values may be credentials.
+impl fmt::Display for EndpointTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}://{}",
+            self.url.scheme(),
+            self.url.host_str().unwrap_or_default()
+        )?;
+        if let Some(port) = self.url.port_or_known_default() {
+            write!(f, ":{port}")?;
+        }
+
+        let mut options = Vec::new();
+        if let Some(domain) = &self.resolution_domain {
+            options.push(format!("resolve_as={domain}"));
+        }
+        if let Some(origin) = &self.origin {
+            options.push(format!("origin={origin}"));
+        }
+        if let Some(user_agent) = &self.user_agent {
+            options.push(format!("user_agent={user_agent:?}"));
+        }
+        if let Some(timeout) = self.timeout {
+            options.push(format!("timeout={timeout:?}"));
+        }
+        if self.connect_timeout_fn.is_some() {
+            options.push("connect_timeout=per IP".to_owned());
+        } else if let Some(timeout) = self.connect_timeout {
+            options.push(format!("connect_timeout={timeout:?}"));
+        }
+        if let Some(keepalive) = self.tcp_keepalive {
+            options.push(format!("tcp_keepalive={keepalive:?}"));
+        }
+        if let Some(limit) = self.concurrency_limit {
+            options.push(format!("concurrency_limit={limit}"));
+        }
+        if let Some((limit, duration)) = self.rate_limit {
+            options.push(format!("rate_limit={limit}/{duration:?}"));
+        }
+        if let Some(sz) = self.init_stream_window_size {
+            options.push(format!("initial_stream_window_size={sz}"));
+        }
+        if let Some(sz) = self.init_connection_window_size {
+            options.push(format!("initial_connection_window_size={sz}"));
+        }
+        if let Some(sz) = self.buffer_size {
+            options.push(format!("buffer_size={sz}"));
+        }
+        if let Some(enabled) = self.tcp_nodelay {
+            options.push(format!("tcp_nodelay={enabled}"));
+        }
+        if let Some(interval) = self.http2_keep_alive_interval {
+            options.push(format!("http2_keep_alive_interval={interval:?}"));
+        }
+        if let Some(timeout) = self.http2_keep_alive_timeout {
+            options.push(format!("keep_alive_timeout={timeout:?}"));
+        }
+        if let Some(enabled) = self.http2_keep_alive_while_idle {
+            options.push(format!("keep_alive_while_idle={enabled}"));
+        }
+        if let Some(enabled) = self.http2_adaptive_window {
+            options.push(format!("http2_adaptive_window={enabled}"));
+        }
+        #[cfg(feature = "tls")]
+        if self.tls_config.is_some() {
+            options.push("tls".to_owned());
+        }
+        if !self.default_metadata.is_empty() {
+            let keys: Vec<&str> = self
+                .default_metadata
+                .keys()
+                .map(HeaderName::as_str)
+                .collect();
+            options.push(format!("default_metadata=[{}]", keys.join(", ")));
+        }
+        if self.configure_endpoint.is_some() {
+            options.push("configure_endpoint".to_owned());
+        }
+        if let Some(proxy) = &self.proxy {
+            options.push(format!("proxy={proxy}"));
+        }
+        if self.prototype.is_some() {
+            options.push("prototype".to_owned());
+        }
+        #[cfg(feature = "grpc-web")]
+        if self.grpc_web {
+            options.push("grpc_web".to_owned());
+        }
+
+        if !options.is_empty() {
+            write!(f, " ({})", options.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Closure set with [`EndpointTemplate::configure_endpoint`].
+struct ConfigureEndpoint(Box<dyn Fn(Endpoint) -> Endpoint + Send + Sync>);
+
+impl fmt::Debug for ConfigureEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConfigureEndpoint")
+    }
+}
+
+/// Closure set with [`EndpointTemplate::connect_timeout_fn`].
+struct ConnectTimeoutFn(Box<dyn Fn(IpAddr) -> Duration + Send + Sync>);
+
+impl fmt::Debug for ConnectTimeoutFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConnectTimeoutFn")
+    }
+}
+
+fn warn_if_path_ignored(url: &Url) {
+    if !matches!(url.path(), "" | "/") {
+        tracing::warn!(
+            url = url.as_str(),
+            "path {} in endpoint template URL is ignored by gRPC requests",
+            url.path()
+        );
     }
 }
 
@@ -269,13 +794,80 @@ pub enum Error {
     HostMissing,
     AlreadyIpAddress,
     Inconvertible,
+    InvalidMetadataKey,
+    InvalidMetadataValue,
+    InvalidKeepalive,
+    InvalidUrl(url::ParseError),
+    #[cfg(feature = "tls")]
+    InvalidTlsConfig,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HostMissing => f.write_str("URL has no host"),
+            Self::AlreadyIpAddress => f.write_str("URL host is already an IP address"),
+            Self::Inconvertible => f.write_str("URL can't be converted into an endpoint URI"),
+            Self::InvalidMetadataKey => f.write_str("invalid metadata key"),
+            Self::InvalidMetadataValue => f.write_str("invalid metadata value"),
+            Self::InvalidKeepalive => {
+                f.write_str("keepalive timeout must be shorter than interval")
+            }
+            Self::InvalidUrl(_) => f.write_str("invalid URL"),
+            #[cfg(feature = "tls")]
+            Self::InvalidTlsConfig => f.write_str("invalid TLS configuration"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidUrl(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Why [`EndpointTemplate::connect_single`] failed.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The endpoint couldn't be built from the template.
+    Build(Error),
+    /// Connecting to the endpoint failed.
+    Transport(tonic::transport::Error),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Build(error) => write!(f, "failed to build endpoint: {error}"),
+            Self::Transport(error) => write!(f, "failed to connect: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Build(error) => Some(error),
+            Self::Transport(error) => Some(error),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{net::IpAddr, str::FromStr};
+    use std::{
+        net::IpAddr,
+        str::FromStr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
 
     use http::Uri;
+    use ipnet::IpNet;
+    use tonic::transport::Endpoint;
     use url::Url;
 
     use super::Error;
@@ -305,6 +897,228 @@ mod tests {
         );
     }
 
+    #[test]
+    fn prototype_is_copied_with_its_uri() {
+        let prototype = Endpoint::from_static("http://example.com:50051/foo");
+        let builder = EndpointTemplate::from_endpoint(prototype).unwrap();
+
+        for ip in ["203.0.113.6", "2001:db8::"] {
+            let endpoint = builder.build(ip.parse::<IpAddr>().unwrap());
+            assert_eq!(
+                *endpoint.uri(),
+                Uri::from_str("http://example.com:50051/foo").unwrap()
+            );
+        }
+        assert!(builder.to_string().contains("prototype"));
+
+        let prototype = Endpoint::from_static("http://203.0.113.6:50051");
+        assert_eq!(
+            EndpointTemplate::from_endpoint(prototype).unwrap_err(),
+            Error::AlreadyIpAddress
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn built_uri_is_logged() {
+        let builder =
+            EndpointTemplate::new(Url::parse("https://example.com:50051").unwrap()).unwrap();
+
+        builder.build_with_port("203.0.113.6".parse::<IpAddr>().unwrap(), 50052);
+        assert!(logs_contain(
+            "building endpoint ip=203.0.113.6 uri=https://203.0.113.6:50052/"
+        ));
+    }
+
+    #[test]
+    fn building_from_invalid_template_fails() {
+        let mut builder =
+            EndpointTemplate::new(Url::parse("http://example.com:50051").unwrap()).unwrap();
+        // Can't be constructed through Self::new.
+        builder.url = Url::parse("mailto:admin@example.com").unwrap();
+
+        let ip = "203.0.113.6".parse::<IpAddr>().unwrap();
+        assert_eq!(builder.try_build(ip).unwrap_err(), Error::Inconvertible);
+        assert_eq!(
+            builder.try_build_with_port(ip, 50052).unwrap_err(),
+            Error::Inconvertible
+        );
+    }
+
+    #[test]
+    fn can_override_port() {
+        let builder =
+            EndpointTemplate::new(Url::parse("http://example.com:50051/foo").unwrap()).unwrap();
+
+        let endpoint = builder.build_with_port("203.0.113.6".parse::<IpAddr>().unwrap(), 50052);
+        assert_eq!(
+            *endpoint.uri(),
+            Uri::from_str("http://203.0.113.6:50052/foo").unwrap()
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn path_is_kept_but_warned_about() {
+        let builder =
+            EndpointTemplate::new(Url::parse("http://example.com:50051/foo").unwrap()).unwrap();
+        assert!(logs_contain(
+            "path /foo in endpoint template URL is ignored"
+        ));
+        assert_eq!(builder.path(), "/foo");
+
+        let builder = builder.with_path("/");
+        let endpoint = builder.build("203.0.113.6".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            *endpoint.uri(),
+            Uri::from_str("http://203.0.113.6:50051/").unwrap()
+        );
+    }
+
+    #[test]
+    fn every_option_can_be_set() {
+        let template = EndpointTemplate::new(Url::parse("http://example.com:50051").unwrap())
+            .unwrap()
+            .origin(Uri::from_static("http://origin.example.com"))
+            .user_agent("my-agent")
+            .timeout(Duration::from_secs(1))
+            .connect_timeout(Duration::from_secs(2))
+            .tcp_keepalive(Some(Duration::from_secs(3)))
+            .concurrency_limit(4)
+            .rate_limit(5, Duration::from_secs(6))
+            .initial_stream_window_size(7)
+            .initial_connection_window_size(8)
+            .buffer_size(9)
+            .tcp_nodelay(false)
+            .http2_keepalive(Duration::from_secs(11), Duration::from_secs(10), true)
+            .unwrap()
+            .http2_adaptive_window(true);
+
+        // tonic only exposes the URI; the integration tests check what the
+        // other options do to requests.
+        let endpoint = template
+            .try_build("203.0.113.6".parse::<IpAddr>().unwrap())
+            .unwrap();
+        assert_eq!(
+            *endpoint.uri(),
+            Uri::from_str("http://203.0.113.6:50051/").unwrap()
+        );
+    }
+
+    #[test]
+    fn display_summarizes_options_set() {
+        let template = EndpointTemplate::new(Url::parse("http://example.com:50051").unwrap())
+            .unwrap()
+            .user_agent("my-agent")
+            .timeout(Duration::from_secs(1))
+            .default_metadata("authorization", "Bearer secret")
+            .unwrap();
+        assert_eq!(
+            template.to_string(),
+            "http://example.com:50051 (user_agent=\"my-agent\", timeout=1s, \
+             default_metadata=[authorization])"
+        );
+
+        // The scheme's default port, if none is set.
+        let template = EndpointTemplate::new(Url::parse("https://example.com").unwrap()).unwrap();
+        assert_eq!(template.to_string(), "https://example.com:443");
+    }
+
+    #[test]
+    fn connect_timeout_is_computed_per_ip() {
+        let near = "10.0.0.1".parse::<IpAddr>().unwrap();
+        let far = "192.0.2.1".parse::<IpAddr>().unwrap();
+        let computed = Arc::new(Mutex::new(Vec::new()));
+        let template = {
+            let computed = computed.clone();
+            EndpointTemplate::new(Url::parse("http://example.com:50051").unwrap())
+                .unwrap()
+                .connect_timeout(Duration::from_secs(1))
+                .connect_timeout_fn(move |ip| {
+                    let timeout = if IpNet::from_str("10.0.0.0/8").unwrap().contains(&ip) {
+                        Duration::from_millis(100)
+                    } else {
+                        Duration::from_secs(3)
+                    };
+                    computed.lock().unwrap().push((ip, timeout));
+                    timeout
+                })
+        };
+
+        template.build(near);
+        template.build(far);
+        assert_eq!(
+            *computed.lock().unwrap(),
+            [
+                (near, Duration::from_millis(100)),
+                (far, Duration::from_secs(3))
+            ]
+        );
+        assert_eq!(
+            template.to_string(),
+            "http://example.com:50051 (connect_timeout=per IP)"
+        );
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn client_identity_is_validated_and_applied() {
+        use tonic::transport::Identity;
+
+        let template =
+            || EndpointTemplate::new(Url::parse("https://example.com:50051").unwrap()).unwrap();
+        let identity = Identity::from_pem(
+            include_str!("../tests/data/client.pem"),
+            include_str!("../tests/data/client.key"),
+        );
+        let template = template().client_identity(identity).unwrap();
+        let endpoint = template
+            .try_build("203.0.113.6".parse::<IpAddr>().unwrap())
+            .unwrap();
+        assert_eq!(
+            *endpoint.uri(),
+            Uri::from_str("https://203.0.113.6:50051/").unwrap()
+        );
+        assert!(template.to_string().ends_with("(tls)"));
+
+        let not_a_key = Identity::from_pem(include_str!("../tests/data/client.pem"), "");
+        assert_eq!(
+            EndpointTemplate::new(Url::parse("https://example.com:50051").unwrap())
+                .unwrap()
+                .client_identity(not_a_key)
+                .unwrap_err(),
+            Error::InvalidTlsConfig
+        );
+    }
+
+    #[test]
+    fn http2_keepalive_sets_all_parameters() {
+        let template = || EndpointTemplate::new(Url::parse("http://example.com").unwrap()).unwrap();
+
+        let combined = template()
+            .http2_keepalive(Duration::from_secs(30), Duration::from_secs(10), true)
+            .unwrap();
+        let separate = template()
+            .http2_keep_alive_interval(Duration::from_secs(30))
+            .keep_alive_timeout(Duration::from_secs(10))
+            .keep_alive_while_idle(true);
+        assert_eq!(format!("{combined:?}"), format!("{separate:?}"));
+
+        let result =
+            template().http2_keepalive(Duration::from_secs(10), Duration::from_secs(10), false);
+        assert_eq!(result.unwrap_err(), Error::InvalidKeepalive);
+    }
+
+    #[rstest::rstest]
+    #[case("in valid", "value", Error::InvalidMetadataKey)]
+    #[case("x-tenant", "line\nbreak", Error::InvalidMetadataValue)]
+    fn default_metadata_error(#[case] key: &str, #[case] value: &str, #[case] expected: Error) {
+        let result = EndpointTemplate::new(Url::parse("http://example.com:50051").unwrap())
+            .unwrap()
+            .default_metadata(key, value);
+        assert_eq!(result.unwrap_err(), expected);
+    }
+
     #[rstest::rstest]
     #[case("http://127.0.0.1:50051", Error::AlreadyIpAddress)]
     #[case("http://[::1]:50051", Error::AlreadyIpAddress)]
@@ -314,4 +1128,36 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), expected);
     }
+
+    #[rstest::rstest]
+    #[case(
+        "//example.com:50051",
+        Error::InvalidUrl(url::ParseError::RelativeUrlWithoutBase)
+    )]
+    #[case(
+        "http://exa mple.com",
+        Error::InvalidUrl(url::ParseError::InvalidDomainCharacter)
+    )]
+    #[case("http://127.0.0.1:50051", Error::AlreadyIpAddress)]
+    fn parse_error(#[case] input: &str, #[case] expected: Error) {
+        assert_eq!(EndpointTemplate::parse(input).unwrap_err(), expected);
+    }
+
+    #[test]
+    fn parse_accepts_valid_url() {
+        let template = EndpointTemplate::parse("http://example.com:50051").unwrap();
+        assert_eq!(template.domain(), "example.com");
+    }
+
+    #[cfg(feature = "async-resolver")]
+    #[test]
+    fn origin_for_domain_replaces_host() {
+        let template =
+            EndpointTemplate::new(Url::parse("https://alias.test:50051/foo").unwrap()).unwrap();
+
+        assert_eq!(
+            template.origin_for_domain("canonical.test").unwrap(),
+            Uri::from_str("https://canonical.test:50051/").unwrap()
+        );
+    }
 }
@@ -0,0 +1,178 @@
+use std::{error::Error, fmt, net::SocketAddr};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::ResolutionError;
+
+/// Change to the endpoints registered with the balanced channel, see
+/// [`AutoBalancedChannel::events`](crate::AutoBalancedChannel::events).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndpointEvent {
+    Added(SocketAddr),
+    Removed(SocketAddr),
+}
+
+/// Edge into or out of [`Health::Undetermined`](crate::Health::Undetermined),
+/// see [`AutoBalancedChannel::health_events`](crate::AutoBalancedChannel::health_events).
+#[derive(Clone, Debug, PartialEq)]
+pub enum HealthEvent {
+    /// Resolution failed while health was `Ok`, so the channel went on with
+    /// the endpoints it had.
+    Undetermined { error: ResolutionError },
+    /// Resolution succeeded again after health was `Undetermined`.
+    Recovered,
+}
+
+/// What happens to an [`EndpointEvents`] or [`HealthEvents`] receiver that falls more than the
+/// buffer depth behind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Report how many events were missed with [`EventsError::Lagged`], then
+    /// carry on with the oldest event still buffered. The default, so that
+    /// missed events never go unnoticed.
+    #[default]
+    Error,
+    /// Silently carry on with the oldest event still buffered.
+    Skip,
+}
+
+/// Why [`EndpointEvents::recv`] or [`HealthEvents::recv`] returned no event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventsError {
+    /// The receiver fell behind and this many events were dropped.
+    Lagged(u64),
+    /// The channel was dropped, there will be no more events.
+    Closed,
+}
+
+impl fmt::Display for EventsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Lagged(missed) => write!(f, "missed {missed} endpoint events"),
+            Self::Closed => f.write_str("channel closed"),
+        }
+    }
+}
+
+impl Error for EventsError {}
+
+/// Receiver of the [`EndpointEvent`]s of a channel, from the moment it was
+/// created on.
+pub struct EndpointEvents {
+    receiver: broadcast::Receiver<EndpointEvent>,
+    lag_policy: LagPolicy,
+}
+
+impl EndpointEvents {
+    pub(crate) fn new(receiver: broadcast::Receiver<EndpointEvent>, lag_policy: LagPolicy) -> Self {
+        Self {
+            receiver,
+            lag_policy,
+        }
+    }
+
+    /// Waits for the next event.
+    pub async fn recv(&mut self) -> Result<EndpointEvent, EventsError> {
+        recv(&mut self.receiver, self.lag_policy).await
+    }
+}
+
+/// Receiver of the [`HealthEvent`]s of a channel, from the moment it was
+/// created on.
+pub struct HealthEvents {
+    receiver: broadcast::Receiver<HealthEvent>,
+    lag_policy: LagPolicy,
+}
+
+impl HealthEvents {
+    pub(crate) fn new(receiver: broadcast::Receiver<HealthEvent>, lag_policy: LagPolicy) -> Self {
+        Self {
+            receiver,
+            lag_policy,
+        }
+    }
+
+    /// Waits for the next event.
+    pub async fn recv(&mut self) -> Result<HealthEvent, EventsError> {
+        recv(&mut self.receiver, self.lag_policy).await
+    }
+}
+
+async fn recv<T: Clone>(
+    receiver: &mut broadcast::Receiver<T>,
+    lag_policy: LagPolicy,
+) -> Result<T, EventsError> {
+    loop {
+        match receiver.recv().await {
+            Ok(event) => return Ok(event),
+            Err(RecvError::Lagged(missed)) => match lag_policy {
+                LagPolicy::Error => return Err(EventsError::Lagged(missed)),
+                LagPolicy::Skip => tracing::debug!(missed, "skipping missed events"),
+            },
+            Err(RecvError::Closed) => return Err(EventsError::Closed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+
+    use sequential_test::sequential;
+
+    use super::{EndpointEvent, EventsError, LagPolicy};
+    use crate::{
+        test_util::{set_dns, set_dns_sockets, template},
+        AutoBalancedChannel,
+    };
+
+    #[rstest::rstest]
+    #[case::error(LagPolicy::Error, &[Err(EventsError::Lagged(6)), Ok(()), Ok(())])]
+    #[case::skip(LagPolicy::Skip, &[Ok(()), Ok(())])]
+    #[tokio::test]
+    #[sequential]
+    async fn slow_consumer_under_churn(
+        #[case] lag_policy: LagPolicy,
+        #[case] expected: &[Result<(), EventsError>],
+    ) {
+        set_dns(&[]);
+
+        let template = template();
+        let (sender, _receiver) = tokio::sync::mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template)
+            .interval(Duration::from_millis(1))
+            .with_event_buffer(2, lag_policy)
+            .build_with_sender(sender);
+        let mut events = balanced.events();
+
+        // Eight endpoints added at once, while the buffer only holds two.
+        let ips: Vec<Ipv4Addr> = (1..=8).map(|i| Ipv4Addr::new(10, 0, 0, i)).collect();
+        set_dns_sockets(
+            ips.iter()
+                .map(|ip| SocketAddr::new((*ip).into(), 0))
+                .collect(),
+        );
+        balanced
+            .endpoint_count_receiver()
+            .wait_for(|count| *count == 8)
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        for _ in expected {
+            let event = events.recv().await;
+            assert!(matches!(event, Ok(EndpointEvent::Added(_)) | Err(_)));
+            received.push(event.map(|_| ()));
+        }
+        assert_eq!(received, expected);
+        // Only the latest two events were left.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), events.recv())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! Telling failures of the connection to an endpoint from other request
+//! errors, for the features reacting to endpoints going away.
+
+use std::{error::Error, io};
+
+/// Whether `error`, or any error it was caused by, means that the connection
+/// to the endpoint couldn't be opened or broke under the request.
+///
+/// gRPC errors come as responses rather than errors, but not every error is
+/// the endpoint's fault either: a request timing out (tonic's
+/// `TimeoutExpired`) says nothing about its connection, for one.
+pub(crate) fn is_connection_failure(error: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<hyper::Error>() {
+            if error.is_connect() || error.is_closed() || error.is_incomplete_message() {
+                return true;
+            }
+        } else if let Some(error) = error.downcast_ref::<h2::Error>() {
+            if error.is_go_away() || error.is_io() {
+                return true;
+            }
+        } else if let Some(error) = error.downcast_ref::<io::Error>() {
+            if matches!(
+                error.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::TimedOut
+            ) {
+                return true;
+            }
+        }
+        source = error.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, fmt, io};
+
+    use super::is_connection_failure;
+
+    #[derive(Debug)]
+    struct Wrapped(io::Error);
+
+    impl fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "request failed")
+        }
+    }
+
+    impl Error for Wrapped {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[rstest::rstest]
+    #[case::refused(io::ErrorKind::ConnectionRefused, true)]
+    #[case::reset(io::ErrorKind::ConnectionReset, true)]
+    #[case::other(io::ErrorKind::Other, false)]
+    fn io_errors_are_classified_by_kind(#[case] kind: io::ErrorKind, #[case] expected: bool) {
+        let error = io::Error::new(kind, "test");
+        assert_eq!(is_connection_failure(&error), expected);
+    }
+
+    #[test]
+    fn causes_are_classified() {
+        let error = Wrapped(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert!(is_connection_failure(&error));
+    }
+}
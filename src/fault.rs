@@ -0,0 +1,111 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[cfg(feature = "async-resolver")]
+use crate::resolver::CanonicalNameFuture;
+use crate::resolver::{ResolveFuture, Resolver, WeightedResolveFuture};
+
+/// What a resolution returns instead of the resolver's result, see
+/// [`AutoBalancedChannel::inject_fault`](crate::AutoBalancedChannel::inject_fault).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// No address.
+    Empty,
+    /// An error of this kind.
+    Error(io::ErrorKind),
+    /// The resolver's result, but only after this long. Past the DNS timeout,
+    /// resolution times out.
+    Latency(Duration),
+}
+
+/// Faults for the next resolutions to return, in order.
+pub(crate) type Faults = Arc<Mutex<VecDeque<Fault>>>;
+
+/// Returns injected faults instead of the result of the wrapped resolver, as
+/// long as there are any.
+pub(crate) struct FaultInjector {
+    inner: Arc<dyn Resolver>,
+    faults: Faults,
+}
+
+impl FaultInjector {
+    pub(crate) fn new(inner: Arc<dyn Resolver>, faults: Faults) -> Self {
+        Self { inner, faults }
+    }
+}
+
+impl Resolver for FaultInjector {
+    fn resolve<'a>(&'a self, domain: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let addrs = self.resolve_weighted(domain).await?;
+            Ok(addrs.into_iter().map(|(addr, _)| addr).collect())
+        })
+    }
+
+    fn resolve_weighted<'a>(&'a self, domain: &'a str) -> WeightedResolveFuture<'a> {
+        Box::pin(async move {
+            let fault = self.faults.lock().unwrap().pop_front();
+            match fault {
+                None => self.inner.resolve_weighted(domain).await,
+                Some(Fault::Empty) => Ok(Vec::<(SocketAddr, u32)>::new()),
+                Some(Fault::Error(kind)) => Err(io::Error::new(kind, "injected fault")),
+                Some(Fault::Latency(latency)) => {
+                    tokio::time::sleep(latency).await;
+                    self.inner.resolve_weighted(domain).await
+                }
+            }
+        })
+    }
+
+    #[cfg(feature = "async-resolver")]
+    fn canonical_name<'a>(&'a self, domain: &'a str) -> CanonicalNameFuture<'a> {
+        self.inner.canonical_name(domain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, io, net::SocketAddr, time::Duration};
+
+    use sequential_test::sequential;
+
+    use super::Fault;
+    use crate::{dns::mock_net, test_util::template, AutoBalancedChannel, Health, HealthEvent};
+
+    #[tokio::test]
+    #[sequential]
+    async fn injected_error_makes_health_undetermined_until_reverted() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 0));
+        mock_net::set_socket_addrs(Box::new(move |_, _| Ok(vec![addr])));
+
+        let template = template();
+        let (sender, _receiver) = tokio::sync::mpsc::channel(16);
+        let balanced = AutoBalancedChannel::builder(template)
+            .interval(Duration::from_millis(10))
+            .build_with_sender(sender);
+        let mut events = balanced.health_events();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(balanced.get_health(), Health::Ok);
+
+        balanced.inject_faults(Fault::Error(io::ErrorKind::ConnectionRefused), 3);
+        let HealthEvent::Undetermined { error } = events.recv().await.unwrap() else {
+            panic!("not an Undetermined event");
+        };
+        let source = error.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(balanced.endpoint_count(), 1);
+
+        assert_eq!(events.recv().await.unwrap(), HealthEvent::Recovered);
+        let failures = balanced
+            .resolution_history()
+            .iter()
+            .filter(|record| record.error.is_some())
+            .count();
+        assert_eq!(failures, 3);
+    }
+}
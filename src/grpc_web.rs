@@ -0,0 +1,178 @@
+//! Channels to endpoints speaking gRPC-Web rather than gRPC, see
+//! [`EndpointTemplate::grpc_web`](crate::EndpointTemplate::grpc_web).
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use http::{HeaderMap, Request, Response};
+use hyper::body::HttpBody;
+use tonic::{
+    body::BoxBody,
+    transport::{Body, Channel},
+};
+use tonic_web::{GrpcWebCall, GrpcWebClientService};
+use tower::Service;
+
+/// Channel to a single endpoint, framing requests as gRPC-Web and turning
+/// gRPC-Web responses back into gRPC ones, with trailers.
+#[derive(Clone)]
+pub(crate) struct GrpcWebChannel {
+    inner: GrpcWebClientService<BoxRequests>,
+}
+
+impl GrpcWebChannel {
+    pub(crate) fn new(channel: Channel) -> Self {
+        Self {
+            inner: GrpcWebClientService::new(BoxRequests(channel)),
+        }
+    }
+}
+
+impl Service<Request<BoxBody>> for GrpcWebChannel {
+    type Response = Response<Body>;
+    type Error = tonic::transport::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<BoxBody>) -> Self::Future {
+        let response = self.inner.call(request);
+        Box::pin(async move { Ok(response.await?.map(into_body)) })
+    }
+}
+
+/// Tonic's `Channel` only takes boxed bodies, so box the gRPC-Web framed ones.
+#[derive(Clone)]
+struct BoxRequests(Channel);
+
+impl Service<Request<GrpcWebCall<BoxBody>>> for BoxRequests {
+    type Response = Response<Body>;
+    type Error = tonic::transport::Error;
+    type Future = <Channel as Service<Request<BoxBody>>>::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<GrpcWebCall<BoxBody>>) -> Self::Future {
+        self.0.call(request.map(BoxBody::new))
+    }
+}
+
+/// Streams a decoded gRPC-Web response body into a body of the type the
+/// balanced channel returns, with the trailers gRPC-Web carries in the body
+/// sent as actual trailers.
+fn into_body(mut web_body: GrpcWebCall<Body>) -> Body {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let trailers = loop {
+            match web_body.data().await {
+                Some(Ok(data)) => {
+                    if sender.send_data(data).await.is_err() {
+                        // The response was dropped.
+                        return;
+                    }
+                }
+                Some(Err(status)) => break Err(status),
+                None => break web_body.trailers().await,
+            }
+        };
+        let trailers = match trailers {
+            Ok(Some(trailers)) => trailers,
+            Ok(None) => return,
+            // Malformed framing: end the call with its status instead.
+            Err(status) => {
+                let mut trailers = HeaderMap::new();
+                if status.add_header(&mut trailers).is_err() {
+                    sender.abort();
+                    return;
+                }
+                trailers
+            }
+        };
+        let _ = sender.send_trailers(trailers).await;
+    });
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{convert::Infallible, future::ready, io};
+
+    use bytes::{BufMut, Bytes, BytesMut};
+    use http::header::CONTENT_TYPE;
+    use hyper::server::conn::Http;
+    use tonic::{
+        transport::{Endpoint, Uri},
+        Status,
+    };
+    use tower::ServiceExt;
+
+    /// A gRPC message frame holding `message`.
+    fn frame(flags: u8, message: &[u8]) -> Bytes {
+        let mut frame = BytesMut::new();
+        frame.put_u8(flags);
+        frame.put_u32(message.len() as u32);
+        frame.put_slice(message);
+        frame.freeze()
+    }
+
+    /// Echoes gRPC-Web requests' messages back, followed by a trailers frame.
+    async fn echo(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        assert_eq!(request.headers()[CONTENT_TYPE], "application/grpc-web");
+        let mut body = hyper::body::to_bytes(request.into_body())
+            .await
+            .unwrap()
+            .to_vec();
+        body.extend_from_slice(&frame(0x80, b"grpc-status:0\r\nx-echo:yes\r\n"));
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/grpc-web+proto")
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    fn echo_channel() -> Channel {
+        Endpoint::from_static("http://echo.invalid").connect_with_connector_lazy(tower::service_fn(
+            |_: Uri| {
+                let (client, server) = tokio::io::duplex(1024);
+                tokio::spawn(
+                    Http::new()
+                        .http2_only(true)
+                        .serve_connection(server, tower::service_fn(echo)),
+                );
+                ready(Ok::<_, io::Error>(client))
+            },
+        ))
+    }
+
+    #[tokio::test]
+    async fn frames_requests_and_decodes_trailers() {
+        let message = frame(0, b"hello");
+        let request = Request::builder()
+            .uri("http://echo.invalid/foo.Foo/Bar")
+            .body(
+                Body::from(message.clone())
+                    .map_err(|error| Status::from_error(error.into()))
+                    .boxed_unsync(),
+            )
+            .unwrap();
+
+        let response = GrpcWebChannel::new(echo_channel())
+            .oneshot(request)
+            .await
+            .unwrap();
+        let mut body = response.into_body();
+        assert_eq!(body.data().await.unwrap().unwrap(), message);
+        assert!(body.data().await.is_none());
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers["x-echo"], "yes");
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes};
+use http::uri::PathAndQuery;
+use tonic::{
+    client::Grpc,
+    codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder},
+    transport::Channel,
+    Request, Status,
+};
+
+use crate::discovery::join_bounded;
+
+/// Application level health check periodically sent to every endpoint.
+///
+/// Endpoints failing `failure_threshold` checks in a row are removed from the
+/// balanced channel until they pass a check again.
+#[derive(Clone, Debug)]
+pub struct HealthCheck {
+    path: PathAndQuery,
+    request: Bytes,
+    interval: Duration,
+    failure_threshold: u32,
+}
+
+impl HealthCheck {
+    const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+    const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+
+    /// Creates a check calling the unary method at `path` (e.g.
+    /// `/grpc.health.v1.Health/Check`) with the already encoded `request`
+    /// message. Any `OK` response counts as a pass.
+    pub fn new(path: PathAndQuery, request: impl Into<Bytes>) -> Self {
+        Self {
+            path,
+            request: request.into(),
+            interval: Self::DEFAULT_INTERVAL,
+            failure_threshold: Self::DEFAULT_FAILURE_THRESHOLD,
+        }
+    }
+
+    /// How often to check each endpoint. This is also the timeout of a single
+    /// check. Defaults to 5 seconds.
+    pub fn interval(self, interval: Duration) -> Self {
+        Self { interval, ..self }
+    }
+
+    /// Number of consecutive failed checks after which an endpoint is ejected.
+    /// Defaults to 3.
+    pub fn failure_threshold(self, failure_threshold: u32) -> Self {
+        Self {
+            failure_threshold,
+            ..self
+        }
+    }
+}
+
+/// Keeps a dedicated connection and the failure streak of every checked
+/// endpoint.
+pub(crate) struct HealthChecker {
+    check: HealthCheck,
+    channels: HashMap<SocketAddr, Channel>,
+    failures: HashMap<SocketAddr, u32>,
+}
+
+impl HealthChecker {
+    pub(crate) fn new(check: HealthCheck) -> Self {
+        Self {
+            check,
+            channels: HashMap::new(),
+            failures: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn interval(&self) -> Duration {
+        self.check.interval
+    }
+
+    /// Checks all `endpoints`, up to `limit` at a time, and returns those that
+    /// reached the failure threshold. `connect` creates lazily connected
+    /// channels to endpoints not checked before; addresses it fails for are
+    /// skipped.
+    pub(crate) async fn probe(
+        &mut self,
+        endpoints: &HashSet<SocketAddr>,
+        limit: usize,
+        connect: impl Fn(SocketAddr) -> Option<Channel>,
+    ) -> HashSet<SocketAddr> {
+        self.channels.retain(|addr, _| endpoints.contains(addr));
+        self.failures.retain(|addr, _| endpoints.contains(addr));
+
+        let mut probes = Vec::new();
+        for addr in endpoints {
+            let channel = match self.channels.get(addr) {
+                Some(channel) => channel.clone(),
+                None => {
+                    let Some(channel) = connect(*addr) else {
+                        continue;
+                    };
+                    self.channels.insert(*addr, channel.clone());
+                    channel
+                }
+            };
+            let check = self.check.clone();
+            let addr = *addr;
+            probes.push(async move { (addr, probe(channel, check).await) });
+        }
+
+        for (addr, result) in join_bounded(probes, limit).await {
+            let failures = self.failures.entry(addr).or_default();
+            match result {
+                Ok(()) => *failures = 0,
+                Err(reason) => {
+                    tracing::debug!(endpoint = %addr, reason, "health check failed");
+                    *failures += 1;
+                }
+            }
+        }
+
+        self.failures
+            .iter()
+            .filter(|(_, failures)| **failures >= self.check.failure_threshold)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+}
+
+/// Returns the reason of failure, if any.
+async fn probe(channel: Channel, check: HealthCheck) -> Result<(), String> {
+    let mut grpc = Grpc::new(channel);
+    let call = async {
+        grpc.ready().await.map_err(|e| e.to_string())?;
+        grpc.unary(Request::new(check.request), check.path, RawCodec)
+            .await
+            .map(|_| ())
+            .map_err(|status| status.to_string())
+    };
+
+    tokio::time::timeout(check.interval, call)
+        .await
+        .unwrap_or_else(|_| Err("timed out".to_owned()))
+}
+
+/// Passes already encoded messages through, so that health checks don't need
+/// to know the message types.
+struct RawCodec;
+
+impl Codec for RawCodec {
+    type Encode = Bytes;
+    type Decode = Bytes;
+    type Encoder = RawCodec;
+    type Decoder = RawCodec;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        RawCodec
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        RawCodec
+    }
+}
+
+impl Encoder for RawCodec {
+    type Item = Bytes;
+    type Error = Status;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut EncodeBuf<'_>) -> Result<(), Self::Error> {
+        dst.put(item);
+        Ok(())
+    }
+}
+
+impl Decoder for RawCodec {
+    type Item = Bytes;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<Self::Item>, Self::Error> {
+        Ok(Some(src.copy_to_bytes(src.remaining())))
+    }
+}
@@ -1,9 +1,60 @@
 mod endpoint_template;
-pub use endpoint_template::{EndpointTemplate, Error as EndpointTemplateError};
+pub use endpoint_template::{ConnectError, EndpointTemplate, Error as EndpointTemplateError};
 
+mod circuit_breaker;
+pub use circuit_breaker::CircuitBreakerConfig;
+
+mod discovery;
 mod dns;
+mod failure;
 #[cfg(feature = "mock-dns")]
 pub use dns::mock_net;
 
+mod resolver;
+#[cfg(feature = "mock-dns")]
+pub use resolver::StaticResolver;
+#[cfg(feature = "async-resolver")]
+pub use resolver::{CanonicalNameFuture, DnsProtocol, DnsServerResolver, QueryTypes, SrvResolver};
+pub use resolver::{ResolveFuture, Resolver, SystemResolver, WeightedResolveFuture};
+
+mod balance;
+pub use balance::{BalancePolicy, BalancedChannel, Load};
+
+#[cfg(feature = "grpc-web")]
+mod grpc_web;
+
+mod events;
+pub use events::{
+    EndpointEvent, EndpointEvents, EventsError, HealthEvent, HealthEvents, LagPolicy,
+};
+
+#[cfg(feature = "fault-injection")]
+mod fault;
+#[cfg(feature = "fault-injection")]
+pub use fault::Fault;
+
+mod proxy;
+pub use proxy::ProxyConfig;
+
+mod sink;
+pub use sink::{EndpointDelta, SinkOverflow};
+
+mod health_check;
+pub use health_check::HealthCheck;
+
+#[cfg(any(test, feature = "mock-dns"))]
+mod recording;
+#[cfg(feature = "mock-dns")]
+pub use recording::{RecordedChange, RecordingChannel};
+
 mod dynamic_channel;
-pub use dynamic_channel::{AutoBalancedChannel, DnsStatus, Health};
+#[cfg(feature = "async-resolver")]
+pub use dynamic_channel::OriginName;
+pub use dynamic_channel::{
+    AddressOrder, AutoBalancedChannel, AutoBalancedChannelBuilder, ChannelError, ChannelSnapshot,
+    DiscoveryHandle, DnsStatus, EmptyResolutionPolicy, EndpointTags, ErrorDisposition, Health,
+    ResolutionError, ResolutionRecord, RetryPolicy, RuntimeConfig,
+};
+
+#[cfg(test)]
+mod test_util;
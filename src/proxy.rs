@@ -0,0 +1,328 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use base64::Engine;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tonic::transport::{Channel, Endpoint, Uri};
+use tower::Service;
+
+/// Proxy to connect to endpoints through, see
+/// [`EndpointTemplate::proxy`](crate::EndpointTemplate::proxy).
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    protocol: ProxyProtocol,
+    address: String,
+    credentials: Option<(String, String)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ProxyProtocol {
+    HttpConnect,
+    Socks5,
+}
+
+impl ProxyConfig {
+    /// HTTP proxy at `address` (`host:port`), tunnelling connections with
+    /// `CONNECT` requests.
+    pub fn http(address: impl Into<String>) -> Self {
+        Self {
+            protocol: ProxyProtocol::HttpConnect,
+            address: address.into(),
+            credentials: None,
+        }
+    }
+
+    /// SOCKS5 proxy at `address` (`host:port`).
+    pub fn socks5(address: impl Into<String>) -> Self {
+        Self {
+            protocol: ProxyProtocol::Socks5,
+            address: address.into(),
+            credentials: None,
+        }
+    }
+
+    /// Authenticate with the proxy: basic authentication for HTTP proxies,
+    /// username/password authentication (RFC 1929) for SOCKS5 ones.
+    pub fn credentials(self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            credentials: Some((username.into(), password.into())),
+            ..self
+        }
+    }
+}
+
+impl fmt::Debug for ProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyConfig")
+            .field("protocol", &self.protocol)
+            .field("address", &self.address)
+            .field(
+                "credentials",
+                &self
+                    .credentials
+                    .as_ref()
+                    .map(|(username, _)| (username, "***")),
+            )
+            .finish()
+    }
+}
+
+impl fmt::Display for ProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.protocol {
+            ProxyProtocol::HttpConnect => "http",
+            ProxyProtocol::Socks5 => "socks5",
+        };
+        write!(f, "{scheme}://{}", self.address)
+    }
+}
+
+/// Connector for endpoints tonic can't connect on its own: opening a tunnel
+/// through the proxy, if any, and dialing the resolved address rather than
+/// the URI's host for endpoints built from a prototype, whose URIs keep the
+/// domain. tonic layers TLS and HTTP/2 on top, and applies the connect timeout
+/// to the whole handshake.
+#[derive(Clone, Debug)]
+pub(crate) struct Connector {
+    proxy: Option<Arc<ProxyConfig>>,
+    nodelay: bool,
+    /// Where to connect instead of the URI's host and port. Port 0 keeps the
+    /// URI's port.
+    addr: Option<SocketAddr>,
+}
+
+impl Connector {
+    pub(crate) fn new(proxy: Option<ProxyConfig>, nodelay: bool) -> Self {
+        Self {
+            proxy: proxy.map(Arc::new),
+            nodelay,
+            addr: None,
+        }
+    }
+
+    /// Connect to `addr` whatever the URI's host.
+    fn dialing(self, addr: SocketAddr) -> Self {
+        Self {
+            addr: Some(addr),
+            ..self
+        }
+    }
+}
+
+impl Service<Uri> for Connector {
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move {
+            let uri_port = uri
+                .port_u16()
+                .unwrap_or(if uri.scheme_str() == Some("https") {
+                    443
+                } else {
+                    80
+                });
+            let (host, port) = match connector.addr {
+                Some(addr) => {
+                    let port = Some(addr.port()).filter(|port| *port != 0);
+                    // Bracketed like IPv6 hosts in URIs.
+                    let host = match addr.ip() {
+                        IpAddr::V4(ip) => ip.to_string(),
+                        IpAddr::V6(ip) => format!("[{ip}]"),
+                    };
+                    (host, port.unwrap_or(uri_port))
+                }
+                None => {
+                    let host = uri.host().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "URI without host")
+                    })?;
+                    (host.to_owned(), uri_port)
+                }
+            };
+
+            let Some(config) = &connector.proxy else {
+                let ip = host.trim_start_matches('[').trim_end_matches(']');
+                let stream = TcpStream::connect((ip, port)).await?;
+                stream.set_nodelay(connector.nodelay)?;
+                return Ok(stream);
+            };
+            let mut stream = TcpStream::connect(config.address.as_str()).await?;
+            stream.set_nodelay(connector.nodelay)?;
+            match config.protocol {
+                ProxyProtocol::HttpConnect => {
+                    http_connect(&mut stream, &host, port, config).await?
+                }
+                ProxyProtocol::Socks5 => socks5_connect(&mut stream, &host, port, config).await?,
+            }
+            tracing::debug!(proxy = %config, %uri, "connected through proxy");
+            Ok(stream)
+        })
+    }
+}
+
+/// Connects to `endpoint` lazily, at `addr` (port 0 keeping the endpoint's
+/// port), through `connector` if any.
+pub(crate) fn connect_lazy(
+    endpoint: &Endpoint,
+    connector: Option<&Connector>,
+    addr: SocketAddr,
+) -> Channel {
+    match connector {
+        Some(connector) => endpoint.connect_with_connector_lazy(connector.clone().dialing(addr)),
+        None => endpoint.connect_lazy(),
+    }
+}
+
+/// Connects to `endpoint` at `addr`, through `connector` if any.
+pub(crate) async fn connect(
+    endpoint: &Endpoint,
+    connector: Option<&Connector>,
+    addr: SocketAddr,
+) -> Result<Channel, tonic::transport::Error> {
+    match connector {
+        Some(connector) => {
+            endpoint
+                .connect_with_connector(connector.clone().dialing(addr))
+                .await
+        }
+        None => endpoint.connect().await,
+    }
+}
+
+/// Longest response head accepted from an HTTP proxy.
+const MAX_RESPONSE_HEAD: usize = 8 * 1024;
+
+async fn http_connect(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    config: &ProxyConfig,
+) -> io::Result<()> {
+    // IPv6 hosts are already bracketed in URIs.
+    let mut request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+    if let Some((username, password)) = &config.credentials {
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read a byte at a time, so that nothing the server sends through the
+    // tunnel right away is consumed along with the response.
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(proxy_error("HTTP proxy response too long"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let status_line = String::from_utf8_lossy(&head);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some("200") => Ok(()),
+        _ => Err(proxy_error(format!(
+            "HTTP proxy refused to connect: {status_line}"
+        ))),
+    }
+}
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_PASSWORD_AUTH: u8 = 2;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_IPV4: u8 = 1;
+const SOCKS_DOMAIN: u8 = 3;
+const SOCKS_IPV6: u8 = 4;
+
+async fn socks5_connect(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    config: &ProxyConfig,
+) -> io::Result<()> {
+    let method = match config.credentials {
+        Some(_) => SOCKS_PASSWORD_AUTH,
+        None => SOCKS_NO_AUTH,
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [SOCKS_VERSION, method] {
+        return Err(proxy_error("SOCKS5 proxy refused authentication method"));
+    }
+
+    if let Some((username, password)) = &config.credentials {
+        let mut request = vec![1];
+        for field in [username, password] {
+            let len = u8::try_from(field.len())
+                .map_err(|_| proxy_error("SOCKS5 credentials longer than 255 bytes"))?;
+            request.push(len);
+            request.extend_from_slice(field.as_bytes());
+        }
+        stream.write_all(&request).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(proxy_error("SOCKS5 proxy rejected credentials"));
+        }
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+    match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(SOCKS_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(SOCKS_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len())
+                .map_err(|_| proxy_error("host name longer than 255 bytes"))?;
+            request.extend_from_slice(&[SOCKS_DOMAIN, len]);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(proxy_error(format!(
+            "SOCKS5 proxy refused to connect, reply {}",
+            reply[1]
+        )));
+    }
+    // Skip the address the proxy bound, and its port.
+    let bound_len = match reply[3] {
+        SOCKS_IPV4 => 4,
+        SOCKS_IPV6 => 16,
+        SOCKS_DOMAIN => usize::from(stream.read_u8().await?),
+        _ => return Err(proxy_error("invalid SOCKS5 reply")),
+    };
+    let mut bound = vec![0; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+fn proxy_error(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, message.into())
+}
@@ -0,0 +1,311 @@
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::sync::mpsc;
+use tonic::transport::Endpoint;
+use tower::discover::Change;
+
+use crate::{AutoBalancedChannel, AutoBalancedChannelBuilder};
+
+/// Endpoint change recorded by [`RecordingChannel`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecordedChange {
+    Insert(SocketAddr),
+    Remove(SocketAddr),
+}
+
+impl RecordedChange {
+    fn from_change(change: Change<SocketAddr, Endpoint>) -> Self {
+        match change {
+            Change::Insert(addr, _) => Self::Insert(addr),
+            Change::Remove(addr) => Self::Remove(addr),
+        }
+    }
+}
+
+/// An [`AutoBalancedChannel`] recording the endpoint changes it makes instead
+/// of connecting to anything, for testing code that depends on how the
+/// endpoints evolve. Combine with [`mock_net`](crate::mock_net) to control the
+/// resolved addresses.
+pub struct RecordingChannel {
+    channel: AutoBalancedChannel,
+    receiver: mpsc::Receiver<Change<SocketAddr, Endpoint>>,
+    changes: Vec<RecordedChange>,
+}
+
+impl RecordingChannel {
+    pub fn new(builder: AutoBalancedChannelBuilder) -> Self {
+        // Large enough not to block the discovery loop between inspections
+        // in a reasonable test.
+        let (sender, receiver) = mpsc::channel(1024);
+        Self {
+            channel: builder.build_with_sender(sender),
+            receiver,
+            changes: Vec::new(),
+        }
+    }
+
+    pub fn channel(&self) -> &AutoBalancedChannel {
+        &self.channel
+    }
+
+    /// Returns all changes made so far, in order.
+    pub fn changes(&mut self) -> &[RecordedChange] {
+        while let Ok(change) = self.receiver.try_recv() {
+            self.changes.push(RecordedChange::from_change(change));
+        }
+        &self.changes
+    }
+
+    /// Panics unless an endpoint with `ip` has been inserted.
+    #[track_caller]
+    pub fn assert_inserted(&mut self, ip: impl Into<IpAddr>) {
+        let ip = ip.into();
+        let changes = self.changes();
+        assert!(
+            changes
+                .iter()
+                .any(|change| matches!(change, RecordedChange::Insert(addr) if addr.ip() == ip)),
+            "{ip} was not inserted, changes: {changes:?}"
+        );
+    }
+
+    /// Panics unless an endpoint with `ip` has been removed.
+    #[track_caller]
+    pub fn assert_removed(&mut self, ip: impl Into<IpAddr>) {
+        let ip = ip.into();
+        let changes = self.changes();
+        assert!(
+            changes
+                .iter()
+                .any(|change| matches!(change, RecordedChange::Remove(addr) if addr.ip() == ip)),
+            "{ip} was not removed, changes: {changes:?}"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+
+    use sequential_test::sequential;
+
+    use super::{RecordedChange, RecordingChannel};
+    use crate::{
+        test_util::{set_dns, set_dns_sockets, template},
+        AddressOrder, AutoBalancedChannel, Health,
+    };
+
+    #[tokio::test]
+    #[sequential]
+    async fn records_inserts_and_removes() {
+        let first = Ipv4Addr::new(10, 0, 0, 1);
+        let second = Ipv4Addr::new(10, 0, 0, 2);
+        set_dns(&[&first.to_string()]);
+
+        let template = template();
+        let mut recording = RecordingChannel::new(
+            AutoBalancedChannel::builder(template).interval(Duration::from_millis(1)),
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        recording.assert_inserted(first);
+        assert_eq!(recording.channel().get_health(), Health::Ok);
+
+        set_dns(&[&second.to_string()]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        recording.assert_inserted(second);
+        recording.assert_removed(first);
+        assert_eq!(
+            recording.changes(),
+            [
+                RecordedChange::Insert(SocketAddr::new(first.into(), 0)),
+                RecordedChange::Insert(SocketAddr::new(second.into(), 0)),
+                RecordedChange::Remove(SocketAddr::new(first.into(), 0)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn unchanged_resolutions_record_nothing() {
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        set_dns(&[&ip.to_string()]);
+
+        let template = template();
+        let mut recording = RecordingChannel::new(
+            AutoBalancedChannel::builder(template).interval(Duration::from_millis(1)),
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            recording.changes(),
+            [RecordedChange::Insert(SocketAddr::new(ip.into(), 0))]
+        );
+
+        // Many more cycles resolving the same address.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(recording.changes().len(), 1);
+        assert_eq!(recording.channel().last_delta(), (0, 0));
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn seeded_shuffle_is_reproducible() {
+        let ips: Vec<Ipv4Addr> = (1..=8).map(|i| Ipv4Addr::new(10, 0, 0, i)).collect();
+        set_dns_sockets(
+            ips.iter()
+                .map(|ip| SocketAddr::new((*ip).into(), 0))
+                .collect(),
+        );
+
+        let mut orders = Vec::new();
+        for _ in 0..2 {
+            let template = template();
+            let mut recording = RecordingChannel::new(
+                AutoBalancedChannel::builder(template)
+                    .interval(Duration::from_millis(1))
+                    .with_shuffle_seed(42),
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            orders.push(recording.changes().to_vec());
+        }
+
+        let sorted: Vec<RecordedChange> = ips
+            .iter()
+            .map(|ip| RecordedChange::Insert(SocketAddr::new((*ip).into(), 0)))
+            .collect();
+        assert_eq!(orders[0], orders[1]);
+        assert_ne!(orders[0], sorted);
+        let mut shuffled = orders[0].clone();
+        shuffled.sort_by_key(|change| match change {
+            RecordedChange::Insert(addr) | RecordedChange::Remove(addr) => *addr,
+        });
+        assert_eq!(shuffled, sorted);
+    }
+
+    #[rstest::rstest]
+    #[case::sorted(AddressOrder::Sorted, Some([1, 2, 3]))]
+    #[case::resolver_order(AddressOrder::ResolverOrder, Some([3, 1, 2]))]
+    #[case::shuffled(AddressOrder::Shuffled, None)]
+    #[tokio::test]
+    #[sequential]
+    async fn endpoints_are_inserted_in_address_order(
+        #[case] address_order: AddressOrder,
+        #[case] expected: Option<[u8; 3]>,
+    ) {
+        let ips: Vec<Ipv4Addr> = [3, 1, 2].map(|i| Ipv4Addr::new(10, 0, 0, i)).to_vec();
+        set_dns_sockets(
+            ips.iter()
+                .map(|ip| SocketAddr::new((*ip).into(), 0))
+                .collect(),
+        );
+
+        let template = template();
+        let mut recording = RecordingChannel::new(
+            AutoBalancedChannel::builder(template)
+                .interval(Duration::from_millis(1))
+                .address_order(address_order),
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let inserted: Vec<u8> = recording
+            .changes()
+            .iter()
+            .map(|change| match change {
+                RecordedChange::Insert(SocketAddr::V4(addr)) => addr.ip().octets()[3],
+                change => panic!("unexpected change {change:?}"),
+            })
+            .collect();
+        match expected {
+            Some(expected) => assert_eq!(inserted, expected),
+            // Any order, as long as all are inserted once.
+            None => {
+                let mut sorted = inserted.clone();
+                sorted.sort();
+                assert_eq!(sorted, [1, 2, 3]);
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[sequential]
+    async fn slow_builds_overlap_but_keep_their_order() {
+        let ips: Vec<Ipv4Addr> = (1..=8).map(|i| Ipv4Addr::new(10, 0, 0, i)).collect();
+        set_dns_sockets(
+            ips.iter()
+                .map(|ip| SocketAddr::new((*ip).into(), 0))
+                .collect(),
+        );
+
+        let template = template().configure_endpoint(|endpoint| {
+            std::thread::sleep(Duration::from_millis(50));
+            endpoint
+        });
+        let mut recording = RecordingChannel::new(
+            AutoBalancedChannel::builder(template).interval(Duration::from_secs(60)),
+        );
+        // One after the other, the builds would take 400ms.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let inserted: Vec<RecordedChange> = ips
+            .iter()
+            .map(|ip| RecordedChange::Insert(SocketAddr::new((*ip).into(), 0)))
+            .collect();
+        assert_eq!(recording.changes(), inserted);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn seeded_recycling_stagger_is_reproducible() {
+        let ips: Vec<Ipv4Addr> = (1..=8).map(|i| Ipv4Addr::new(10, 0, 0, i)).collect();
+        set_dns_sockets(
+            ips.iter()
+                .map(|ip| SocketAddr::new((*ip).into(), 0))
+                .collect(),
+        );
+
+        let mut recycled = Vec::new();
+        for _ in 0..2 {
+            let template = template();
+            let mut recording = RecordingChannel::new(
+                AutoBalancedChannel::builder(template)
+                    .with_max_connection_age(Duration::from_secs(40))
+                    .with_rng_seed(7),
+            );
+            // All recycled once, within 40s plus up to 10s of stagger.
+            tokio::time::sleep(Duration::from_secs(50)).await;
+            let changes = recording.changes();
+            assert_eq!(changes.len(), 16);
+            recycled.push(changes[8..].to_vec());
+        }
+
+        let inserted: Vec<RecordedChange> = ips
+            .iter()
+            .map(|ip| RecordedChange::Insert(SocketAddr::new((*ip).into(), 0)))
+            .collect();
+        assert_eq!(recycled[0], recycled[1]);
+        // In the order of their stagger rather than of insertion.
+        assert_ne!(recycled[0], inserted);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[sequential]
+    async fn connections_are_recycled_after_max_age() {
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        set_dns(&[&ip.to_string()]);
+
+        let template = template();
+        let mut recording = RecordingChannel::new(
+            AutoBalancedChannel::builder(template).with_max_connection_age(Duration::from_secs(40)),
+        );
+        let insert = RecordedChange::Insert(SocketAddr::new(ip.into(), 0));
+
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        assert_eq!(recording.changes().len(), 1);
+
+        // Recycled within 40s plus up to 10s of stagger.
+        tokio::time::sleep(Duration::from_secs(40)).await;
+        assert_eq!(recording.changes(), [insert.clone(), insert]);
+    }
+}
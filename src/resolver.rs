@@ -0,0 +1,660 @@
+use std::{future::Future, io, net::SocketAddr, pin::Pin};
+
+#[cfg(feature = "async-resolver")]
+use std::collections::BTreeMap;
+#[cfg(any(test, feature = "mock-dns"))]
+use std::{collections::HashMap, net::IpAddr};
+
+#[cfg(feature = "async-resolver")]
+use hickory_resolver::proto::rr::rdata::SRV;
+
+use crate::dns::resolve_domain;
+
+/// Future returned by [`Resolver::resolve`].
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+/// Future returned by [`Resolver::resolve_weighted`].
+pub type WeightedResolveFuture<'a> =
+    Pin<Box<dyn Future<Output = io::Result<Vec<(SocketAddr, u32)>>> + Send + 'a>>;
+
+/// Future returned by [`Resolver::canonical_name`].
+#[cfg(feature = "async-resolver")]
+pub type CanonicalNameFuture<'a> =
+    Pin<Box<dyn Future<Output = io::Result<Option<String>>> + Send + 'a>>;
+
+/// Resolves the domain of an endpoint template into the addresses to balance
+/// over.
+///
+/// Like the system resolver, implementations may return port 0 to use the
+/// template's port.
+///
+/// Link-local IPv6 addresses (`fe80::/10`) are skipped with a warning, whether
+/// or not they come with a scope ID: endpoint URIs can't carry one, and
+/// without it the address is ambiguous on hosts with several interfaces.
+///
+/// Resolvers are called from the background task of each channel using them,
+/// which may run on any runtime thread, hence `Send + Sync`. A resolver shared
+/// with [`AutoBalancedChannelBuilder::with_shared_resolver`] is called
+/// concurrently by all its channels.
+///
+/// [`AutoBalancedChannelBuilder::with_shared_resolver`]: crate::AutoBalancedChannelBuilder::with_shared_resolver
+pub trait Resolver: Send + Sync + 'static {
+    fn resolve<'a>(&'a self, domain: &'a str) -> ResolveFuture<'a>;
+
+    /// Like [`Self::resolve`], along with the weight of each address, e.g.
+    /// from SRV records. This is what channels call. By default, every address
+    /// weighs 1.
+    fn resolve_weighted<'a>(&'a self, domain: &'a str) -> WeightedResolveFuture<'a> {
+        Box::pin(async move {
+            let addrs = self.resolve(domain).await?;
+            Ok(addrs.into_iter().map(|addr| (addr, 1)).collect())
+        })
+    }
+
+    /// Returns the name at the end of the CNAME chain starting at `domain`, or
+    /// `None` if `domain` isn't an alias. Only used with
+    /// [`OriginName::Canonical`](crate::OriginName::Canonical). By default,
+    /// no domain is an alias.
+    #[cfg(feature = "async-resolver")]
+    fn canonical_name<'a>(&'a self, domain: &'a str) -> CanonicalNameFuture<'a> {
+        let _ = domain;
+        Box::pin(async { Ok(None) })
+    }
+}
+
+/// Resolver using the operating system's name resolution. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, domain: &'a str) -> ResolveFuture<'a> {
+        let domain = domain.to_owned();
+        Box::pin(async move {
+            // The lookup blocks, so run it off the runtime threads, where it
+            // can neither stall other tasks nor outlive the DNS timeout.
+            tokio::task::spawn_blocking(move || resolve_domain(&domain).map(Iterator::collect))
+                .await
+                .map_err(io::Error::other)?
+        })
+    }
+}
+
+/// Transport used to reach a DNS server.
+#[cfg(feature = "async-resolver")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DnsProtocol {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+/// Address records a [`DnsServerResolver`] queries.
+#[cfg(feature = "async-resolver")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueryTypes {
+    /// A records, then AAAA records only if there is no A record.
+    #[default]
+    AThenAaaa,
+    /// Only A records, e.g. on IPv4-only networks.
+    A,
+    /// Only AAAA records.
+    Aaaa,
+}
+
+/// Resolver querying a specific DNS server, e.g. a local caching proxy,
+/// instead of the one configured in the operating system.
+#[cfg(feature = "async-resolver")]
+pub struct DnsServerResolver {
+    resolver: hickory_resolver::TokioAsyncResolver,
+}
+
+#[cfg(feature = "async-resolver")]
+impl DnsServerResolver {
+    pub fn new(server: SocketAddr, protocol: DnsProtocol) -> Self {
+        Self::with_query_types(server, protocol, QueryTypes::default())
+    }
+
+    /// Like [`Self::new`], but only querying the address records of
+    /// `query_types`, rather than filtering the addresses afterwards.
+    pub fn with_query_types(
+        server: SocketAddr,
+        protocol: DnsProtocol,
+        query_types: QueryTypes,
+    ) -> Self {
+        use hickory_resolver::config::{
+            LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+        };
+
+        let protocol = match protocol {
+            DnsProtocol::Udp => Protocol::Udp,
+            DnsProtocol::Tcp => Protocol::Tcp,
+        };
+        let mut config = ResolverConfig::new();
+        config.add_name_server(NameServerConfig::new(server, protocol));
+        let mut opts = ResolverOpts::default();
+        opts.ip_strategy = match query_types {
+            QueryTypes::AThenAaaa => LookupIpStrategy::Ipv4thenIpv6,
+            QueryTypes::A => LookupIpStrategy::Ipv4Only,
+            QueryTypes::Aaaa => LookupIpStrategy::Ipv6Only,
+        };
+        Self {
+            resolver: hickory_resolver::TokioAsyncResolver::tokio(config, opts),
+        }
+    }
+}
+
+#[cfg(feature = "async-resolver")]
+impl Resolver for DnsServerResolver {
+    fn resolve<'a>(&'a self, domain: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let lookup = self.resolver.lookup_ip(domain).await?;
+            Ok(lookup.iter().map(|ip| SocketAddr::new(ip, 0)).collect())
+        })
+    }
+
+    fn canonical_name<'a>(&'a self, domain: &'a str) -> CanonicalNameFuture<'a> {
+        use hickory_resolver::proto::rr::RData;
+
+        Box::pin(async move {
+            let lookup = self.resolver.lookup_ip(domain).await?;
+            let records = lookup.as_lookup().records();
+            let mut name = lookup.query().name().clone();
+            let mut aliased = false;
+            // Bounded by the number of records in case the chain is a loop.
+            for _ in 0..records.len() {
+                let target = records.iter().find_map(|record| match record.data() {
+                    Some(RData::CNAME(target)) if *record.name() == name => Some(target.0.clone()),
+                    _ => None,
+                });
+                let Some(target) = target else {
+                    break;
+                };
+                name = target;
+                aliased = true;
+            }
+            Ok(aliased.then(|| name.to_utf8().trim_end_matches('.').to_owned()))
+        })
+    }
+}
+
+/// Resolver treating the domain as the name of SRV records, e.g.
+/// `_grpc._tcp.backend.example`, and returning the addresses of their targets
+/// with the port and weight of the record. Queries the DNS server at `server`,
+/// like [`DnsServerResolver`].
+///
+/// Only the targets of the lowest priority are used, the others being
+/// fallbacks: a priority is skipped only if none of its targets resolves.
+/// Targets of weight 0 are balanced as if of weight 1, see
+/// [`AutoBalancedChannel::endpoint_weight`](crate::AutoBalancedChannel::endpoint_weight).
+#[cfg(feature = "async-resolver")]
+pub struct SrvResolver {
+    inner: DnsServerResolver,
+}
+
+#[cfg(feature = "async-resolver")]
+impl SrvResolver {
+    pub fn new(server: SocketAddr, protocol: DnsProtocol) -> Self {
+        Self {
+            inner: DnsServerResolver::new(server, protocol),
+        }
+    }
+}
+
+#[cfg(feature = "async-resolver")]
+impl Resolver for SrvResolver {
+    fn resolve<'a>(&'a self, domain: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let addrs = self.resolve_weighted(domain).await?;
+            Ok(addrs.into_iter().map(|(addr, _)| addr).collect())
+        })
+    }
+
+    fn resolve_weighted<'a>(&'a self, domain: &'a str) -> WeightedResolveFuture<'a> {
+        Box::pin(async move {
+            let lookup = self.inner.resolver.srv_lookup(domain).await?;
+            let mut tiers: BTreeMap<u16, Vec<&SRV>> = BTreeMap::new();
+            for srv in lookup.iter() {
+                // A target of "." means the service is unavailable.
+                if !srv.target().is_root() {
+                    tiers.entry(srv.priority()).or_default().push(srv);
+                }
+            }
+
+            for (priority, targets) in tiers {
+                let mut addrs = Vec::new();
+                for srv in targets {
+                    match self.inner.resolver.lookup_ip(srv.target().clone()).await {
+                        Ok(ips) => {
+                            addrs.extend(ips.iter().map(|ip| {
+                                (SocketAddr::new(ip, srv.port()), u32::from(srv.weight()))
+                            }))
+                        }
+                        Err(error) => tracing::debug!(
+                            name = %srv.target(),
+                            %error,
+                            "failed to resolve SRV target"
+                        ),
+                    }
+                }
+                if !addrs.is_empty() {
+                    return Ok(addrs);
+                }
+                tracing::debug!(priority, "no SRV target of priority {priority} resolved");
+            }
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no SRV target of {domain} resolved"),
+            ))
+        })
+    }
+}
+
+/// Resolver with fixed mappings from domains to IP addresses, like
+/// `/etc/hosts`. Unknown domains fail with [`io::ErrorKind::NotFound`].
+///
+/// Unlike [`mock_net`](crate::mock_net), it only affects the channels it's
+/// passed to.
+#[cfg(any(test, feature = "mock-dns"))]
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+#[cfg(any(test, feature = "mock-dns"))]
+impl StaticResolver {
+    pub fn new(hosts: HashMap<String, Vec<IpAddr>>) -> Self {
+        Self { hosts }
+    }
+}
+
+#[cfg(any(test, feature = "mock-dns"))]
+impl Resolver for StaticResolver {
+    fn resolve<'a>(&'a self, domain: &'a str) -> ResolveFuture<'a> {
+        let result = match self.hosts.get(domain) {
+            Some(ips) => Ok(ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no such host: {domain}"),
+            )),
+        };
+        Box::pin(async move { result })
+    }
+}
+
+#[cfg(all(test, feature = "async-resolver"))]
+mod dns_server_tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use hickory_resolver::proto::{
+        op::{Message, MessageType},
+        rr::{
+            rdata::{A, CNAME, SRV},
+            Name, RData, Record, RecordType,
+        },
+    };
+    use sequential_test::sequential;
+    use tokio::net::UdpSocket;
+    use url::Url;
+
+    use super::{DnsProtocol, DnsServerResolver, QueryTypes, Resolver, SrvResolver};
+    use crate::{recording::RecordingChannel, AutoBalancedChannel, EndpointTemplate, OriginName};
+
+    /// Answers every A query with 10.0.0.1 and everything else with nothing.
+    /// `alias.test` is a CNAME of `middle.test`, which is a CNAME of
+    /// `canonical.test`.
+    ///
+    /// Except that the `srv-N.test` targets of the SRV records (see
+    /// [`srv_records`]) are at 10.0.1.N, and `missing.test` has no address.
+    async fn mock_dns_server() -> SocketAddr {
+        mock_dns_server_recording().await.0
+    }
+
+    /// [`mock_dns_server`], also recording the types of the records queried.
+    async fn mock_dns_server_recording() -> (SocketAddr, Arc<Mutex<Vec<RecordType>>>) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queried = Arc::new(Mutex::new(Vec::new()));
+        let recorded = queried.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let query = Message::from_vec(&buf[..len]).unwrap();
+                recorded
+                    .lock()
+                    .unwrap()
+                    .extend(query.queries().iter().map(|question| question.query_type()));
+                let mut response = Message::new();
+                response
+                    .set_id(query.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_available(true)
+                    .add_queries(query.queries().to_vec());
+                for question in query.queries() {
+                    if question.query_type() == RecordType::SRV {
+                        for (priority, weight, port, target) in srv_records(question.name()) {
+                            let srv =
+                                SRV::new(priority, weight, port, Name::from_ascii(target).unwrap());
+                            response.add_answer(Record::from_rdata(
+                                question.name().clone(),
+                                60,
+                                RData::SRV(srv),
+                            ));
+                        }
+                        continue;
+                    }
+                    if question.query_type() != RecordType::A {
+                        continue;
+                    }
+                    let ascii = question.name().to_ascii();
+                    if ascii == "missing.test." {
+                        continue;
+                    }
+                    if let Some(n) = ascii
+                        .strip_prefix("srv-")
+                        .and_then(|rest| rest.strip_suffix(".test."))
+                    {
+                        response.add_answer(Record::from_rdata(
+                            question.name().clone(),
+                            60,
+                            RData::A(A::new(10, 0, 1, n.parse().unwrap())),
+                        ));
+                        continue;
+                    }
+                    let mut name = question.name().clone();
+                    if name == Name::from_ascii("alias.test.").unwrap() {
+                        for target in ["middle.test.", "canonical.test."] {
+                            let target = Name::from_ascii(target).unwrap();
+                            response.add_answer(Record::from_rdata(
+                                name,
+                                60,
+                                RData::CNAME(CNAME(target.clone())),
+                            ));
+                            name = target;
+                        }
+                    }
+                    response.add_answer(Record::from_rdata(
+                        name,
+                        60,
+                        RData::A(A::new(10, 0, 0, 1)),
+                    ));
+                }
+                socket
+                    .send_to(&response.to_vec().unwrap(), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+        (addr, queried)
+    }
+
+    /// `_grpc._tcp.backend.test` has two weighted targets of priority 10 and
+    /// a fallback of priority 20. `_grpc._tcp.fallback.test` only has a target
+    /// without address at priority 10, so the priority 20 ones are used.
+    fn srv_records(name: &Name) -> Vec<(u16, u16, u16, &'static str)> {
+        match name.to_ascii().as_str() {
+            "_grpc._tcp.backend.test." => vec![
+                (10, 3, 50051, "srv-1.test."),
+                (10, 1, 50052, "srv-2.test."),
+                (20, 5, 50053, "srv-3.test."),
+            ],
+            "_grpc._tcp.fallback.test." => vec![
+                (10, 3, 50051, "missing.test."),
+                (20, 2, 50052, "srv-2.test."),
+                (20, 4, 50053, "srv-3.test."),
+            ],
+            _ => Vec::new(),
+        }
+    }
+
+    #[rstest::rstest]
+    #[case::first_priority("_grpc._tcp.backend.test", &[(2, 50052, 1), (1, 50051, 3)])]
+    #[case::fallback_priority("_grpc._tcp.fallback.test", &[(2, 50052, 2), (3, 50053, 4)])]
+    #[tokio::test]
+    #[sequential]
+    async fn srv_targets_of_lowest_resolving_priority_are_used(
+        #[case] domain: &str,
+        #[case] expected: &[(u8, u16, u32)],
+    ) {
+        let server = mock_dns_server().await;
+        let resolver = SrvResolver::new(server, DnsProtocol::Udp);
+
+        let mut addrs = resolver.resolve_weighted(domain).await.unwrap();
+        addrs.sort();
+        let mut expected: Vec<(SocketAddr, u32)> = expected
+            .iter()
+            .map(|(n, port, weight)| {
+                (
+                    SocketAddr::from((Ipv4Addr::new(10, 0, 1, *n), *port)),
+                    *weight,
+                )
+            })
+            .collect();
+        expected.sort();
+        assert_eq!(addrs, expected);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn srv_weights_are_endpoint_weights() {
+        let server = mock_dns_server().await;
+
+        let template =
+            EndpointTemplate::new(Url::parse("http://_grpc._tcp.backend.test:50051").unwrap())
+                .unwrap();
+        let mut recording = RecordingChannel::new(
+            AutoBalancedChannel::builder(template)
+                .interval(Duration::from_millis(10))
+                .with_resolver(SrvResolver::new(server, DnsProtocol::Udp)),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        recording.assert_inserted(Ipv4Addr::new(10, 0, 1, 1));
+        recording.assert_inserted(Ipv4Addr::new(10, 0, 1, 2));
+        assert_eq!(recording.changes().len(), 2);
+        let weight = |n| {
+            recording
+                .channel()
+                .endpoint_weight(Ipv4Addr::new(10, 0, 1, n).into())
+        };
+        assert_eq!(weight(1), Some(3));
+        assert_eq!(weight(2), Some(1));
+        assert_eq!(weight(3), None);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn resolves_with_custom_dns_server() {
+        let server = mock_dns_server().await;
+
+        let template =
+            EndpointTemplate::new(Url::parse("http://backend.test:50051").unwrap()).unwrap();
+        let mut recording = RecordingChannel::new(
+            AutoBalancedChannel::builder(template)
+                .interval(Duration::from_millis(10))
+                .with_dns_server(server, DnsProtocol::Udp),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        recording.assert_inserted(Ipv4Addr::new(10, 0, 0, 1));
+    }
+
+    #[rstest::rstest]
+    #[case::a(QueryTypes::A, &[RecordType::A])]
+    #[case::aaaa(QueryTypes::Aaaa, &[RecordType::AAAA])]
+    #[tokio::test]
+    #[sequential]
+    async fn only_selected_record_types_are_queried(
+        #[case] query_types: QueryTypes,
+        #[case] expected: &[RecordType],
+    ) {
+        let (server, queried) = mock_dns_server_recording().await;
+
+        let template =
+            EndpointTemplate::new(Url::parse("http://backend.test:50051").unwrap()).unwrap();
+        let _recording = RecordingChannel::new(
+            AutoBalancedChannel::builder(template)
+                .interval(Duration::from_millis(10))
+                .query_types(query_types)
+                .with_dns_server(server, DnsProtocol::Udp),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut queried = queried.lock().unwrap().clone();
+        queried.dedup();
+        assert_eq!(queried, expected);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn resolver_can_be_used_directly() {
+        let server = mock_dns_server().await;
+        let resolver = DnsServerResolver::new(server, DnsProtocol::Udp);
+        let addrs = resolver.resolve("backend.test").await.unwrap();
+        assert_eq!(addrs, [SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 0))]);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn follows_cname_chain_to_canonical_name() {
+        let server = mock_dns_server().await;
+        let resolver = DnsServerResolver::new(server, DnsProtocol::Udp);
+
+        assert_eq!(
+            resolver
+                .canonical_name("alias.test")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("canonical.test")
+        );
+        assert_eq!(resolver.canonical_name("backend.test").await.unwrap(), None);
+    }
+
+    #[rstest::rstest]
+    #[case(OriginName::Original, None)]
+    #[case(OriginName::Canonical, Some("canonical.test"))]
+    #[tokio::test]
+    #[sequential]
+    async fn canonical_name_is_looked_up_if_requested(
+        #[case] origin_name: OriginName,
+        #[case] expected: Option<&str>,
+    ) {
+        let server = mock_dns_server().await;
+
+        let template =
+            EndpointTemplate::new(Url::parse("http://alias.test:50051").unwrap()).unwrap();
+        let mut recording = RecordingChannel::new(
+            AutoBalancedChannel::builder(template)
+                .interval(Duration::from_millis(10))
+                .with_dns_server(server, DnsProtocol::Udp)
+                .origin_name(origin_name),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        recording.assert_inserted(Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(recording.channel().canonical_name().as_deref(), expected);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        error::Error,
+        io,
+        net::{IpAddr, Ipv4Addr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use sequential_test::sequential;
+    use url::Url;
+
+    use super::{ResolveFuture, Resolver, StaticResolver};
+    use crate::{
+        dns::mock_net, recording::RecordingChannel, AutoBalancedChannel,
+        AutoBalancedChannelBuilder, DnsStatus, EndpointTemplate, Health,
+    };
+
+    fn builder(domain: &str) -> AutoBalancedChannelBuilder {
+        let template =
+            EndpointTemplate::new(Url::parse(&format!("http://{domain}:50051")).unwrap()).unwrap();
+        let ip = IpAddr::from(Ipv4Addr::new(10, 0, 0, 1));
+        AutoBalancedChannel::builder(template)
+            .interval(Duration::from_millis(1))
+            .with_resolver(StaticResolver::new(HashMap::from([(
+                "backend.test".to_owned(),
+                vec![ip],
+            )])))
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn static_resolver_is_used_instead_of_system_resolver() {
+        mock_net::set_socket_addrs(Box::new(|_, _| Err(io::Error::other("not used"))));
+
+        let mut recording = RecordingChannel::new(builder("backend.test"));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        recording.assert_inserted(Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(recording.channel().get_health(), Health::Ok);
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn static_resolver_fails_for_unknown_domains() {
+        let balanced = builder("unknown.test").build();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let DnsStatus::ResolutionError { error } = balanced.get_dns_status() else {
+            panic!("status is {:?}", balanced.get_dns_status());
+        };
+        let source = error.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::NotFound);
+    }
+
+    /// Counts the lookups passed on to the wrapped resolver.
+    struct CountingResolver {
+        inner: StaticResolver,
+        calls: AtomicUsize,
+    }
+
+    impl Resolver for CountingResolver {
+        fn resolve<'a>(&'a self, domain: &'a str) -> ResolveFuture<'a> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.inner.resolve(domain)
+        }
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn resolver_can_be_shared_between_channels() {
+        let ip = IpAddr::from(Ipv4Addr::new(10, 0, 0, 1));
+        let resolver = Arc::new(CountingResolver {
+            inner: StaticResolver::new(HashMap::from([("backend.test".to_owned(), vec![ip])])),
+            calls: AtomicUsize::new(0),
+        });
+
+        let template =
+            || EndpointTemplate::new(Url::parse("http://backend.test:50051").unwrap()).unwrap();
+        let mut first = RecordingChannel::new(
+            AutoBalancedChannel::builder(template()).with_shared_resolver(resolver.clone()),
+        );
+        let mut second = RecordingChannel::new(
+            AutoBalancedChannel::builder(template()).with_shared_resolver(resolver.clone()),
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        first.assert_inserted(ip);
+        second.assert_inserted(ip);
+        assert_eq!(resolver.calls.load(Ordering::SeqCst), 2);
+    }
+}
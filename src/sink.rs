@@ -0,0 +1,277 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+use tracing::Instrument;
+
+/// Endpoints added and removed by a single update, as reported to the sink
+/// set with
+/// [`AutoBalancedChannelBuilder::with_delta_sink`](crate::AutoBalancedChannelBuilder::with_delta_sink).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EndpointDelta {
+    pub added: Vec<SocketAddr>,
+    pub removed: Vec<SocketAddr>,
+}
+
+/// What discovery does with a delta when the sink's buffer is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SinkOverflow {
+    /// Drop the oldest buffered delta to make room, so that discovery never
+    /// waits for the sink.
+    DropOldest,
+    /// Wait up to this long for room, holding up discovery, then drop the new
+    /// delta.
+    Block(Duration),
+}
+
+pub(crate) type DeltaSinkFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+
+pub(crate) type DeltaSink = Arc<dyn Fn(EndpointDelta) -> DeltaSinkFuture + Send + Sync>;
+
+/// Attempts to deliver a delta after the first one failed.
+const SINK_RETRIES: u32 = 3;
+
+/// Delay before the first retry, doubled for each one after it.
+const SINK_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+struct Buffer {
+    deltas: Mutex<VecDeque<EndpointDelta>>,
+    capacity: usize,
+    /// Notified when a delta is buffered or the sender goes away.
+    pushed: Notify,
+    /// Notified when a delta is taken out of the buffer.
+    popped: Notify,
+    closed: AtomicBool,
+}
+
+/// Discovery's end of the buffer feeding the sink. Dropping it lets the sink
+/// task deliver what is left and stop.
+pub(crate) struct DeltaSender {
+    buffer: Arc<Buffer>,
+    overflow: SinkOverflow,
+}
+
+impl DeltaSender {
+    /// Starts a task delivering the deltas sent to the returned sender to
+    /// `sink`, one at a time, with up to `capacity` of them buffered.
+    pub(crate) fn spawn(sink: DeltaSink, capacity: usize, overflow: SinkOverflow) -> Self {
+        let buffer = Arc::new(Buffer {
+            deltas: Mutex::new(VecDeque::new()),
+            capacity: capacity.max(1),
+            pushed: Notify::new(),
+            popped: Notify::new(),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(deliver(buffer.clone(), sink).in_current_span());
+        Self { buffer, overflow }
+    }
+
+    pub(crate) async fn send(&self, delta: EndpointDelta) {
+        let deadline = match self.overflow {
+            SinkOverflow::DropOldest => None,
+            SinkOverflow::Block(timeout) => Some(tokio::time::Instant::now() + timeout),
+        };
+        loop {
+            {
+                let mut deltas = self
+                    .buffer
+                    .deltas
+                    .lock()
+                    .expect("sink buffer lock poisoned");
+                if deltas.len() < self.buffer.capacity {
+                    deltas.push_back(delta);
+                    self.buffer.pushed.notify_one();
+                    return;
+                }
+                if deadline.is_none() {
+                    tracing::warn!("sink buffer full, dropping oldest endpoint delta");
+                    deltas.pop_front();
+                    deltas.push_back(delta);
+                    return;
+                }
+            }
+            let deadline = deadline.expect("blocking overflow");
+            // A pop between releasing the lock and waiting leaves a permit
+            // behind, so no wakeup is missed.
+            if tokio::time::timeout_at(deadline, self.buffer.popped.notified())
+                .await
+                .is_err()
+            {
+                tracing::warn!("sink buffer still full, dropping endpoint delta");
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for DeltaSender {
+    fn drop(&mut self) {
+        self.buffer.closed.store(true, Ordering::Release);
+        self.buffer.pushed.notify_one();
+    }
+}
+
+/// Delivers buffered deltas in order until the sender is gone and the buffer
+/// empty. Failed deliveries are retried with backoff, holding up the deltas
+/// behind, and eventually given up on.
+async fn deliver(buffer: Arc<Buffer>, sink: DeltaSink) {
+    loop {
+        let delta = loop {
+            let delta = buffer
+                .deltas
+                .lock()
+                .expect("sink buffer lock poisoned")
+                .pop_front();
+            if let Some(delta) = delta {
+                buffer.popped.notify_one();
+                break delta;
+            }
+            if buffer.closed.load(Ordering::Acquire) {
+                return;
+            }
+            buffer.pushed.notified().await;
+        };
+
+        let mut backoff = SINK_RETRY_BACKOFF;
+        for attempt in 0..=SINK_RETRIES {
+            match sink(delta.clone()).await {
+                Ok(()) => break,
+                Err(error) if attempt < SINK_RETRIES => {
+                    tracing::warn!(error, ?backoff, "failed to report endpoint delta, retrying");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(error) => {
+                    tracing::error!(error, ?delta, "failed to report endpoint delta, giving up");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use sequential_test::sequential;
+    use tokio::sync::{mpsc, watch};
+
+    use super::{DeltaSender, DeltaSink, EndpointDelta, SinkOverflow};
+    use crate::{
+        test_util::{set_dns, template},
+        AutoBalancedChannel,
+    };
+
+    fn delta(added: &[SocketAddr], removed: &[SocketAddr]) -> EndpointDelta {
+        EndpointDelta {
+            added: added.to_vec(),
+            removed: removed.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    #[sequential]
+    async fn deltas_are_reported_in_order_with_retries() {
+        let first = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 0);
+        let second = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 0);
+        set_dns(&["10.0.0.1"]);
+
+        let attempts = Arc::new(Mutex::new(0));
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink = {
+            let attempts = attempts.clone();
+            let reported = reported.clone();
+            move |delta: EndpointDelta| {
+                let attempts = attempts.clone();
+                let reported = reported.clone();
+                async move {
+                    *attempts.lock().unwrap() += 1;
+                    // The very first delivery fails and is retried.
+                    if *attempts.lock().unwrap() == 1 {
+                        return Err("sink unavailable");
+                    }
+                    reported.lock().unwrap().push(delta);
+                    Ok(())
+                }
+            }
+        };
+
+        let template = template();
+        let (sender, _receiver) = mpsc::channel(16);
+        let _balanced = AutoBalancedChannel::builder(template)
+            .interval(Duration::from_millis(1))
+            .with_delta_sink(4, SinkOverflow::DropOldest, sink)
+            .build_with_sender(sender);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        set_dns(&["10.0.0.2"]);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(*attempts.lock().unwrap(), 3);
+        assert_eq!(
+            *reported.lock().unwrap(),
+            [delta(&[first], &[]), delta(&[second], &[first])]
+        );
+    }
+
+    #[rstest::rstest]
+    #[case::drop_oldest(SinkOverflow::DropOldest, 2)]
+    #[case::block(SinkOverflow::Block(Duration::from_millis(10)), 1)]
+    #[tokio::test]
+    #[sequential]
+    async fn full_buffer_overflows_by_policy(
+        #[case] overflow: SinkOverflow,
+        #[case] reported_second: usize,
+    ) {
+        let (open, opened) = watch::channel(false);
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink: DeltaSink = {
+            let reported = reported.clone();
+            Arc::new(move |delta| {
+                let mut opened = opened.clone();
+                let reported = reported.clone();
+                Box::pin(async move {
+                    opened.wait_for(|open| *open).await.unwrap();
+                    reported.lock().unwrap().push(delta);
+                    Ok(())
+                })
+            })
+        };
+        let deltas: Vec<EndpointDelta> = (1..=3)
+            .map(|i| {
+                delta(
+                    &[SocketAddr::new(Ipv4Addr::new(10, 0, 0, i).into(), 0)],
+                    &[],
+                )
+            })
+            .collect();
+
+        let sender = DeltaSender::spawn(sink, 1, overflow);
+        sender.send(deltas[0].clone()).await;
+        // The first delta is taken out of the buffer and stuck in the sink,
+        // the second one fills the buffer.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        sender.send(deltas[1].clone()).await;
+        sender.send(deltas[2].clone()).await;
+
+        open.send_replace(true);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            *reported.lock().unwrap(),
+            [deltas[0].clone(), deltas[reported_second].clone()]
+        );
+    }
+}
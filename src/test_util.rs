@@ -0,0 +1,35 @@
+//! Helpers shared by the unit tests.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use url::Url;
+
+use crate::{dns::mock_net, EndpointTemplate};
+
+/// Makes every domain resolve to `addresses`, with port 0.
+pub(crate) fn set_dns(addresses: &[&str]) {
+    let sockets = addresses
+        .iter()
+        .map(|address| SocketAddr::new(IpAddr::from_str(address).unwrap(), 0))
+        .collect::<Vec<_>>();
+    set_dns_sockets(sockets);
+}
+
+/// Makes every domain resolve to `sockets`.
+pub(crate) fn set_dns_sockets(sockets: Vec<SocketAddr>) {
+    mock_net::set_socket_addrs(Box::new(move |_, _| Ok(sockets.clone())));
+}
+
+pub(crate) fn socket_addrs(addresses: &[&str]) -> Vec<SocketAddr> {
+    addresses
+        .iter()
+        .map(|address| SocketAddr::from_str(address).unwrap())
+        .collect()
+}
+
+pub(crate) fn template() -> EndpointTemplate {
+    EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap()
+}
@@ -1,16 +1,29 @@
+// The original tests predate these lints; keep them as written.
+#![allow(
+    clippy::assertions_on_constants,
+    clippy::bind_instead_of_map,
+    clippy::io_other_error
+)]
+
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use sequential_test::sequential;
-use tokio::task::JoinSet;
-use tonic::{transport::Server, Request, Response};
-use tonic_dynamic_channel::{AutoBalancedChannel, DnsStatus, EndpointTemplate, Health};
+use tokio::{net::TcpListener, task::JoinSet};
+use tokio_stream::{wrappers::TcpListenerStream, StreamExt};
+use tonic::{codec::CompressionEncoding, transport::Server, Request, Response};
+use tonic_dynamic_channel::{
+    AddressOrder, AutoBalancedChannel, BalancePolicy, CircuitBreakerConfig, ConnectError,
+    DnsStatus, EndpointTemplate, Health, HealthCheck, ProxyConfig,
+};
 
 use foo::foo_client::FooClient;
 use foo::foo_server::{Foo, FooServer};
 use foo::{Empty, ServerResponse};
+use http::uri::PathAndQuery;
 use url::Url;
 
 pub mod foo {
@@ -50,6 +63,61 @@ impl Foo for MyServer {
     }
 }
 
+/// Server whose every call fails, standing in for a broken backend.
+pub struct FailingServer;
+
+impl FailingServer {
+    async fn run(address: impl Into<String>) -> Result<(), tonic::transport::Error> {
+        Server::builder()
+            .add_service(FooServer::new(Self))
+            .serve((address.into() + ":50051").parse().unwrap())
+            .await
+    }
+}
+
+#[tonic::async_trait]
+impl Foo for FailingServer {
+    async fn get_server(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<ServerResponse>, tonic::Status> {
+        Err(tonic::Status::unavailable("broken"))
+    }
+}
+
+/// Server replying with the value of the given metadata.
+pub struct MetadataEchoServer(&'static str);
+
+#[tonic::async_trait]
+impl Foo for MetadataEchoServer {
+    async fn get_server(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ServerResponse>, tonic::Status> {
+        let message = request
+            .metadata()
+            .get(self.0)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        Ok(Response::new(ServerResponse { message }))
+    }
+}
+
+/// Server taking its time to reply.
+pub struct SlowServer;
+
+#[tonic::async_trait]
+impl Foo for SlowServer {
+    async fn get_server(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<ServerResponse>, tonic::Status> {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        Ok(Response::new(ServerResponse::default()))
+    }
+}
+
 fn set_dns(addresses: &[&str]) {
     let sockets = addresses
         .iter()
@@ -59,21 +127,31 @@ fn set_dns(addresses: &[&str]) {
     tonic_dynamic_channel::mock_net::set_socket_addrs(Box::new(move |_, _| Ok(sockets.clone())));
 }
 
-fn setup() -> (
+type Setup = (
     JoinSet<Result<(), tonic::transport::Error>>,
-    std::sync::Arc<AutoBalancedChannel>,
-    std::sync::Arc<std::sync::RwLock<HashMap<String, i32>>>,
-) {
+    Arc<AutoBalancedChannel>,
+    Arc<RwLock<HashMap<String, i32>>>,
+);
+
+fn setup() -> Setup {
+    setup_with_policy(BalancePolicy::default())
+}
+
+fn setup_with_policy(balance_policy: BalancePolicy) -> Setup {
     let mut set = JoinSet::new();
 
     set.spawn(async { MyServer::run("[::1]").await });
     set.spawn(async { MyServer::run("127.0.0.1").await });
 
-    let balanced = Arc::new(AutoBalancedChannel::with_interval(
-        EndpointTemplate::new(Url::parse("http://localhost:50051").expect("url fialed"))
-            .expect("endpoint template"),
-        Duration::from_millis(1),
-    ));
+    let balanced = Arc::new(
+        AutoBalancedChannel::builder(
+            EndpointTemplate::new(Url::parse("http://localhost:50051").expect("url fialed"))
+                .expect("endpoint template"),
+        )
+        .interval(Duration::from_millis(1))
+        .balance_policy(balance_policy)
+        .build(),
+    );
 
     let responses: Arc<RwLock<HashMap<String, i32>>> = Arc::new(RwLock::new(HashMap::new()));
 
@@ -147,6 +225,112 @@ async fn test_balancing() {
         .expect("can't get a write lock");
 }
 
+#[tokio::test]
+#[sequential]
+async fn test_round_robin_balancing() {
+    let (_set, _balanced, responses) = setup_with_policy(BalancePolicy::RoundRobin);
+
+    set_dns(&["127.0.0.1", "::1"]);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    responses.write().expect("can't get a write lock").clear();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    responses
+        .read()
+        .map(|responses| {
+            let ipv4 = *responses
+                .get("127.0.0.1")
+                .expect("no response from 127.0.0.1 server");
+            let ipv6 = *responses
+                .get("[::1]")
+                .expect("no response from [::1] server");
+            assert!(
+                ipv4 >= 40 && ipv6 >= 40,
+                "strangely few responses: {ipv4} from 127.0.0.1, {ipv6} from [::1]"
+            );
+            assert!(
+                ipv4.abs_diff(ipv6) <= 2,
+                "uneven distribution: {ipv4} from 127.0.0.1, {ipv6} from [::1]"
+            );
+        })
+        .expect("can't get a read lock");
+}
+
+/// First requests of `clients` channels inserting endpoints in `order`, by
+/// responding server.
+async fn first_requests(order: AddressOrder, clients: u64) -> HashMap<String, u64> {
+    let mut first_requests = HashMap::new();
+    for seed in 0..clients {
+        let balanced = AutoBalancedChannel::builder(
+            EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap(),
+        )
+        .balance_policy(BalancePolicy::RoundRobin)
+        .address_order(order)
+        .with_rng_seed(seed)
+        .build();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let response = FooClient::new(balanced.channel())
+            .get_server(tonic::Request::new(Empty {}))
+            .await
+            .expect("response");
+        *first_requests
+            .entry(response.into_inner().message)
+            .or_default() += 1;
+    }
+    first_requests
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_shuffled_addresses_spread_first_requests() {
+    let mut set = JoinSet::new();
+    set.spawn(async { MyServer::run("127.0.0.1").await });
+    set.spawn(async { MyServer::run("[::1]").await });
+    set_dns(&["127.0.0.1", "::1"]);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    // Every client starts with the same endpoint.
+    let sorted = first_requests(AddressOrder::Sorted, 20).await;
+    assert_eq!(sorted, HashMap::from([("127.0.0.1".to_owned(), 20)]));
+
+    let shuffled = first_requests(AddressOrder::Shuffled, 20).await;
+    let ipv4 = shuffled.get("127.0.0.1").copied().unwrap_or_default();
+    let ipv6 = shuffled.get("[::1]").copied().unwrap_or_default();
+    assert!(
+        ipv4 >= 5 && ipv6 >= 5,
+        "uneven spread: {ipv4} from 127.0.0.1, {ipv6} from [::1]"
+    );
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_weighted_round_robin_balancing() {
+    let mut set = JoinSet::new();
+    set.spawn(async { MyServer::run("[::1]").await });
+    set.spawn(async { MyServer::run("127.0.0.1").await });
+    set_dns(&["127.0.0.1", "::1"]);
+
+    let balanced = AutoBalancedChannel::builder(
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap(),
+    )
+    .interval(Duration::from_millis(1))
+    .balance_policy(BalancePolicy::RoundRobin)
+    .with_subnet_weights([("127.0.0.0/8".parse().unwrap(), 3)])
+    .build();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = FooClient::new(balanced.channel());
+    let mut responses = HashMap::<String, usize>::new();
+    for _ in 0..40 {
+        let response = client
+            .get_server(tonic::Request::new(Empty {}))
+            .await
+            .expect("response");
+        *responses.entry(response.into_inner().message).or_default() += 1;
+    }
+    assert_eq!(responses["127.0.0.1"], 30, "{responses:?}");
+    assert_eq!(responses["[::1]"], 10, "{responses:?}");
+}
+
 #[tokio::test]
 #[sequential]
 async fn test_switching() {
@@ -223,3 +407,757 @@ async fn test_dns_error() {
         _ => assert!(false, "status is not DnsResolutionError"),
     }
 }
+
+#[tokio::test]
+#[sequential]
+async fn test_health_check_ejection() {
+    let mut set = JoinSet::new();
+    set.spawn(async { MyServer::run("[::1]").await });
+    set.spawn(async { FailingServer::run("127.0.0.1").await });
+    set_dns(&["127.0.0.1", "::1"]);
+
+    let balanced = AutoBalancedChannel::builder(
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap(),
+    )
+    .interval(Duration::from_millis(1))
+    .with_health_check(
+        // Empty is encoded as zero bytes.
+        HealthCheck::new(PathAndQuery::from_static("/foo.Foo/GetServer"), Vec::new())
+            .interval(Duration::from_millis(10))
+            .failure_threshold(2),
+    )
+    .build();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut client = FooClient::new(balanced.channel());
+    for _ in 0..20 {
+        let response = client
+            .get_server(tonic::Request::new(Empty {}))
+            .await
+            .expect("request reached the ejected server");
+        assert_eq!(response.into_inner().message, "[::1]");
+    }
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_default_metadata() {
+    let mut set = JoinSet::new();
+    set.spawn(async {
+        Server::builder()
+            .add_service(FooServer::new(MetadataEchoServer("x-tenant")))
+            .serve("127.0.0.1:50051".parse().unwrap())
+            .await
+    });
+    set_dns(&["127.0.0.1"]);
+
+    let balanced = AutoBalancedChannel::with_interval(
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap())
+            .unwrap()
+            .default_metadata("x-tenant", "acme")
+            .unwrap(),
+        Duration::from_millis(1),
+    );
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let mut client = FooClient::new(balanced.channel());
+    let response = client
+        .get_server(tonic::Request::new(Empty {}))
+        .await
+        .expect("response");
+    assert_eq!(response.into_inner().message, "acme");
+
+    let mut request = tonic::Request::new(Empty {});
+    request
+        .metadata_mut()
+        .insert("x-tenant", "override".parse().unwrap());
+    let response = client.get_server(request).await.expect("response");
+    assert_eq!(response.into_inner().message, "override");
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_compression() {
+    let mut set = JoinSet::new();
+    set.spawn(async {
+        Server::builder()
+            .add_service(
+                FooServer::new(MyServer {
+                    address: "127.0.0.1".to_owned(),
+                })
+                .accept_compressed(CompressionEncoding::Gzip)
+                .send_compressed(CompressionEncoding::Gzip),
+            )
+            .serve("127.0.0.1:50051".parse().unwrap())
+            .await
+    });
+    set_dns(&["127.0.0.1"]);
+
+    let balanced = AutoBalancedChannel::with_interval(
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap(),
+        Duration::from_millis(1),
+    );
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let mut client = FooClient::new(balanced.channel())
+        .send_compressed(CompressionEncoding::Gzip)
+        .accept_compressed(CompressionEncoding::Gzip);
+    let response = client
+        .get_server(tonic::Request::new(Empty {}))
+        .await
+        .expect("response");
+    assert_eq!(
+        response
+            .metadata()
+            .get("grpc-encoding")
+            .and_then(|value| value.to_str().ok()),
+        Some("gzip")
+    );
+    assert_eq!(response.into_inner().message, "127.0.0.1");
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_idle_connection_timeout() {
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let listener = TcpListener::bind("127.0.0.1:50051").await.unwrap();
+    let counter = accepted.clone();
+    let incoming = TcpListenerStream::new(listener).map(move |connection| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        connection
+    });
+    let mut set = JoinSet::new();
+    set.spawn(async move {
+        Server::builder()
+            .add_service(FooServer::new(MyServer {
+                address: "127.0.0.1".to_owned(),
+            }))
+            .serve_with_incoming(incoming)
+            .await
+    });
+    set_dns(&["127.0.0.1"]);
+
+    let balanced = AutoBalancedChannel::builder(
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap(),
+    )
+    .interval(Duration::from_millis(1))
+    .with_idle_connection_timeout(Duration::from_millis(50))
+    .build();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let mut client = FooClient::new(balanced.channel());
+    for _ in 0..2 {
+        client
+            .get_server(tonic::Request::new(Empty {}))
+            .await
+            .expect("response");
+    }
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+    // The idle connection is closed and a new one opened on demand.
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    client
+        .get_server(tonic::Request::new(Empty {}))
+        .await
+        .expect("response");
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_request_deadline() {
+    let mut set = JoinSet::new();
+    set.spawn(async {
+        Server::builder()
+            .add_service(FooServer::new(SlowServer))
+            .serve("127.0.0.1:50051".parse().unwrap())
+            .await
+    });
+    set_dns(&["127.0.0.1"]);
+
+    let balanced = AutoBalancedChannel::builder(
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap())
+            .unwrap()
+            .timeout(Duration::from_secs(5)),
+    )
+    .interval(Duration::from_millis(1))
+    .with_request_deadline(Duration::from_millis(100))
+    .build();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let mut client = FooClient::new(balanced.channel());
+    let started = tokio::time::Instant::now();
+    let status = client
+        .get_server(tonic::Request::new(Empty {}))
+        .await
+        .expect_err("deadline");
+    assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+    assert!(started.elapsed() < Duration::from_millis(500));
+}
+
+#[cfg(feature = "grpc-web")]
+#[tokio::test]
+#[sequential]
+async fn test_grpc_web() {
+    let mut set = JoinSet::new();
+    set.spawn(async {
+        // tonic-web sets this header on the gRPC-Web requests it translates.
+        Server::builder()
+            .layer(tonic_web::GrpcWebLayer::new())
+            .add_service(FooServer::new(MetadataEchoServer("accept-encoding")))
+            .serve("127.0.0.1:50051".parse().unwrap())
+            .await
+    });
+    set_dns(&["127.0.0.1"]);
+
+    let balanced = AutoBalancedChannel::builder(
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap())
+            .unwrap()
+            .grpc_web(),
+    )
+    .interval(Duration::from_millis(1))
+    .build();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let response = FooClient::new(balanced.channel())
+        .get_server(tonic::Request::new(Empty {}))
+        .await
+        .expect("response");
+    assert_eq!(response.into_inner().message, "identity,deflate,gzip");
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_every_template_option() {
+    let mut set = JoinSet::new();
+    set.spawn(async {
+        Server::builder()
+            .add_service(FooServer::new(MetadataEchoServer("user-agent")))
+            .serve("127.0.0.1:50051".parse().unwrap())
+            .await
+    });
+    set_dns(&["127.0.0.1"]);
+
+    let template = EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap())
+        .unwrap()
+        .origin("http://origin.localhost:50051".parse().unwrap())
+        .user_agent("my-agent")
+        .timeout(Duration::from_secs(5))
+        .connect_timeout(Duration::from_secs(5))
+        .tcp_keepalive(Some(Duration::from_secs(60)))
+        .concurrency_limit(8)
+        .rate_limit(100, Duration::from_secs(1))
+        .initial_stream_window_size(1 << 20)
+        .initial_connection_window_size(1 << 20)
+        .buffer_size(64)
+        .tcp_nodelay(true)
+        .http2_keepalive(Duration::from_secs(30), Duration::from_secs(10), true)
+        .unwrap()
+        .http2_adaptive_window(true);
+    let balanced = AutoBalancedChannel::with_interval(template, Duration::from_millis(1));
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let mut client = FooClient::new(balanced.channel());
+    let response = client
+        .get_server(tonic::Request::new(Empty {}))
+        .await
+        .expect("response");
+    assert!(response.into_inner().message.starts_with("my-agent"));
+}
+
+#[rstest::rstest]
+#[case::default(None, EndpointTemplate::DEFAULT_USER_AGENT)]
+#[case::overridden(Some("my-agent"), "my-agent")]
+#[tokio::test]
+#[sequential]
+async fn test_user_agent(#[case] user_agent: Option<&'static str>, #[case] expected: &str) {
+    let mut set = JoinSet::new();
+    set.spawn(async {
+        Server::builder()
+            .add_service(FooServer::new(MetadataEchoServer("user-agent")))
+            .serve("127.0.0.1:50051".parse().unwrap())
+            .await
+    });
+    set_dns(&["127.0.0.1"]);
+
+    let mut template =
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap();
+    if let Some(user_agent) = user_agent {
+        template = template.user_agent(user_agent);
+    }
+    let balanced = AutoBalancedChannel::with_interval(template, Duration::from_millis(1));
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let response = FooClient::new(balanced.channel())
+        .get_server(tonic::Request::new(Empty {}))
+        .await
+        .expect("response");
+    // Followed by tonic's own.
+    let sent = response.into_inner().message;
+    assert_eq!(sent.split(' ').next(), Some(expected));
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_into_parts() {
+    let mut set = JoinSet::new();
+    set.spawn(async { MyServer::run("127.0.0.1").await });
+    set_dns(&["127.0.0.1"]);
+
+    let (channel, discovery) = AutoBalancedChannel::with_interval(
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap(),
+        Duration::from_millis(1),
+    )
+    .into_parts();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(discovery.get_health(), Health::Ok);
+
+    let mut client = FooClient::new(channel);
+    client
+        .get_server(tonic::Request::new(Empty {}))
+        .await
+        .expect("response");
+
+    // Without discovery, the channel keeps using the last endpoints.
+    drop(discovery);
+    let response = client
+        .get_server(tonic::Request::new(Empty {}))
+        .await
+        .expect("response");
+    assert_eq!(response.into_inner().message, "127.0.0.1");
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_resolve_once() {
+    let mut set = JoinSet::new();
+    set.spawn(async { MyServer::run("127.0.0.1").await });
+    set_dns(&["127.0.0.1"]);
+
+    let channel = AutoBalancedChannel::resolve_once(
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap(),
+    )
+    .await
+    .expect("resolved");
+    let response = FooClient::new(channel)
+        .get_server(tonic::Request::new(Empty {}))
+        .await
+        .expect("response");
+    assert_eq!(response.into_inner().message, "127.0.0.1");
+}
+
+/// Serves `MyServer` on 127.0.0.1 until `shutdown` fires, then shuts down
+/// gracefully, sending GOAWAY to its clients.
+async fn run_stoppable_server(
+    shutdown: tokio::sync::oneshot::Receiver<()>,
+) -> Result<(), tonic::transport::Error> {
+    // Bound by tokio, with SO_REUSEADDR, so that it can be restarted right
+    // away.
+    let listener = TcpListener::bind("127.0.0.1:50051").await.unwrap();
+    Server::builder()
+        .add_service(FooServer::new(MyServer {
+            address: "127.0.0.1".to_owned(),
+        }))
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+            let _ = shutdown.await;
+        })
+        .await
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_lost_connection_rediscovery() {
+    let mut set = JoinSet::new();
+    set.spawn(async { MyServer::run("[::1]").await });
+    let (shutdown, shutdown_receiver) = tokio::sync::oneshot::channel();
+    let stopping = tokio::spawn(run_stoppable_server(shutdown_receiver));
+    set_dns(&["127.0.0.1", "::1"]);
+
+    let balanced = AutoBalancedChannel::builder(
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap(),
+    )
+    .interval(Duration::from_secs(60))
+    .rediscover_lost_connections(true)
+    .build();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = FooClient::new(balanced.channel());
+    let mut servers = std::collections::HashSet::new();
+    for _ in 0..20 {
+        let response = client
+            .get_server(tonic::Request::new(Empty {}))
+            .await
+            .expect("response");
+        servers.insert(response.into_inner().message);
+    }
+    assert_eq!(servers.len(), 2, "{servers:?}");
+
+    shutdown.send(()).unwrap();
+    stopping.await.unwrap().expect("graceful shutdown");
+
+    // DNS still returns the stopped server, but only the request that found
+    // its connection gone fails.
+    let mut failures = 0;
+    for _ in 0..20 {
+        match client.get_server(tonic::Request::new(Empty {})).await {
+            Ok(response) => assert_eq!(response.into_inner().message, "[::1]"),
+            Err(_) => failures += 1,
+        }
+    }
+    assert!(failures <= 1, "{failures} requests failed");
+    assert_eq!(balanced.endpoint_count(), 1);
+
+    // Readmitted by a resolution once it's back.
+    let (_shutdown, shutdown_receiver) = tokio::sync::oneshot::channel();
+    set.spawn(run_stoppable_server(shutdown_receiver));
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    balanced.refresh();
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(balanced.endpoint_count(), 2);
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_timeouts_are_not_lost_connections() {
+    let mut set = JoinSet::new();
+    set.spawn(async {
+        Server::builder()
+            .add_service(FooServer::new(SlowServer))
+            .serve("127.0.0.1:50051".parse().unwrap())
+            .await
+    });
+    set_dns(&["127.0.0.1"]);
+
+    let balanced = AutoBalancedChannel::builder(
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap())
+            .unwrap()
+            .timeout(Duration::from_millis(100)),
+    )
+    .interval(Duration::from_secs(60))
+    .rediscover_lost_connections(true)
+    .build();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = FooClient::new(balanced.channel());
+    for _ in 0..3 {
+        client
+            .get_server(tonic::Request::new(Empty {}))
+            .await
+            .expect_err("timeout");
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(balanced.endpoint_count(), 1);
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_circuit_breaker() {
+    let mut set = JoinSet::new();
+    set.spawn(async { MyServer::run("[::1]").await });
+    // Nothing listens on 127.0.0.1.
+    set_dns(&["127.0.0.1", "::1"]);
+
+    let balanced = AutoBalancedChannel::builder(
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap(),
+    )
+    .interval(Duration::from_secs(60))
+    .with_circuit_breaker(CircuitBreakerConfig {
+        failure_threshold: 1,
+        cooldown: Duration::from_secs(60),
+    })
+    .build();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = FooClient::new(balanced.channel());
+    let mut failures = 0;
+    for _ in 0..20 {
+        match client.get_server(tonic::Request::new(Empty {})).await {
+            Ok(response) => assert_eq!(response.into_inner().message, "[::1]"),
+            Err(_) => failures += 1,
+        }
+    }
+    assert!(failures <= 1, "{failures} requests failed");
+    // Still registered, just routed around.
+    assert_eq!(balanced.endpoint_count(), 2);
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_connect_single() {
+    let mut set = JoinSet::new();
+    set.spawn(async { MyServer::run("127.0.0.1").await });
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let template = EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap();
+    let channel = template
+        .connect_single(std::net::Ipv4Addr::LOCALHOST)
+        .await
+        .expect("connected");
+    let response = FooClient::new(channel)
+        .get_server(tonic::Request::new(Empty {}))
+        .await
+        .expect("response");
+    assert_eq!(response.into_inner().message, "127.0.0.1");
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_connect_single_reports_connection_errors() {
+    let template = EndpointTemplate::new(Url::parse("http://localhost:50059").unwrap()).unwrap();
+    let error = template
+        .connect_single(std::net::Ipv4Addr::LOCALHOST)
+        .await
+        .expect_err("nothing listening");
+    assert!(matches!(error, ConnectError::Transport(_)));
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_endpoint_load() {
+    let mut set = JoinSet::new();
+    set.spawn(async {
+        Server::builder()
+            .add_service(FooServer::new(SlowServer))
+            .serve("127.0.0.1:50051".parse().unwrap())
+            .await
+    });
+    set_dns(&["127.0.0.1"]);
+
+    let balanced = AutoBalancedChannel::builder(
+        EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap(),
+    )
+    .interval(Duration::from_millis(1))
+    .with_load_tracking()
+    .build();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let ip = std::net::IpAddr::from_str("127.0.0.1").unwrap();
+    assert_eq!(balanced.endpoint_load()[&ip].in_flight, 0);
+
+    let client = FooClient::new(balanced.channel());
+    let requests: Vec<_> = (0..2)
+        .map(|_| {
+            let mut client = client.clone();
+            tokio::spawn(async move { client.get_server(tonic::Request::new(Empty {})).await })
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(balanced.endpoint_load()[&ip].in_flight, 2);
+
+    for request in requests {
+        request.await.unwrap().expect("response");
+    }
+    assert_eq!(balanced.endpoint_load()[&ip].in_flight, 0);
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_configure_endpoint() {
+    let mut set = JoinSet::new();
+    for address in ["127.0.0.1:50051", "[::1]:50051"] {
+        set.spawn(async move {
+            Server::builder()
+                .add_service(FooServer::new(MetadataEchoServer("user-agent")))
+                .serve(address.parse().unwrap())
+                .await
+        });
+    }
+    set_dns(&["127.0.0.1", "::1"]);
+
+    let template = EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap())
+        .unwrap()
+        .configure_endpoint(|endpoint| endpoint.user_agent("configured-agent").unwrap());
+    let balanced = AutoBalancedChannel::builder(template)
+        .interval(Duration::from_millis(1))
+        .balance_policy(BalancePolicy::RoundRobin)
+        .build();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(balanced.endpoint_count(), 2);
+
+    // Round robin, so both endpoints are used.
+    let mut client = FooClient::new(balanced.channel());
+    for _ in 0..4 {
+        let response = client
+            .get_server(tonic::Request::new(Empty {}))
+            .await
+            .expect("response");
+        assert!(response
+            .into_inner()
+            .message
+            .starts_with("configured-agent"));
+    }
+}
+
+#[tokio::test]
+#[sequential]
+async fn test_endpoint_prototype() {
+    let mut set = JoinSet::new();
+    for address in ["127.0.0.1:50051", "[::1]:50051"] {
+        set.spawn(async move {
+            Server::builder()
+                .add_service(FooServer::new(MetadataEchoServer("user-agent")))
+                .serve(address.parse().unwrap())
+                .await
+        });
+    }
+    set_dns(&["127.0.0.1", "::1"]);
+
+    // Only the mock resolver knows the domain, so endpoints must be dialed
+    // at the resolved addresses rather than the prototype's URI.
+    let prototype = tonic::transport::Endpoint::from_static("http://backend.invalid:50051")
+        .user_agent("prototype-agent")
+        .unwrap()
+        .timeout(Duration::from_secs(5));
+    let balanced =
+        AutoBalancedChannel::builder(EndpointTemplate::from_endpoint(prototype).unwrap())
+            .interval(Duration::from_millis(1))
+            .balance_policy(BalancePolicy::RoundRobin)
+            .build();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(balanced.endpoint_count(), 2);
+
+    // Round robin, so both endpoints are used.
+    let mut client = FooClient::new(balanced.channel());
+    for _ in 0..4 {
+        let response = client
+            .get_server(tonic::Request::new(Empty {}))
+            .await
+            .expect("response");
+        assert!(response.into_inner().message.starts_with("prototype-agent"));
+    }
+}
+
+/// Proxy tunnelling every connection it accepts to the target requested with
+/// HTTP `CONNECT` or SOCKS5, as long as the credentials are `user:secret`.
+/// Returns its address and the targets requested so far.
+async fn run_mock_proxy(socks5: bool) -> (String, Arc<RwLock<Vec<String>>>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn handshake(client: &mut tokio::net::TcpStream, socks5: bool) -> Option<String> {
+        if !socks5 {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(client.read_u8().await.ok()?);
+            }
+            let head = String::from_utf8(head).ok()?;
+            // "user:secret" in base64.
+            if !head.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n") {
+                client
+                    .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                    .await
+                    .ok()?;
+                return None;
+            }
+            let target = head.strip_prefix("CONNECT ")?.split(' ').next()?.to_owned();
+            return Some(target);
+        }
+
+        let mut greeting = [0; 3];
+        client.read_exact(&mut greeting).await.ok()?;
+        client.write_all(&[5, 2]).await.ok()?;
+        // Version of the username/password subnegotiation.
+        client.read_u8().await.ok()?;
+        let mut credentials = Vec::new();
+        for _ in 0..2 {
+            let mut field = vec![0; usize::from(client.read_u8().await.ok()?)];
+            client.read_exact(&mut field).await.ok()?;
+            credentials.push(String::from_utf8(field).ok()?);
+        }
+        let authenticated = credentials == ["user", "secret"];
+        client
+            .write_all(&[1, if authenticated { 0 } else { 1 }])
+            .await
+            .ok()?;
+        if !authenticated {
+            return None;
+        }
+
+        let mut request = [0; 4];
+        client.read_exact(&mut request).await.ok()?;
+        let ip: std::net::IpAddr = match request[3] {
+            1 => {
+                let mut octets = [0; 4];
+                client.read_exact(&mut octets).await.ok()?;
+                octets.into()
+            }
+            4 => {
+                let mut octets = [0; 16];
+                client.read_exact(&mut octets).await.ok()?;
+                octets.into()
+            }
+            _ => return None,
+        };
+        let port = client.read_u16().await.ok()?;
+        Some(std::net::SocketAddr::new(ip, port).to_string())
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let targets = Arc::new(RwLock::new(Vec::new()));
+    {
+        let targets = targets.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut client, _) = listener.accept().await.unwrap();
+                let targets = targets.clone();
+                tokio::spawn(async move {
+                    let Some(target) = handshake(&mut client, socks5).await else {
+                        return;
+                    };
+                    targets.write().unwrap().push(target.clone());
+                    let mut server = tokio::net::TcpStream::connect(target).await.unwrap();
+                    let reply: &[u8] = if socks5 {
+                        &[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]
+                    } else {
+                        b"HTTP/1.1 200 Connection established\r\n\r\n"
+                    };
+                    client.write_all(reply).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+                });
+            }
+        });
+    }
+    (address, targets)
+}
+
+#[rstest::rstest]
+#[case::http_connect(false)]
+#[case::socks5(true)]
+#[tokio::test]
+#[sequential]
+async fn test_proxy(#[case] socks5: bool) {
+    let mut set = JoinSet::new();
+    set.spawn(async { MyServer::run("127.0.0.1").await });
+    set.spawn(async { MyServer::run("[::1]").await });
+    set_dns(&["127.0.0.1", "::1"]);
+    let (proxy_address, targets) = run_mock_proxy(socks5).await;
+
+    let proxy = match socks5 {
+        true => ProxyConfig::socks5(proxy_address),
+        false => ProxyConfig::http(proxy_address),
+    };
+    let template = EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap())
+        .unwrap()
+        .proxy(proxy.credentials("user", "secret"));
+    let balanced = AutoBalancedChannel::builder(template)
+        .interval(Duration::from_millis(1))
+        .balance_policy(BalancePolicy::RoundRobin)
+        .build();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let mut client = FooClient::new(balanced.channel());
+    let mut servers = Vec::new();
+    for _ in 0..2 {
+        let response = client
+            .get_server(tonic::Request::new(Empty {}))
+            .await
+            .expect("response");
+        servers.push(response.into_inner().message);
+    }
+    servers.sort();
+    assert_eq!(servers, ["127.0.0.1", "[::1]"]);
+
+    // Tunnels are opened to the resolved addresses.
+    let mut targets = targets.read().unwrap().clone();
+    targets.sort();
+    assert_eq!(targets, ["127.0.0.1:50051", "[::1]:50051"]);
+}
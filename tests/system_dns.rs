@@ -0,0 +1,15 @@
+//! Resolution through the system resolver, which the DNS mock replaces
+//! everywhere else. Run with `--no-default-features`.
+#![cfg(not(feature = "mock-dns"))]
+
+use tonic_dynamic_channel::{AutoBalancedChannel, EndpointTemplate};
+use url::Url;
+
+#[tokio::test]
+async fn localhost_is_resolved_by_the_system() {
+    let template = EndpointTemplate::new(Url::parse("http://localhost:50051").unwrap()).unwrap();
+    let channel = AutoBalancedChannel::builder(template).build();
+
+    assert!(channel.wait_for_cycles(1).await);
+    assert!(channel.endpoint_count() > 0);
+}